@@ -55,10 +55,14 @@ pub fn main() {
             let flying = tello.flying();
             if !flying {
                 tracing::info!("takeoff");
-                tello.takeoff();
+                if let Err(e) = tello.takeoff() {
+                    tracing::warn!("can't take off: {}", e);
+                }
             } else {
                 tracing::info!("land");
-                tello.land();
+                if let Err(e) = tello.land() {
+                    tracing::warn!("can't land: {}", e);
+                }
             }
         }
         if st.button_clicked(Buttons::SELECT, &last_state) {
@@ -1,20 +1,23 @@
-use std::{sync::mpsc, thread, time::Duration};
+use std::{thread, time::Duration};
 
-use rust_tello::TelloController;
+use rust_tello::{
+    video_sink::{FanOut, FileSink, PlayerSink},
+    TelloController,
+};
 
 pub fn main() {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::WARN)
         .init();
 
-    let (tx, rx) = mpsc::channel();
+    let (tx, rx) = rust_tello::video_channel();
     let mut tello = TelloController::new();
 
     let (update_tx, _update_rx) = rust_tello::comm_channel();
     let h = tello.start_ctrl_receiver(update_tx);
     tello.start_video_receiver(tx);
 
-    tello.start_video_contoller(); // send video request every 500ms if video is on
+    tello.start_video_contoller(); // request keyframes while video is on
     tello.connect();
     loop {
         tracing::info!("waiting to connect to tello...");
@@ -27,7 +30,12 @@ pub fn main() {
 
     tello.toggle_video(); // toggle video on
 
-    tello.start_mplayer(rx);
+    // ffplay, mpv or mplayer, the stream is also recorded when a file name is given
+    let mut sink = FanOut::new().with(PlayerSink::detect().expect("no video player found"));
+    if let Some(path) = std::env::args().nth(1) {
+        sink.add(FileSink::create(path).expect("can't create the recording"));
+    }
+    tello.start_video_sink(rx, sink);
 
     tracing::info!("waiting for main thread to finish");
     let _ = h.join();
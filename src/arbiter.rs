@@ -0,0 +1,473 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use crate::{
+    events::{EventBus, TelloEvent},
+    tello::{AtomicStick, Stick},
+};
+
+pub const PRIORITY_MANUAL: u8 = 0;
+pub const PRIORITY_AUTOPILOT: u8 = 50;
+pub const PRIORITY_PLAYER: u8 = 60;
+pub const PRIORITY_FAILSAFE: u8 = 100;
+
+pub const MANUAL_SOURCE: &str = "manual";
+pub const AUTOPILOT_SOURCE: &str = "autopilot";
+pub const PLAYER_SOURCE: &str = "player";
+pub const FAILSAFE_SOURCE: &str = "failsafe";
+// the sources driven by the library, can't be registered by the user
+pub const RESERVED_SOURCES: [&str; 4] = [
+    MANUAL_SOURCE,
+    AUTOPILOT_SOURCE,
+    PLAYER_SOURCE,
+    FAILSAFE_SOURCE,
+];
+
+// stick movement (since the automatic source engaged) needed for the pilot to take over
+pub const DEFAULT_TAKEOVER_THRESHOLD: f32 = 0.3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    // one of RESERVED_SOURCES
+    ReservedName(String),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::ReservedName(name) => {
+                write!(f, "control source name {:?} is reserved", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Manual,
+    Automatic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchReason {
+    // a source with higher priority was engaged or the active one released
+    Priority,
+    // the pilot moved a stick past the takeover threshold
+    PilotTakeover,
+}
+
+// One writer of the stick values. Manual sources are always engaged and
+// serve as the fallback, automatic sources take part in the arbitration
+// only between engage() and release().
+#[derive(Debug)]
+pub struct ControlSource {
+    name: String,
+    priority: u8,
+    kind: SourceKind,
+    pub(crate) stick: AtomicStick,
+    engaged: AtomicBool,
+    // released when the pilot takes over, by default the sources below PRIORITY_FAILSAFE
+    overridable: AtomicBool,
+    // shared by the sources of an arbiter, counts the automatic sources engaging
+    engagements: Arc<AtomicU64>,
+}
+
+impl ControlSource {
+    fn new(name: &str, priority: u8, kind: SourceKind, engagements: Arc<AtomicU64>) -> Self {
+        Self {
+            name: name.to_owned(),
+            priority,
+            kind,
+            stick: AtomicStick::default(),
+            engaged: AtomicBool::new(kind == SourceKind::Manual),
+            overridable: AtomicBool::new(priority < PRIORITY_FAILSAFE),
+            engagements,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn kind(&self) -> SourceKind {
+        self.kind
+    }
+
+    pub fn set_sticks(&self, st: &Stick) {
+        self.stick.store(st);
+    }
+
+    pub fn sticks(&self) -> Stick {
+        self.stick.load()
+    }
+
+    pub fn engage(&self) {
+        if !self.engaged.swap(true, Ordering::Relaxed) {
+            self.engagements.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Manual sources can't be released, they always stay in control as the fallback.
+    pub fn release(&self) {
+        if self.kind == SourceKind::Automatic {
+            self.engaged.store(false, Ordering::Relaxed);
+            self.stick.store(&Stick::default());
+        }
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::Relaxed)
+    }
+
+    pub fn is_overridable(&self) -> bool {
+        self.overridable.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_overridable(&self, overridable: bool) {
+        self.overridable.store(overridable, Ordering::Relaxed);
+    }
+}
+
+// Decides which control source drives the drone on each stick update tick:
+// the engaged source with the highest priority wins. When the pilot moves
+// a manual stick by more than the takeover threshold since an automatic source
+// engaged, all engaged overridable automatic sources are released and the pilot
+// is in control.
+#[derive(Debug)]
+pub struct ControlArbiter {
+    sources: RwLock<Vec<Arc<ControlSource>>>,
+    manual: Arc<ControlSource>,
+    active: Mutex<Option<Arc<ControlSource>>>,
+    takeover_threshold: AtomicU32,
+    engagements: Arc<AtomicU64>,
+    // the manual sticks when an automatic source engaged (at that engagement count),
+    // a stick left deflected before doesn't take over
+    takeover_reference: Mutex<(u64, Vec<(String, Stick)>)>,
+    events: EventBus,
+}
+
+impl ControlArbiter {
+    pub(crate) fn new(events: EventBus) -> Self {
+        let engagements = Arc::new(AtomicU64::new(0));
+        let manual = Arc::new(ControlSource::new(
+            MANUAL_SOURCE,
+            PRIORITY_MANUAL,
+            SourceKind::Manual,
+            engagements.clone(),
+        ));
+        Self {
+            sources: RwLock::new(vec![manual.clone()]),
+            manual,
+            active: Mutex::new(None),
+            takeover_threshold: AtomicU32::new(DEFAULT_TAKEOVER_THRESHOLD.to_bits()),
+            engagements,
+            takeover_reference: Mutex::new((0, vec![])),
+            events,
+        }
+    }
+
+    // Registers a new source, if there is already a source with the same name it is returned instead.
+    // The RESERVED_SOURCES names are rejected.
+    pub fn register(
+        &self,
+        name: &str,
+        priority: u8,
+        kind: SourceKind,
+    ) -> Result<Arc<ControlSource>, RegisterError> {
+        if RESERVED_SOURCES.contains(&name) {
+            return Err(RegisterError::ReservedName(name.to_owned()));
+        }
+        Ok(self.register_internal(name, priority, kind))
+    }
+
+    // Same as register(), for the sources the library drives itself.
+    pub(crate) fn register_internal(
+        &self,
+        name: &str,
+        priority: u8,
+        kind: SourceKind,
+    ) -> Arc<ControlSource> {
+        let method_name = "register";
+        let mut g = self.sources.write().unwrap();
+        if let Some(src) = g.iter().find(|src| src.name == name) {
+            return src.clone();
+        }
+        tracing::info!(method_name, name, priority, "register control source");
+        let src = Arc::new(ControlSource::new(
+            name,
+            priority,
+            kind,
+            self.engagements.clone(),
+        ));
+        g.push(src.clone());
+        src
+    }
+
+    pub fn source(&self, name: &str) -> Option<Arc<ControlSource>> {
+        let g = self.sources.read().unwrap();
+        g.iter().find(|src| src.name == name).cloned()
+    }
+
+    pub fn manual(&self) -> &Arc<ControlSource> {
+        &self.manual
+    }
+
+    pub fn active_source(&self) -> Option<String> {
+        let g = self.active.lock().unwrap();
+        g.as_ref().map(|src| src.name.clone())
+    }
+
+    pub fn set_takeover_threshold(&self, threshold: f32) {
+        self.takeover_threshold
+            .store(threshold.abs().to_bits(), Ordering::Relaxed);
+    }
+
+    fn takeover_threshold(&self) -> f32 {
+        f32::from_bits(self.takeover_threshold.load(Ordering::Relaxed))
+    }
+
+    // Picks the source in control and returns its stick values.
+    pub(crate) fn resolve(&self) -> (Stick, SourceKind) {
+        let method_name = "resolve";
+        let sources = self.sources.read().unwrap();
+        let threshold = self.takeover_threshold();
+        let takeover = {
+            let engagements = self.engagements.load(Ordering::Relaxed);
+            let mut reference = self.takeover_reference.lock().unwrap();
+            if reference.0 != engagements {
+                let sticks = sources
+                    .iter()
+                    .filter(|src| src.kind == SourceKind::Manual)
+                    .map(|src| (src.name.clone(), src.stick.load()))
+                    .collect();
+                *reference = (engagements, sticks);
+            }
+            sources
+                .iter()
+                .filter(|src| src.kind == SourceKind::Manual)
+                .any(|src| {
+                    let from = reference
+                        .1
+                        .iter()
+                        .find(|(name, _)| *name == src.name)
+                        .map_or_else(Stick::default, |(_, st)| st.clone());
+                    stick_moved(&from, &src.stick.load()) > threshold
+                })
+        };
+        let mut reason = SwitchReason::Priority;
+        if takeover {
+            for src in sources.iter() {
+                if src.kind == SourceKind::Automatic && src.is_overridable() && src.is_engaged() {
+                    tracing::info!(method_name, src.name, "pilot takes over");
+                    src.release();
+                    reason = SwitchReason::PilotTakeover;
+                }
+            }
+        }
+
+        let mut winner: Option<&Arc<ControlSource>> = None;
+        for src in sources.iter().filter(|src| src.is_engaged()) {
+            if winner.is_none_or(|w| src.priority > w.priority) {
+                winner = Some(src);
+            }
+        }
+        let winner = winner.unwrap_or(&self.manual);
+
+        let mut active = self.active.lock().unwrap();
+        let changed = active
+            .as_ref()
+            .is_none_or(|current| !Arc::ptr_eq(current, winner));
+        if changed {
+            let from = active.as_ref().map(|src| src.name.clone());
+            let to = winner.name.clone();
+            tracing::info!(method_name, ?from, to, ?reason, "control source switch");
+            *active = Some(winner.clone());
+            drop(active);
+            self.events.publish(TelloEvent::ControlSourceChanged {
+                from,
+                to: Some(to),
+                reason,
+            });
+        }
+        (winner.stick.load(), winner.kind)
+    }
+}
+
+// Largest change of a single axis.
+fn stick_moved(from: &Stick, to: &Stick) -> f32 {
+    Stick {
+        rx: to.rx - from.rx,
+        ry: to.ry - from.ry,
+        lx: to.lx - from.lx,
+        ly: to.ly - from.ly,
+    }
+    .max_deflection()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arbiter() -> (ControlArbiter, std::sync::mpsc::Receiver<TelloEvent>) {
+        let events = EventBus::default();
+        let rx = events.subscribe();
+        (ControlArbiter::new(events), rx)
+    }
+
+    #[test]
+    fn test_highest_engaged_priority_wins() {
+        let (arbiter, rx) = arbiter();
+        let autopilot =
+            arbiter.register_internal(AUTOPILOT_SOURCE, PRIORITY_AUTOPILOT, SourceKind::Automatic);
+        autopilot.set_sticks(&Stick::new((0.0, 0.5), (0.0, 0.0)));
+        arbiter
+            .manual()
+            .set_sticks(&Stick::new((0.1, 0.0), (0.0, 0.0)));
+
+        // autopilot not engaged yet
+        assert_eq!(0.1, arbiter.resolve().0.rx);
+        assert_eq!(Some(MANUAL_SOURCE.to_owned()), arbiter.active_source());
+
+        autopilot.engage();
+        assert_eq!(0.5, arbiter.resolve().0.ry);
+        assert_eq!(Some(AUTOPILOT_SOURCE.to_owned()), arbiter.active_source());
+
+        autopilot.release();
+        assert_eq!(0.1, arbiter.resolve().0.rx);
+
+        let switches: Vec<TelloEvent> = rx.try_iter().collect();
+        assert_eq!(3, switches.len());
+        assert_eq!(
+            TelloEvent::ControlSourceChanged {
+                from: Some(MANUAL_SOURCE.to_owned()),
+                to: Some(AUTOPILOT_SOURCE.to_owned()),
+                reason: SwitchReason::Priority,
+            },
+            switches[1]
+        );
+    }
+
+    #[test]
+    fn test_pilot_takeover() {
+        let (arbiter, rx) = arbiter();
+        let autopilot =
+            arbiter.register_internal(AUTOPILOT_SOURCE, PRIORITY_AUTOPILOT, SourceKind::Automatic);
+        let failsafe =
+            arbiter.register_internal(FAILSAFE_SOURCE, PRIORITY_FAILSAFE, SourceKind::Automatic);
+        autopilot.engage();
+        arbiter.resolve();
+
+        // small stick movement doesn't take over
+        arbiter
+            .manual()
+            .set_sticks(&Stick::new((0.1, 0.0), (0.0, 0.0)));
+        arbiter.resolve();
+        assert_eq!(Some(AUTOPILOT_SOURCE.to_owned()), arbiter.active_source());
+
+        arbiter
+            .manual()
+            .set_sticks(&Stick::new((0.0, 0.0), (0.0, -0.8)));
+        assert_eq!(-0.8, arbiter.resolve().0.ly);
+        assert!(!autopilot.is_engaged());
+        assert_eq!(
+            Some(TelloEvent::ControlSourceChanged {
+                from: Some(AUTOPILOT_SOURCE.to_owned()),
+                to: Some(MANUAL_SOURCE.to_owned()),
+                reason: SwitchReason::PilotTakeover,
+            }),
+            rx.try_iter().last()
+        );
+
+        // a landing failsafe can't be overridden by the pilot
+        failsafe.set_overridable(false);
+        failsafe.engage();
+        arbiter.resolve();
+        arbiter
+            .manual()
+            .set_sticks(&Stick::new((0.0, 0.0), (0.0, 0.0)));
+        arbiter.resolve();
+        assert!(failsafe.is_engaged());
+        assert_eq!(Some(FAILSAFE_SOURCE.to_owned()), arbiter.active_source());
+
+        // a hovering one can
+        failsafe.set_overridable(true);
+        arbiter
+            .manual()
+            .set_sticks(&Stick::new((0.0, 0.0), (0.8, 0.0)));
+        arbiter.resolve();
+        assert!(!failsafe.is_engaged());
+        assert_eq!(Some(MANUAL_SOURCE.to_owned()), arbiter.active_source());
+    }
+
+    #[test]
+    fn test_deflected_stick_before_engage_keeps_autopilot() {
+        let (arbiter, _rx) = arbiter();
+        let autopilot =
+            arbiter.register_internal(AUTOPILOT_SOURCE, PRIORITY_AUTOPILOT, SourceKind::Automatic);
+        // a stale forward(0.5)
+        arbiter
+            .manual()
+            .set_sticks(&Stick::new((0.0, 0.5), (0.0, 0.0)));
+        arbiter.resolve();
+        autopilot.engage();
+        arbiter.resolve();
+        arbiter.resolve();
+        assert!(autopilot.is_engaged());
+        assert_eq!(Some(AUTOPILOT_SOURCE.to_owned()), arbiter.active_source());
+
+        // moving the stick from there takes over
+        arbiter
+            .manual()
+            .set_sticks(&Stick::new((0.0, 0.9), (0.0, 0.0)));
+        arbiter.resolve();
+        assert!(!autopilot.is_engaged());
+        assert_eq!(Some(MANUAL_SOURCE.to_owned()), arbiter.active_source());
+    }
+
+    #[test]
+    fn test_register_returns_existing_source() {
+        let (arbiter, _rx) = arbiter();
+        let a = arbiter
+            .register("follow", PRIORITY_AUTOPILOT, SourceKind::Automatic)
+            .unwrap();
+        let b = arbiter
+            .register("follow", 10, SourceKind::Automatic)
+            .unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(PRIORITY_AUTOPILOT, b.priority());
+    }
+
+    #[test]
+    fn test_reserved_names_rejected() {
+        let (arbiter, _rx) = arbiter();
+        let internal =
+            arbiter.register_internal(AUTOPILOT_SOURCE, PRIORITY_AUTOPILOT, SourceKind::Automatic);
+        for name in RESERVED_SOURCES {
+            assert_eq!(
+                Some(RegisterError::ReservedName(name.to_owned())),
+                arbiter.register(name, 10, SourceKind::Automatic).err()
+            );
+        }
+        assert!(Arc::ptr_eq(
+            &internal,
+            &arbiter.source(AUTOPILOT_SOURCE).unwrap()
+        ));
+        assert_eq!(
+            Some(RegisterError::ReservedName(FAILSAFE_SOURCE.to_owned())),
+            arbiter
+                .register(FAILSAFE_SOURCE, PRIORITY_FAILSAFE, SourceKind::Automatic)
+                .err()
+        );
+    }
+}
@@ -0,0 +1,773 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    command::{CommandCompleter, CommandHandle},
+    events::{EventBus, TelloEvent},
+    frame::rotate_xy,
+    phase::{Command, CommandError},
+    pid::{Pid, PidGains},
+    telemetry::Telemetry,
+    tello::Stick,
+    trajectory::Trajectory,
+    utils::Vec3,
+    velocity::{VelocityController, VelocityTarget},
+};
+
+pub const MOVE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const ROTATE_TIMEOUT: Duration = Duration::from_secs(15);
+pub const ALTITUDE_TIMEOUT: Duration = Duration::from_secs(20);
+// added to the trajectory duration
+pub const TRAJECTORY_TIMEOUT: Duration = Duration::from_secs(15);
+pub const RETURN_HOME_TIMEOUT: Duration = Duration::from_secs(120);
+// m above the takeoff point
+pub const DEFAULT_RETURN_HEIGHT: f32 = 1.5;
+// the Tello default when we don't know the height limit of the drone
+pub const DEFAULT_HEIGHT_LIMIT: u8 = 10;
+pub const DEFAULT_POSITION_TOLERANCE: f32 = 0.1;
+pub const DEFAULT_HEADING_TOLERANCE: f64 = 3.0;
+pub const DEFAULT_FEEDBACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_STEP: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ManeuverConfig {
+    // position error in m to stick
+    pub position: PidGains,
+    // heading error in degrees to stick
+    pub heading: PidGains,
+    // the maneuver completes within these tolerances (m, degrees)
+    pub position_tolerance: f32,
+    pub heading_tolerance: f64,
+    // altitude error in m to stick
+    pub altitude: PidGains,
+    pub altitude_tolerance: f32,
+    // weight of the MVO z in the fused altitude, the rest is the barometric height
+    pub mvo_altitude_weight: f32,
+    // trajectory velocity (m/s) to stick, added to the position correction
+    pub feed_forward: f32,
+    pub max_stick: f32,
+    // the maneuver fails when the position or yaw is older than this
+    pub feedback_timeout: Duration,
+    // return_home() flies back at this height above the takeoff point (m)
+    pub return_height: f32,
+}
+
+impl Default for ManeuverConfig {
+    fn default() -> Self {
+        Self {
+            position: PidGains::new(0.8, 0.1, 0.2),
+            heading: PidGains::new(0.02, 0.0, 0.002),
+            position_tolerance: DEFAULT_POSITION_TOLERANCE,
+            heading_tolerance: DEFAULT_HEADING_TOLERANCE,
+            altitude: PidGains::new(1.0, 0.2, 0.1),
+            altitude_tolerance: DEFAULT_POSITION_TOLERANCE,
+            mvo_altitude_weight: 0.7,
+            feed_forward: 0.3,
+            max_stick: 0.5,
+            feedback_timeout: DEFAULT_FEEDBACK_TIMEOUT,
+            return_height: DEFAULT_RETURN_HEIGHT,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Goal {
+    // MVO position (x forward, y right, z down of the takeoff frame)
+    Position(Vec3<f32>),
+    // IMU yaw in degrees
+    Heading(f64),
+    // height above the takeoff point in m, kept until stopped
+    Altitude {
+        target: f32,
+        converged: bool,
+        reached: bool,
+    },
+    Trajectory(Follow),
+}
+
+#[derive(Debug)]
+struct Follow {
+    trajectory: Trajectory,
+    // MVO position, heading relative to the takeoff and IMU yaw at the start
+    origin: Vec3<f32>,
+    heading: f64,
+    yaw: Option<f64>,
+    started: Instant,
+}
+
+impl Follow {
+    // Trajectory point to the MVO position.
+    fn to_mvo(&self, p: &Vec3<f32>) -> Vec3<f32> {
+        let (forward, right) = rotate_xy(p.x, p.y, -self.heading);
+        Vec3::new(
+            self.origin.x + forward,
+            self.origin.y + right,
+            self.origin.z - p.z,
+        )
+    }
+}
+
+// A maneuver stopped by Autopilot::pause(), resume() flies the rest of it.
+#[derive(Debug)]
+pub(crate) struct PausedManeuver {
+    command: Command,
+    goal: Goal,
+    paused_at: Instant,
+}
+
+impl PausedManeuver {
+    pub(crate) fn command(&self) -> Command {
+        self.command
+    }
+}
+
+#[derive(Debug)]
+struct Maneuver {
+    command: Command,
+    goal: Goal,
+    completer: CommandCompleter,
+    forward: Pid,
+    right: Pid,
+    up: Pid,
+    yaw: Pid,
+    last_step: Option<Instant>,
+}
+
+impl Maneuver {
+    fn new(
+        command: Command,
+        goal: Goal,
+        config: &ManeuverConfig,
+        completer: CommandCompleter,
+    ) -> Self {
+        let up = match goal {
+            Goal::Altitude { .. } => config.altitude,
+            _ => config.position,
+        };
+        Self {
+            command,
+            goal,
+            completer,
+            forward: Pid::new(config.position, config.max_stick),
+            right: Pid::new(config.position, config.max_stick),
+            up: Pid::new(up, config.max_stick),
+            yaw: Pid::new(config.heading, config.max_stick),
+            last_step: None,
+        }
+    }
+
+    fn step(&mut self, now: Instant) -> f32 {
+        let dt = match self.last_step {
+            Some(last) => now.saturating_duration_since(last).min(MAX_STEP),
+            None => Duration::ZERO,
+        };
+        self.last_step = Some(now);
+        dt.as_secs_f32()
+    }
+}
+
+// Runs the automatic stick controllers, only one of them (a velocity target
+// or a maneuver) is active at a time, a new one replaces the previous one.
+#[derive(Debug, Default)]
+pub(crate) struct Autopilot {
+    config: ManeuverConfig,
+    velocity: VelocityController,
+    maneuver: Option<Maneuver>,
+    events: EventBus,
+}
+
+impl Autopilot {
+    pub(crate) fn new(events: EventBus) -> Self {
+        Self {
+            events,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn config(&self) -> ManeuverConfig {
+        self.config
+    }
+
+    pub(crate) fn set_config(&mut self, config: ManeuverConfig) {
+        self.config = config;
+    }
+
+    pub(crate) fn velocity(&mut self) -> &mut VelocityController {
+        &mut self.velocity
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.maneuver.is_some() || self.velocity.is_active()
+    }
+
+    pub(crate) fn set_velocity(&mut self, target: VelocityTarget) {
+        self.abort_maneuver();
+        self.velocity.set_target(target);
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.abort_maneuver();
+        self.velocity.clear();
+    }
+
+    fn abort_maneuver(&mut self) {
+        if let Some(m) = self.maneuver.take() {
+            let command = m.command;
+            if m.completer.fail(CommandError::Aborted { command }) {
+                tracing::info!(?command, "maneuver aborted");
+            }
+        }
+    }
+
+    // Stops the running maneuver, its handle fails with Aborted.
+    pub(crate) fn pause(&mut self, now: Instant) -> Option<PausedManeuver> {
+        self.velocity.clear();
+        let m = self.maneuver.take()?;
+        let command = m.command;
+        m.completer.fail(CommandError::Aborted { command });
+        tracing::info!(?command, "maneuver paused");
+        Some(PausedManeuver {
+            command,
+            goal: m.goal,
+            paused_at: now,
+        })
+    }
+
+    // Restarts a paused maneuver towards the same goal, a trajectory goes on
+    // from the point where it was paused.
+    pub(crate) fn resume(
+        &mut self,
+        paused: PausedManeuver,
+        telemetry: &Telemetry,
+        now: Instant,
+    ) -> Result<CommandHandle, CommandError> {
+        let PausedManeuver {
+            command,
+            goal,
+            paused_at,
+        } = paused;
+        let feedback = match goal {
+            Goal::Heading(_) => self.yaw(telemetry, now).is_some(),
+            Goal::Altitude { .. } => self.altitude(telemetry, now).is_some(),
+            _ => self.position(telemetry, now).is_some(),
+        };
+        if !feedback {
+            return Err(CommandError::NoTelemetry { command });
+        }
+        let (goal, timeout) = match goal {
+            Goal::Position(target) => (Goal::Position(target), MOVE_TIMEOUT),
+            Goal::Heading(yaw) => (Goal::Heading(yaw), ROTATE_TIMEOUT),
+            Goal::Altitude { target, .. } => (altitude_goal(target), ALTITUDE_TIMEOUT),
+            Goal::Trajectory(mut follow) => {
+                follow.started += now.saturating_duration_since(paused_at);
+                let left = follow
+                    .trajectory
+                    .duration()
+                    .saturating_sub(now.saturating_duration_since(follow.started));
+                (Goal::Trajectory(follow), left + TRAJECTORY_TIMEOUT)
+            }
+        };
+        Ok(self.start(command, goal, timeout))
+    }
+
+    fn start(&mut self, command: Command, goal: Goal, timeout: Duration) -> CommandHandle {
+        let (handle, completer) = CommandHandle::new(command, timeout);
+        self.start_with(command, goal, completer);
+        handle
+    }
+
+    fn start_with(&mut self, command: Command, goal: Goal, completer: CommandCompleter) {
+        tracing::info!(?command, ?goal, "maneuver started");
+        self.stop();
+        self.maneuver = Some(Maneuver::new(command, goal, &self.config, completer));
+    }
+
+    // Moves by (forward, right, up) meters of the current drone heading,
+    // `heading` is the drone heading relative to the takeoff heading.
+    pub(crate) fn move_by(
+        &mut self,
+        offset: Vec3<f32>,
+        telemetry: &Telemetry,
+        heading: Option<f64>,
+        now: Instant,
+    ) -> Result<CommandHandle, CommandError> {
+        let command = Command::MoveBy;
+        let start = self.position(telemetry, now);
+        let (start, heading) = match (start, heading) {
+            (Some(start), Some(heading)) => (start, heading),
+            _ => return Err(CommandError::NoTelemetry { command }),
+        };
+        let (forward, right) = rotate_xy(offset.x, offset.y, -heading);
+        let target = Vec3::new(start.x + forward, start.y + right, start.z - offset.z);
+        Ok(self.start(command, Goal::Position(target), MOVE_TIMEOUT))
+    }
+
+    // Flies to the MVO position (z down).
+    pub(crate) fn fly_to(
+        &mut self,
+        command: Command,
+        target: Vec3<f32>,
+        telemetry: &Telemetry,
+        now: Instant,
+    ) -> Result<CommandHandle, CommandError> {
+        if self.position(telemetry, now).is_none() {
+            return Err(CommandError::NoTelemetry { command });
+        }
+        Ok(self.start(command, Goal::Position(target), MOVE_TIMEOUT))
+    }
+
+    pub(crate) fn rotate_to(
+        &mut self,
+        yaw: f64,
+        telemetry: &Telemetry,
+        now: Instant,
+    ) -> Result<CommandHandle, CommandError> {
+        let command = Command::Rotate;
+        if self.yaw(telemetry, now).is_none() {
+            return Err(CommandError::NoTelemetry { command });
+        }
+        Ok(self.start(command, Goal::Heading(wrap_deg(yaw)), ROTATE_TIMEOUT))
+    }
+
+    pub(crate) fn rotate_by(
+        &mut self,
+        deg: f64,
+        telemetry: &Telemetry,
+        now: Instant,
+    ) -> Result<CommandHandle, CommandError> {
+        match self.yaw(telemetry, now) {
+            Some(yaw) => self.rotate_to(yaw + deg, telemetry, now),
+            None => Err(CommandError::NoTelemetry {
+                command: Command::Rotate,
+            }),
+        }
+    }
+
+    // Holds the height above the takeoff point (m), the handle completes once
+    // the altitude converges, the hold goes on until stopped.
+    pub(crate) fn hold_altitude(
+        &mut self,
+        height: f32,
+        telemetry: &Telemetry,
+        now: Instant,
+    ) -> Result<CommandHandle, CommandError> {
+        let command = Command::HoldAltitude;
+        if self.altitude(telemetry, now).is_none() {
+            return Err(CommandError::NoTelemetry { command });
+        }
+        Ok(self.start(command, altitude_goal(height), ALTITUDE_TIMEOUT))
+    }
+
+    // Same as hold_altitude(), completing a handle created earlier (takeoff_to()).
+    pub(crate) fn climb_to(&mut self, command: Command, height: f32, completer: CommandCompleter) {
+        self.start_with(command, altitude_goal(height), completer);
+    }
+
+    // Follows the trajectory relative to the current position and heading.
+    pub(crate) fn follow(
+        &mut self,
+        trajectory: Trajectory,
+        telemetry: &Telemetry,
+        heading: Option<f64>,
+        now: Instant,
+    ) -> Result<CommandHandle, CommandError> {
+        let command = Command::FollowTrajectory;
+        let (origin, heading) = match (self.position(telemetry, now), heading) {
+            (Some(origin), Some(heading)) => (origin, heading),
+            _ => return Err(CommandError::NoTelemetry { command }),
+        };
+        let yaw = self.yaw(telemetry, now);
+        let needs_yaw = trajectory.waypoints().iter().any(|wp| wp.yaw.is_some());
+        if needs_yaw && yaw.is_none() {
+            return Err(CommandError::NoTelemetry { command });
+        }
+        let timeout = trajectory.duration() + TRAJECTORY_TIMEOUT;
+        let follow = Follow {
+            trajectory,
+            origin,
+            heading,
+            yaw,
+            started: now,
+        };
+        Ok(self.start(command, Goal::Trajectory(follow), timeout))
+    }
+
+    // The MVO position, None when older than ManeuverConfig::feedback_timeout.
+    pub(crate) fn position(&self, telemetry: &Telemetry, now: Instant) -> Option<Vec3<f32>> {
+        let (mvo, at) = telemetry.mvo()?;
+        if now.saturating_duration_since(at) > self.config.feedback_timeout {
+            return None;
+        }
+        mvo.position
+    }
+
+    fn yaw(&self, telemetry: &Telemetry, now: Instant) -> Option<f64> {
+        let (yaw, at) = telemetry.yaw_sample()?;
+        if now.saturating_duration_since(at) > self.config.feedback_timeout {
+            return None;
+        }
+        Some(yaw)
+    }
+
+    // Barometric height fused with the MVO z (which points down).
+    fn altitude(&self, telemetry: &Telemetry, now: Instant) -> Option<f32> {
+        let fresh = telemetry
+            .flight_updated
+            .is_some_and(|at| now.saturating_duration_since(at) <= self.config.feedback_timeout);
+        let baro = match telemetry.flight {
+            Some(ref fd) if fresh => Some(fd.height as f32 / 10.0),
+            _ => None,
+        };
+        let mvo = self.position(telemetry, now).map(|pos| -pos.z);
+        match (baro, mvo) {
+            (Some(baro), Some(mvo)) => {
+                let w = self.config.mvo_altitude_weight.clamp(0.0, 1.0);
+                Some(w * mvo + (1.0 - w) * baro)
+            }
+            (baro, mvo) => baro.or(mvo),
+        }
+    }
+
+    // One control step, returns None when there is nothing to control.
+    pub(crate) fn update(
+        &mut self,
+        telemetry: &Telemetry,
+        heading: Option<f64>,
+        now: Instant,
+    ) -> Option<Stick> {
+        let method_name = "autopilot";
+        let mut m = match self.maneuver.take() {
+            Some(m) => m,
+            None => return self.velocity.update(telemetry.mvo(), heading, now),
+        };
+        let holding = matches!(m.goal, Goal::Altitude { reached: true, .. });
+        if m.completer.is_resolved() && !holding {
+            // timed out
+            return None;
+        }
+        let command = m.command;
+        let dt = m.step(now);
+        let st = match &mut m.goal {
+            Goal::Position(target) => {
+                let pos = self.position(telemetry, now);
+                let (pos, heading) = match (pos, heading) {
+                    (Some(pos), Some(heading)) => (pos, heading),
+                    _ => {
+                        tracing::warn!(method_name, ?command, "position feedback lost");
+                        m.completer.fail(CommandError::FeedbackLost { command });
+                        return Some(Stick::default());
+                    }
+                };
+                let (ex, ey, eup) = (target.x - pos.x, target.y - pos.y, pos.z - target.z);
+                if (ex * ex + ey * ey + eup * eup).sqrt() <= self.config.position_tolerance {
+                    tracing::info!(method_name, ?command, "position reached");
+                    m.completer.complete();
+                    return Some(Stick::default());
+                }
+                let (forward, right) = rotate_xy(ex, ey, heading);
+                Stick::new(
+                    (m.right.update(right, dt), m.forward.update(forward, dt)),
+                    (0.0, m.up.update(eup, dt)),
+                )
+            }
+            Goal::Trajectory(follow) => {
+                let pos = self.position(telemetry, now);
+                let (pos, heading) = match (pos, heading) {
+                    (Some(pos), Some(heading)) => (pos, heading),
+                    _ => {
+                        tracing::warn!(method_name, ?command, "position feedback lost");
+                        m.completer.fail(CommandError::FeedbackLost { command });
+                        return Some(Stick::default());
+                    }
+                };
+                let elapsed = now.saturating_duration_since(follow.started);
+                let sample = follow.trajectory.sample(elapsed);
+                let target = follow.to_mvo(&sample.position);
+                let (ex, ey, eup) = (target.x - pos.x, target.y - pos.y, pos.z - target.z);
+                let done = elapsed >= follow.trajectory.duration();
+                if done && (ex * ex + ey * ey + eup * eup).sqrt() <= self.config.position_tolerance
+                {
+                    tracing::info!(method_name, ?command, "trajectory finished");
+                    m.completer.complete();
+                    return Some(Stick::default());
+                }
+                let lx = match (sample.yaw, follow.yaw) {
+                    (Some(target), Some(start)) => match self.yaw(telemetry, now) {
+                        Some(yaw) => m.yaw.update(wrap_deg(start + target - yaw) as f32, dt),
+                        None => {
+                            tracing::warn!(method_name, ?command, "heading feedback lost");
+                            m.completer.fail(CommandError::FeedbackLost { command });
+                            return Some(Stick::default());
+                        }
+                    },
+                    _ => 0.0,
+                };
+                // the velocity from the trajectory frame to the body frame
+                let v = &sample.velocity;
+                let (vf, vr) = rotate_xy(v.x, v.y, -follow.heading);
+                let (vf, vr) = rotate_xy(vf, vr, heading);
+                let (ef, er) = rotate_xy(ex, ey, heading);
+                let ff = self.config.feed_forward;
+                let max = self.config.max_stick;
+                Stick::new(
+                    (
+                        (ff * vr + m.right.update(er, dt)).clamp(-max, max),
+                        (ff * vf + m.forward.update(ef, dt)).clamp(-max, max),
+                    ),
+                    (lx, (ff * v.z + m.up.update(eup, dt)).clamp(-max, max)),
+                )
+            }
+            Goal::Heading(target) => {
+                let yaw = match self.yaw(telemetry, now) {
+                    Some(yaw) => yaw,
+                    None => {
+                        tracing::warn!(method_name, ?command, "heading feedback lost");
+                        m.completer.fail(CommandError::FeedbackLost { command });
+                        return Some(Stick::default());
+                    }
+                };
+                let error = wrap_deg(*target - yaw);
+                if error.abs() <= self.config.heading_tolerance {
+                    tracing::info!(method_name, ?command, "heading reached");
+                    m.completer.complete();
+                    return Some(Stick::default());
+                }
+                Stick::new((0.0, 0.0), (m.yaw.update(error as f32, dt), 0.0))
+            }
+            Goal::Altitude {
+                target,
+                converged,
+                reached,
+            } => {
+                let height = match self.altitude(telemetry, now) {
+                    Some(height) => height,
+                    None => {
+                        tracing::warn!(method_name, ?command, "altitude feedback lost");
+                        m.completer.fail(CommandError::FeedbackLost { command });
+                        return Some(Stick::default());
+                    }
+                };
+                let error = *target - height;
+                let tolerance = self.config.altitude_tolerance;
+                if !*converged && error.abs() <= tolerance {
+                    tracing::info!(method_name, ?command, target, height, "altitude converged");
+                    *converged = true;
+                    *reached = true;
+                    m.completer.complete();
+                    self.events.publish(TelloEvent::AltitudeConverged {
+                        target: *target,
+                        height,
+                    });
+                } else if *converged && error.abs() > 2.0 * tolerance {
+                    tracing::info!(method_name, ?command, target, height, "altitude diverged");
+                    *converged = false;
+                }
+                Stick::new((0.0, 0.0), (0.0, m.up.update(error, dt)))
+            }
+        };
+        self.maneuver = Some(m);
+        Some(st)
+    }
+}
+
+fn altitude_goal(target: f32) -> Goal {
+    Goal::Altitude {
+        target,
+        converged: false,
+        reached: false,
+    }
+}
+
+// Wraps the angle to -180..180 degrees.
+fn wrap_deg(deg: f64) -> f64 {
+    let deg = (deg + 180.0).rem_euclid(360.0) - 180.0;
+    if deg == -180.0 {
+        180.0
+    } else {
+        deg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::{IMUData, MVOData};
+
+    use super::*;
+
+    fn telemetry(position: Option<Vec3<f32>>, yaw: f64, at: Instant) -> Telemetry {
+        let mut t = Telemetry::default();
+        t.mvo = Some(MVOData {
+            position,
+            vx: Some(0),
+            vy: Some(0),
+            vz: Some(0),
+        });
+        t.mvo_updated = Some(at);
+        t.imu = Some(IMUData {
+            roll: 0.0,
+            pitch: 0.0,
+            yaw,
+            temperature: 0,
+        });
+        t.imu_updated = Some(at);
+        t
+    }
+
+    #[test]
+    fn test_move_by_completes() {
+        let mut ap = Autopilot::default();
+        let now = Instant::now();
+        let t = telemetry(Some(Vec3::new(0.0, 0.0, 0.0)), 90.0, now);
+        // the drone faces right of the takeoff heading
+        let handle = ap
+            .move_by(Vec3::new(1.0, 0.0, 0.5), &t, Some(90.0), now)
+            .unwrap();
+        let st = ap.update(&t, Some(90.0), now).unwrap();
+        assert!(st.ry > 0.0 && st.rx.abs() < 1e-5, "{:?}", st);
+        assert!(st.ly > 0.0);
+        assert!(!handle.is_done());
+
+        // 1m to the right of the takeoff frame, 0.5m up (z points down)
+        let t = telemetry(Some(Vec3::new(0.0, 0.95, -0.5)), 90.0, now);
+        ap.update(&t, Some(90.0), now).unwrap();
+        assert_eq!(Some(Ok(())), handle.try_result());
+        assert!(!ap.is_active());
+        assert!(ap.update(&t, Some(90.0), now).is_none());
+    }
+
+    #[test]
+    fn test_pause_mid_move() {
+        let mut ap = Autopilot::default();
+        let now = Instant::now();
+        let t = telemetry(Some(Vec3::new(0.0, 0.0, 0.0)), 0.0, now);
+        let handle = ap
+            .move_by(Vec3::new(2.0, 0.0, 0.0), &t, Some(0.0), now)
+            .unwrap();
+        // halfway
+        let t = telemetry(Some(Vec3::new(1.0, 0.0, 0.0)), 0.0, now);
+        ap.update(&t, Some(0.0), now).unwrap();
+        let paused = ap.pause(now).unwrap();
+        assert_eq!(
+            Some(Err(CommandError::Aborted {
+                command: Command::MoveBy
+            })),
+            handle.try_result()
+        );
+        assert!(!ap.is_active());
+        assert!(ap.update(&t, Some(0.0), now).is_none());
+
+        // the rest of the move, not the whole offset again
+        let later = now + Duration::from_secs(5);
+        let t = telemetry(Some(Vec3::new(1.0, 0.0, 0.0)), 0.0, later);
+        let handle = ap.resume(paused, &t, later).unwrap();
+        assert!(ap.update(&t, Some(0.0), later).unwrap().ry > 0.0);
+        let t = telemetry(Some(Vec3::new(1.98, 0.0, 0.0)), 0.0, later);
+        ap.update(&t, Some(0.0), later).unwrap();
+        assert_eq!(Some(Ok(())), handle.try_result());
+    }
+
+    #[test]
+    fn test_move_aborts_without_position() {
+        let mut ap = Autopilot::default();
+        let now = Instant::now();
+        let t = telemetry(Some(Vec3::new(0.0, 0.0, 0.0)), 0.0, now);
+        let handle = ap
+            .move_by(Vec3::new(1.0, 0.0, 0.0), &t, Some(0.0), now)
+            .unwrap();
+        let t = telemetry(None, 0.0, now);
+        assert_eq!(0.0, ap.update(&t, Some(0.0), now).unwrap().max_deflection());
+        assert_eq!(
+            Some(Err(CommandError::FeedbackLost {
+                command: Command::MoveBy
+            })),
+            handle.try_result()
+        );
+        assert!(ap
+            .move_by(Vec3::new(1.0, 0.0, 0.0), &t, Some(0.0), now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_altitude_hold_fuses_height() {
+        let events = EventBus::default();
+        let rx = events.subscribe();
+        let mut ap = Autopilot::new(events);
+        let now = Instant::now();
+        // barometric 1.0m, MVO 1.2m
+        let mut t = telemetry(Some(Vec3::new(0.0, 0.0, -1.2)), 0.0, now);
+        let mut pl = vec![0u8; 24];
+        pl[0] = 10;
+        t.flight = Some(crate::messages::FlightData::new(&pl));
+        t.flight_updated = Some(now);
+        assert!((ap.altitude(&t, now).unwrap() - 1.14).abs() < 1e-5);
+
+        let handle = ap.hold_altitude(1.5, &t, now).unwrap();
+        assert!(ap.update(&t, None, now).unwrap().ly > 0.0);
+        assert!(rx.try_recv().is_err());
+
+        let t = telemetry(Some(Vec3::new(0.0, 0.0, -1.45)), 0.0, now);
+        ap.update(&t, None, now).unwrap();
+        assert_eq!(Some(Ok(())), handle.try_result());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(TelloEvent::AltitudeConverged { target, .. }) if target == 1.5
+        ));
+        // keeps holding after the convergence
+        let t = telemetry(Some(Vec3::new(0.0, 0.0, -1.8)), 0.0, now);
+        assert!(ap.update(&t, None, now).unwrap().ly < 0.0);
+        assert!(ap.is_active());
+    }
+
+    #[test]
+    fn test_follow_trajectory() {
+        use crate::trajectory::{Interpolation, Waypoint};
+
+        let mut ap = Autopilot::default();
+        let now = Instant::now();
+        let tr = Trajectory::new(
+            vec![
+                Waypoint::new(Duration::ZERO, Vec3::new(0.0, 0.0, 0.0), None),
+                Waypoint::new(Duration::from_secs(2), Vec3::new(1.0, 0.0, 0.0), None),
+            ],
+            Interpolation::MinJerk,
+        )
+        .unwrap();
+        let t = telemetry(Some(Vec3::new(0.0, 0.0, 0.0)), 0.0, now);
+        let handle = ap.follow(tr, &t, Some(0.0), now).unwrap();
+        // at rest on the start point
+        assert_eq!(0.0, ap.update(&t, Some(0.0), now).unwrap().max_deflection());
+        // in the middle, exactly on the path: just the feed forward
+        let mid = now + Duration::from_secs(1);
+        let t = telemetry(Some(Vec3::new(0.5, 0.0, 0.0)), 0.0, mid);
+        let st = ap.update(&t, Some(0.0), mid).unwrap();
+        assert!((st.ry - 0.3 * 0.9375).abs() < 1e-4, "{:?}", st);
+        assert!(!handle.is_done());
+
+        let end = now + Duration::from_secs(2);
+        let t = telemetry(Some(Vec3::new(1.0, 0.0, 0.0)), 0.0, end);
+        ap.update(&t, Some(0.0), end);
+        assert_eq!(Some(Ok(())), handle.try_result());
+    }
+
+    #[test]
+    fn test_rotate_the_short_way() {
+        let mut ap = Autopilot::default();
+        let now = Instant::now();
+        let t = telemetry(None, 170.0, now);
+        let first = ap.rotate_by(20.0, &t, now).unwrap();
+        // crossing +-180 turns clockwise
+        assert!(ap.update(&t, None, now).unwrap().lx > 0.0);
+
+        let second = ap.rotate_to(-170.0, &t, now).unwrap();
+        assert_eq!(
+            Some(Err(CommandError::Aborted {
+                command: Command::Rotate
+            })),
+            first.try_result()
+        );
+        ap.update(&telemetry(None, -171.0, now), None, now);
+        assert_eq!(Some(Ok(())), second.try_result());
+        assert_eq!(180.0, wrap_deg(-180.0));
+        assert_eq!(-90.0, wrap_deg(270.0));
+    }
+}
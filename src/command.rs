@@ -0,0 +1,438 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, OnceLock, Weak,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    messages::FlightData,
+    phase::{Command, CommandError},
+};
+
+pub const TAKEOFF_TIMEOUT: Duration = Duration::from_secs(10);
+pub const THROW_TAKEOFF_TIMEOUT: Duration = Duration::from_secs(20);
+pub const LAND_TIMEOUT: Duration = Duration::from_secs(15);
+pub const FLIP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type CommandResult = Result<(), CommandError>;
+
+#[derive(Debug)]
+struct Shared {
+    command: Command,
+    deadline: Instant,
+    result: Mutex<Option<CommandResult>>,
+    done: Condvar,
+    // every task awaiting a clone of the handle
+    wakers: Mutex<Vec<Waker>>,
+    // handed to the deadline timer by the first poll
+    timer_registered: AtomicBool,
+}
+
+impl Shared {
+    // The first result wins, later ones (e.g. the timeout) are ignored.
+    fn resolve(&self, result: CommandResult) -> bool {
+        let mut g = self.result.lock().unwrap();
+        if g.is_some() {
+            return false;
+        }
+        *g = Some(result);
+        drop(g);
+        self.done.notify_all();
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+        true
+    }
+
+    // Fails the command once past the deadline. The blocking waiters, the stick
+    // loop and CommandWatcher::expire() check it, the DeadlineTimer does it for
+    // the awaited handles.
+    fn expire(&self) {
+        if Instant::now() < self.deadline {
+            return;
+        }
+        let command = self.command;
+        if self.resolve(Err(CommandError::Timeout { command })) {
+            tracing::warn!(?command, "command timed out");
+        }
+    }
+
+    fn is_resolved(&self) -> bool {
+        self.expire();
+        self.result.lock().unwrap().is_some()
+    }
+}
+
+// Resolves once the drone state confirms the command outcome, or fails
+// on timeout. Wait for it with wait() or .await it.
+#[derive(Debug, Clone)]
+pub struct CommandHandle {
+    command: Command,
+    deadline: Instant,
+    shared: Arc<Shared>,
+}
+
+impl CommandHandle {
+    pub(crate) fn new(command: Command, timeout: Duration) -> (Self, CommandCompleter) {
+        let deadline = Instant::now() + timeout;
+        let shared = Arc::new(Shared {
+            command,
+            deadline,
+            result: Mutex::new(None),
+            done: Condvar::new(),
+            wakers: Mutex::new(vec![]),
+            timer_registered: AtomicBool::new(false),
+        });
+        let handle = Self {
+            command,
+            deadline,
+            shared: shared.clone(),
+        };
+        let completer = CommandCompleter { command, shared };
+        (handle, completer)
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.shared.is_resolved()
+    }
+
+    pub fn try_result(&self) -> Option<CommandResult> {
+        self.shared.expire();
+        self.shared.result.lock().unwrap().clone()
+    }
+
+    // Blocks until the command completes or fails.
+    pub fn wait(&self) -> CommandResult {
+        loop {
+            let left = self.deadline.saturating_duration_since(Instant::now());
+            if let Some(result) = self.wait_timeout(left) {
+                return result;
+            }
+        }
+    }
+
+    // Like wait(), but gives up after `timeout` and returns None if the command is still running.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<CommandResult> {
+        let timeout = timeout.min(self.deadline.saturating_duration_since(Instant::now()));
+        let g = self.shared.result.lock().unwrap();
+        let (g, _) = self
+            .shared
+            .done
+            .wait_timeout_while(g, timeout, |result| result.is_none())
+            .unwrap();
+        if let Some(ref result) = *g {
+            return Some(result.clone());
+        }
+        drop(g);
+        self.try_result()
+    }
+}
+
+impl Future for CommandHandle {
+    type Output = CommandResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // register the waker first, so we don't miss a resolve() between the check and the registration
+        {
+            let mut wakers = self.shared.wakers.lock().unwrap();
+            if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        }
+        match self.try_result() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                if !self.shared.timer_registered.swap(true, Ordering::Relaxed) {
+                    DeadlineTimer::get().register(&self.shared);
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Expires the awaited handles at their deadline, so a future resolves without
+// the stick loop running. One thread for all handles, started by the first poll.
+struct DeadlineTimer {
+    pending: Mutex<Vec<Weak<Shared>>>,
+    changed: Condvar,
+}
+
+impl DeadlineTimer {
+    fn get() -> &'static DeadlineTimer {
+        static TIMER: OnceLock<DeadlineTimer> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            thread::spawn(|| DeadlineTimer::get().run());
+            DeadlineTimer {
+                pending: Mutex::new(vec![]),
+                changed: Condvar::new(),
+            }
+        })
+    }
+
+    fn register(&self, shared: &Arc<Shared>) {
+        self.pending.lock().unwrap().push(Arc::downgrade(shared));
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            // drops the resolved (or expired) and the forgotten handles
+            pending.retain(|shared| shared.upgrade().is_some_and(|s| !s.is_resolved()));
+            let next = pending
+                .iter()
+                .filter_map(|shared| shared.upgrade().map(|s| s.deadline))
+                .min();
+            pending = match next {
+                Some(next) => {
+                    let left = next.saturating_duration_since(Instant::now());
+                    self.changed.wait_timeout(pending, left).unwrap().0
+                }
+                None => self.changed.wait(pending).unwrap(),
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CommandCompleter {
+    command: Command,
+    shared: Arc<Shared>,
+}
+
+impl CommandCompleter {
+    pub(crate) fn complete(&self) -> bool {
+        self.shared.resolve(Ok(()))
+    }
+
+    pub(crate) fn fail(&self, err: CommandError) -> bool {
+        self.shared.resolve(Err(err))
+    }
+
+    pub(crate) fn is_resolved(&self) -> bool {
+        self.shared.is_resolved()
+    }
+}
+
+pub(crate) type FlightPredicate = Box<dyn FnMut(&FlightData) -> bool + Send>;
+
+struct CommandWatch {
+    predicate: FlightPredicate,
+    completer: CommandCompleter,
+}
+
+// Pending commands waiting for the flight status to confirm them.
+#[derive(Default)]
+pub(crate) struct CommandWatcher {
+    watches: Mutex<Vec<CommandWatch>>,
+}
+
+impl std::fmt::Debug for CommandWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pending = self.watches.lock().unwrap().len();
+        f.debug_struct("CommandWatcher")
+            .field("pending", &pending)
+            .finish()
+    }
+}
+
+impl CommandWatcher {
+    pub(crate) fn watch(
+        &self,
+        command: Command,
+        predicate: FlightPredicate,
+        timeout: Duration,
+    ) -> CommandHandle {
+        let (handle, completer) = CommandHandle::new(command, timeout);
+        self.watches.lock().unwrap().push(CommandWatch {
+            predicate,
+            completer,
+        });
+        handle
+    }
+
+    // Drops the timed out commands, waking their waiters. Called by the stick
+    // loop, so the timeouts fire without flight data too.
+    pub(crate) fn expire(&self) {
+        self.watches
+            .lock()
+            .unwrap()
+            .retain(|watch| !watch.completer.is_resolved());
+    }
+
+    pub(crate) fn on_flight_data(&self, fd: &FlightData) {
+        let method_name = "on_flight_data";
+        let mut g = self.watches.lock().unwrap();
+        g.retain_mut(|watch| {
+            if watch.completer.is_resolved() {
+                return false;
+            }
+            if (watch.predicate)(fd) {
+                tracing::info!(method_name, command = ?watch.completer.command, "command completed");
+                watch.completer.complete();
+                return false;
+            }
+            true
+        });
+    }
+}
+
+pub(crate) fn takeoff_done() -> FlightPredicate {
+    Box::new(|fd| fd.flying && fd.drone_hover)
+}
+
+pub(crate) fn throw_takeoff_done() -> FlightPredicate {
+    Box::new(|fd| fd.flying)
+}
+
+pub(crate) fn land_done() -> FlightPredicate {
+    Box::new(|fd| fd.on_ground && !fd.flying)
+}
+
+// the drone lands in the hand, so it never reports being on the ground
+pub(crate) fn palm_land_done() -> FlightPredicate {
+    Box::new(|fd| !fd.flying)
+}
+
+// the drone leaves the hover state during the flip, the flip is done once it hovers again
+pub(crate) fn flip_done() -> FlightPredicate {
+    let mut flipping = false;
+    Box::new(move |fd| {
+        if !fd.drone_hover {
+            flipping = true;
+        }
+        flipping && fd.drone_hover
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        task::{Wake, Waker},
+        thread,
+    };
+
+    use super::*;
+
+    fn flight_data(flying: bool, on_ground: bool, hover: bool) -> FlightData {
+        let mut pl = vec![0u8; 24];
+        pl[17] = (flying as u8) | (on_ground as u8) << 1 | (hover as u8) << 3;
+        FlightData::new(&pl)
+    }
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = Box::pin(fut);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_resolves_on_flight_data() {
+        let watcher = CommandWatcher::default();
+        let handle = watcher.watch(Command::Takeoff, takeoff_done(), TAKEOFF_TIMEOUT);
+        watcher.on_flight_data(&flight_data(true, false, false));
+        assert!(!handle.is_done());
+        watcher.on_flight_data(&flight_data(true, false, true));
+        assert_eq!(Ok(()), handle.wait());
+        assert_eq!(0, watcher.watches.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_flip_needs_to_leave_hover() {
+        let watcher = CommandWatcher::default();
+        let handle = watcher.watch(
+            Command::Flip(crate::messages::FlipDirection::Left),
+            flip_done(),
+            FLIP_TIMEOUT,
+        );
+        watcher.on_flight_data(&flight_data(true, false, true));
+        assert!(!handle.is_done());
+        watcher.on_flight_data(&flight_data(true, false, false));
+        watcher.on_flight_data(&flight_data(true, false, true));
+        assert_eq!(Some(Ok(())), handle.try_result());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let watcher = CommandWatcher::default();
+        let handle = watcher.watch(Command::Land, land_done(), Duration::from_millis(20));
+        assert_eq!(None, handle.wait_timeout(Duration::from_millis(1)));
+        // the stick loop expiring it wakes the pending future
+        let watcher = Arc::new(watcher);
+        let w = watcher.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            w.expire();
+        });
+        assert_eq!(
+            Err(CommandError::Timeout {
+                command: Command::Land
+            }),
+            block_on(handle.clone())
+        );
+        t.join().unwrap();
+        assert_eq!(0, watcher.watches.lock().unwrap().len());
+        // late confirmation doesn't change the result
+        watcher.on_flight_data(&flight_data(false, true, false));
+        assert!(handle.wait().is_err());
+    }
+
+    #[test]
+    fn test_await_times_out_without_stick_loop() {
+        let watcher = CommandWatcher::default();
+        let handle = watcher.watch(Command::Land, land_done(), Duration::from_millis(20));
+        assert_eq!(
+            Err(CommandError::Timeout {
+                command: Command::Land
+            }),
+            block_on(handle)
+        );
+    }
+
+    #[test]
+    fn test_await_from_other_thread() {
+        let watcher = Arc::new(CommandWatcher::default());
+        let handle = watcher.watch(Command::Land, land_done(), LAND_TIMEOUT);
+        // a second task awaiting a clone
+        let other = handle.clone();
+        let t2 = thread::spawn(move || block_on(other));
+        let w = watcher.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            w.on_flight_data(&flight_data(false, true, false));
+        });
+        assert_eq!(Ok(()), block_on(handle));
+        t.join().unwrap();
+        assert_eq!(Ok(()), t2.join().unwrap());
+    }
+}
@@ -0,0 +1,263 @@
+use std::{fmt, time::Duration};
+
+#[cfg(feature = "decode")]
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+};
+
+#[cfg(feature = "decode")]
+use openh264::{decoder::Decoder, formats::YUVSource};
+
+#[cfg(feature = "decode")]
+use crate::{video::VideoFrame, video_sink::VideoSink};
+
+// One decoded picture, 8 bit RGB rows without padding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+    pub pts: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderConfig {
+    // 1 keeps the full 960x720, 2 halves both sides, ...
+    pub downscale: u32,
+    // frames skipped after each delivered one; every frame is still decoded,
+    // the skipped ones just aren't converted to RGB
+    pub frame_skip: u32,
+}
+
+impl Default for DecoderConfig {
+    fn default() -> Self {
+        Self {
+            downscale: 1,
+            frame_skip: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Init(String),
+    Decode(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Init(e) => write!(f, "can't create the decoder: {}", e),
+            DecodeError::Decode(e) => write!(f, "can't decode the frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl DecodedFrame {
+    // Baseline JPEG, `quality` 1..=100.
+    #[cfg(feature = "mjpeg")]
+    pub fn to_jpeg(&self, quality: u8) -> Result<Vec<u8>, jpeg_encoder::EncodingError> {
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, quality.clamp(1, 100)).encode(
+            &self.rgb,
+            self.width as u16,
+            self.height as u16,
+            jpeg_encoder::ColorType::Rgb,
+        )?;
+        Ok(jpeg)
+    }
+}
+
+// A YUV 4:2:0 picture, `strides` of the y, u and v planes.
+pub struct Yuv420<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub y: &'a [u8],
+    pub u: &'a [u8],
+    pub v: &'a [u8],
+    pub strides: (usize, usize, usize),
+}
+
+// BT.601 limited range to RGB, keeping every `downscale`-th pixel.
+pub fn yuv420_to_rgb(yuv: &Yuv420, downscale: u32) -> (u32, u32, Vec<u8>) {
+    let step = downscale.max(1) as usize;
+    let (width, height) = (yuv.width / step, yuv.height / step);
+    let (ys, us, vs) = yuv.strides;
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for row in 0..height {
+        let sy = row * step;
+        for col in 0..width {
+            let sx = col * step;
+            let y = yuv.y[sy * ys + sx] as i32 - 16;
+            let u = yuv.u[sy / 2 * us + sx / 2] as i32 - 128;
+            let v = yuv.v[sy / 2 * vs + sx / 2] as i32 - 128;
+            let c = 298 * y + 128;
+            rgb.push(((c + 409 * v) >> 8).clamp(0, 255) as u8);
+            rgb.push(((c - 100 * u - 208 * v) >> 8).clamp(0, 255) as u8);
+            rgb.push(((c + 516 * u) >> 8).clamp(0, 255) as u8);
+        }
+    }
+    (width as u32, height as u32, rgb)
+}
+
+// Software H.264 decoding with openh264, feed it the assembled video frames.
+#[cfg(feature = "decode")]
+pub struct FrameDecoder {
+    decoder: Decoder,
+    config: DecoderConfig,
+    skipped: u32,
+}
+
+#[cfg(feature = "decode")]
+impl FrameDecoder {
+    pub fn new(config: DecoderConfig) -> Result<Self, DecodeError> {
+        let decoder = Decoder::new().map_err(|e| DecodeError::Init(e.to_string()))?;
+        Ok(Self {
+            decoder,
+            config,
+            // the first decoded frame is delivered
+            skipped: config.frame_skip,
+        })
+    }
+
+    pub fn config(&self) -> DecoderConfig {
+        self.config
+    }
+
+    // None while the decoder has no picture yet, or the frame is skipped.
+    pub fn decode(&mut self, frame: &VideoFrame) -> Result<Option<DecodedFrame>, DecodeError> {
+        let yuv = self
+            .decoder
+            .decode(&frame.data)
+            .map_err(|e| DecodeError::Decode(e.to_string()))?;
+        let yuv = match yuv {
+            Some(yuv) => yuv,
+            None => return Ok(None),
+        };
+        if self.skipped < self.config.frame_skip {
+            self.skipped += 1;
+            return Ok(None);
+        }
+        self.skipped = 0;
+        let (width, height) = yuv.dimensions();
+        let picture = Yuv420 {
+            width,
+            height,
+            y: yuv.y(),
+            u: yuv.u(),
+            v: yuv.v(),
+            strides: yuv.strides(),
+        };
+        let (width, height, rgb) = yuv420_to_rgb(&picture, self.config.downscale);
+        Ok(Some(DecodedFrame {
+            width,
+            height,
+            rgb,
+            pts: frame.pts,
+        }))
+    }
+}
+
+#[cfg(feature = "decode")]
+impl fmt::Debug for FrameDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameDecoder")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+#[cfg(feature = "decode")]
+type FrameCallback = Box<dyn FnMut(DecodedFrame) + Send>;
+
+// Decodes the video and hands the pictures to a channel or a callback.
+#[cfg(feature = "decode")]
+pub struct DecoderSink {
+    decoder: FrameDecoder,
+    output: DecoderOutput,
+}
+
+#[cfg(feature = "decode")]
+enum DecoderOutput {
+    Channel(SyncSender<DecodedFrame>),
+    Callback(FrameCallback),
+}
+
+#[cfg(feature = "decode")]
+impl DecoderSink {
+    // The channel holds one picture, the ones the consumer is too slow for are dropped.
+    pub fn channel(config: DecoderConfig) -> Result<(Self, Receiver<DecodedFrame>), DecodeError> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let sink = Self {
+            decoder: FrameDecoder::new(config)?,
+            output: DecoderOutput::Channel(tx),
+        };
+        Ok((sink, rx))
+    }
+
+    // The callback runs on the video sink thread.
+    pub fn callback(
+        config: DecoderConfig,
+        callback: impl FnMut(DecodedFrame) + Send + 'static,
+    ) -> Result<Self, DecodeError> {
+        Ok(Self {
+            decoder: FrameDecoder::new(config)?,
+            output: DecoderOutput::Callback(Box::new(callback)),
+        })
+    }
+}
+
+#[cfg(feature = "decode")]
+impl VideoSink for DecoderSink {
+    // Undecodable frames are skipped, fails when the receiver went away.
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        let method_name = "decode";
+        let decoded = match self.decoder.decode(frame) {
+            Ok(Some(decoded)) => decoded,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                tracing::debug!(method_name, frame.seq, "{}", e);
+                return Ok(());
+            }
+        };
+        match &mut self.output {
+            DecoderOutput::Channel(tx) => match tx.try_send(decoded) {
+                Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+                Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+            DecoderOutput::Callback(callback) => {
+                callback(decoded);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_yuv_to_rgb_downscale() {
+        // 4x2 picture: white on the left half, black on the right
+        let y = [235, 235, 16, 16, 235, 235, 16, 16];
+        let (u, v) = ([128, 128], [128, 128]);
+        let yuv = Yuv420 {
+            width: 4,
+            height: 2,
+            y: &y,
+            u: &u,
+            v: &v,
+            strides: (4, 2, 2),
+        };
+        let (w, h, rgb) = yuv420_to_rgb(&yuv, 1);
+        assert_eq!((4, 2), (w, h));
+        assert_eq!(&[255, 255, 255, 255, 255, 255, 0, 0, 0], &rgb[..9]);
+        let (w, h, rgb) = yuv420_to_rgb(&yuv, 2);
+        assert_eq!((2, 1), (w, h));
+        assert_eq!(vec![255, 255, 255, 0, 0, 0], rgb);
+    }
+}
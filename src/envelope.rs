@@ -0,0 +1,253 @@
+use crate::{geofence::Geofence, phase::Command, tello::Stick, utils::Vec3};
+
+// rough yaw rate of the Tello at full yaw stick (deg/s), used to turn max_yaw_rate into stick authority
+pub const FULL_STICK_YAW_RATE: f32 = 100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeViolation {
+    // stick inputs were clamped at the envelope height or distance,
+    // or a target height is above it
+    MaxHeight,
+    MaxDistance,
+    // refused commands and settings
+    Flip,
+    ThrowTakeoff,
+    Bounce,
+    SportsMode,
+}
+
+// Limits applied to every stick update and command, whatever the control source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlightEnvelope {
+    // stick authority (0..1) per axis, the inputs are scaled by it
+    pub max_roll: f32,
+    pub max_pitch: f32,
+    pub max_throttle: f32,
+    pub max_yaw: f32,
+    // deg/s, see FULL_STICK_YAW_RATE
+    pub max_yaw_rate: Option<f32>,
+    // meters above and away from the takeoff point
+    pub max_height: Option<f32>,
+    pub max_distance: Option<f32>,
+    pub allow_flips: bool,
+    pub allow_throw_takeoff: bool,
+    pub allow_bounce: bool,
+    pub allow_sports_mode: bool,
+}
+
+impl FlightEnvelope {
+    pub fn beginner() -> Self {
+        Self {
+            max_roll: 0.4,
+            max_pitch: 0.4,
+            max_throttle: 0.5,
+            max_yaw: 0.5,
+            max_yaw_rate: Some(45.0),
+            max_height: Some(2.0),
+            max_distance: Some(5.0),
+            allow_flips: false,
+            allow_throw_takeoff: false,
+            allow_bounce: false,
+            allow_sports_mode: false,
+        }
+    }
+
+    pub fn standard() -> Self {
+        Self {
+            max_roll: 0.7,
+            max_pitch: 0.7,
+            max_throttle: 0.8,
+            max_yaw: 0.8,
+            max_yaw_rate: Some(90.0),
+            max_height: Some(5.0),
+            max_distance: Some(20.0),
+            allow_flips: true,
+            allow_throw_takeoff: true,
+            allow_bounce: false,
+            allow_sports_mode: false,
+        }
+    }
+
+    // No limits, the library default.
+    pub fn expert() -> Self {
+        Self {
+            max_roll: 1.0,
+            max_pitch: 1.0,
+            max_throttle: 1.0,
+            max_yaw: 1.0,
+            max_yaw_rate: None,
+            max_height: None,
+            max_distance: None,
+            allow_flips: true,
+            allow_throw_takeoff: true,
+            allow_bounce: true,
+            allow_sports_mode: true,
+        }
+    }
+
+    fn yaw_authority(&self) -> f32 {
+        let rate = self
+            .max_yaw_rate
+            .map_or(1.0, |rate| rate / FULL_STICK_YAW_RATE);
+        self.max_yaw.min(rate).clamp(0.0, 1.0)
+    }
+
+    pub fn check(&self, command: Command) -> Result<(), EnvelopeViolation> {
+        match command {
+            Command::Flip(_) if !self.allow_flips => Err(EnvelopeViolation::Flip),
+            Command::ThrowTakeoff if !self.allow_throw_takeoff => {
+                Err(EnvelopeViolation::ThrowTakeoff)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Target height (m above the takeoff point) of hold_altitude() and takeoff_to().
+    pub fn check_height(&self, height: f32) -> Result<(), EnvelopeViolation> {
+        match self.max_height {
+            Some(max_height) if height > max_height => Err(EnvelopeViolation::MaxHeight),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for FlightEnvelope {
+    fn default() -> Self {
+        Self::expert()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EnvelopeState {
+    envelope: FlightEnvelope,
+    sports_mode: bool,
+    bounce: bool,
+    // reported once per clamping
+    height_limited: bool,
+    distance_limited: bool,
+}
+
+impl EnvelopeState {
+    pub(crate) fn envelope(&self) -> FlightEnvelope {
+        self.envelope
+    }
+
+    // Returns true when the bounce mode was on and has to be switched off.
+    pub(crate) fn set_envelope(&mut self, envelope: FlightEnvelope) -> bool {
+        tracing::info!(?envelope, "flight envelope");
+        self.envelope = envelope;
+        if !envelope.allow_sports_mode {
+            self.sports_mode = false;
+        }
+        let bounce_off = self.bounce && !envelope.allow_bounce;
+        if bounce_off {
+            self.bounce = false;
+        }
+        bounce_off
+    }
+
+    pub(crate) fn sports_mode(&self) -> bool {
+        self.sports_mode
+    }
+
+    pub(crate) fn set_sports_mode(&mut self, on: bool) -> Result<(), EnvelopeViolation> {
+        if on && !self.envelope.allow_sports_mode {
+            return Err(EnvelopeViolation::SportsMode);
+        }
+        self.sports_mode = on;
+        Ok(())
+    }
+
+    pub(crate) fn set_bounce(&mut self, on: bool) -> Result<(), EnvelopeViolation> {
+        if on && !self.envelope.allow_bounce {
+            return Err(EnvelopeViolation::Bounce);
+        }
+        self.bounce = on;
+        Ok(())
+    }
+
+    // Scales the sticks by the axis authority and clamps them at the height and
+    // distance limits, returns the limits that started clamping on this update.
+    // `pos` is relative to the takeoff point, `heading` to the takeoff heading.
+    pub(crate) fn apply(
+        &mut self,
+        st: &Stick,
+        pos: Option<&Vec3<f32>>,
+        height: Option<f32>,
+        heading: Option<f64>,
+    ) -> (Stick, Vec<EnvelopeViolation>) {
+        let env = self.envelope;
+        let mut out = Stick::new(
+            (st.rx * env.max_roll, st.ry * env.max_pitch),
+            (st.lx * env.yaw_authority(), st.ly * env.max_throttle),
+        );
+        let mut violations = vec![];
+        if let Some(max_height) = env.max_height {
+            let fence = Geofence::cylinder(f32::MAX, max_height);
+            let (clamped, limited) = fence.clamp(&out, None, height, None);
+            out = clamped;
+            if limited && !self.height_limited {
+                violations.push(EnvelopeViolation::MaxHeight);
+            }
+            self.height_limited = limited;
+        }
+        if let Some(max_distance) = env.max_distance {
+            let fence = Geofence::cylinder(max_distance, f32::MAX);
+            let (clamped, limited) = fence.clamp(&out, pos, None, heading);
+            out = clamped;
+            if limited && !self.distance_limited {
+                violations.push(EnvelopeViolation::MaxDistance);
+            }
+            self.distance_limited = limited;
+        }
+        (out, violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beginner_limits() {
+        let mut state = EnvelopeState::default();
+        state.set_bounce(true).unwrap();
+        state.set_sports_mode(true).unwrap();
+        // switching to beginner turns both off
+        assert!(state.set_envelope(FlightEnvelope::beginner()));
+        assert!(!state.sports_mode());
+        assert_eq!(
+            Err(EnvelopeViolation::SportsMode),
+            state.set_sports_mode(true)
+        );
+        assert_eq!(
+            Err(EnvelopeViolation::Flip),
+            state
+                .envelope()
+                .check(Command::Flip(crate::messages::FlipDirection::Forward))
+        );
+        assert_eq!(Ok(()), state.envelope().check(Command::Takeoff));
+        assert_eq!(Ok(()), state.envelope().check_height(2.0));
+        assert_eq!(
+            Err(EnvelopeViolation::MaxHeight),
+            state.envelope().check_height(10.0)
+        );
+
+        let st = Stick::new((1.0, -1.0), (1.0, 1.0));
+        let (out, violations) = state.apply(&st, None, Some(1.0), None);
+        assert!(violations.is_empty());
+        assert_eq!(Stick::new((0.4, -0.4), (0.45, 0.5)), out);
+
+        // at the ceiling and the edge, pushing up and outwards
+        let edge = Vec3::new(0.0, 4.9, 1.9);
+        let (out, violations) = state.apply(&st, Some(&edge), Some(1.9), Some(0.0));
+        assert_eq!(
+            vec![EnvelopeViolation::MaxHeight, EnvelopeViolation::MaxDistance],
+            violations
+        );
+        assert_eq!(0.0, out.ly);
+        assert!(out.rx.abs() < 1e-6, "{:?}", out);
+        let (_, violations) = state.apply(&st, Some(&edge), Some(1.9), Some(0.0));
+        assert!(violations.is_empty());
+    }
+}
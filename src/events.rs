@@ -0,0 +1,117 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    arbiter::SwitchReason,
+    envelope::EnvelopeViolation,
+    failsafe::{FailsafeAction, FailsafeReason},
+    geofence::BreachAction,
+    phase::FlightPhase,
+};
+
+// Events published by the library to every subscriber, see TelloController::events().
+#[derive(Debug, Clone, PartialEq)]
+pub enum TelloEvent {
+    ControlSourceChanged {
+        from: Option<String>,
+        to: Option<String>,
+        reason: SwitchReason,
+    },
+    PhaseChanged {
+        from: FlightPhase,
+        to: FlightPhase,
+    },
+    // the altitude hold got within the tolerance of the target (meters)
+    AltitudeConverged {
+        target: f32,
+        height: f32,
+    },
+    MissionStepStarted {
+        index: usize,
+        step: String,
+    },
+    // error is None when the step succeeded
+    MissionStepFinished {
+        index: usize,
+        step: String,
+        error: Option<String>,
+    },
+    MissionEnded {
+        completed: bool,
+    },
+    // stick inputs pushing outwards are being clamped
+    GeofenceNear,
+    GeofenceBreached {
+        action: BreachAction,
+    },
+    // back inside the fence, away from the boundary
+    GeofenceCleared,
+    FailsafeTriggered {
+        reason: FailsafeReason,
+        action: FailsafeAction,
+    },
+    FailsafeCleared {
+        reason: FailsafeReason,
+    },
+    // horizontal distance to the takeoff point (m) while returning home
+    ReturnHomeProgress {
+        distance: f32,
+    },
+    EnvelopeViolation {
+        violation: EnvelopeViolation,
+    },
+    // the video stream broke, frames are dropped until the next keyframe
+    VideoLost {
+        frames: u64,
+    },
+    VideoRecovered {
+        after: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<TelloEvent>>>>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self) -> Receiver<TelloEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    // Sends the event to all subscribers, the ones which went away are dropped.
+    pub(crate) fn publish(&self, event: TelloEvent) {
+        let method_name = "publish";
+        tracing::debug!(method_name, "event: {:?}", event);
+        let mut g = self.subscribers.lock().unwrap();
+        g.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_to_all_subscribers() {
+        let bus = EventBus::default();
+        let rx1 = bus.subscribe();
+        let rx2 = bus.subscribe();
+        drop(rx2);
+        let event = TelloEvent::ControlSourceChanged {
+            from: None,
+            to: Some("manual".to_owned()),
+            reason: SwitchReason::Priority,
+        };
+        bus.publish(event.clone());
+        assert_eq!(event, rx1.recv().unwrap());
+        assert_eq!(1, bus.subscribers.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,348 @@
+use std::time::{Duration, Instant};
+
+use crate::messages::FlightData;
+
+pub const DEFAULT_LOW_BATTERY: i8 = 20;
+pub const DEFAULT_CRITICAL_BATTERY: i8 = 10;
+pub const DEFAULT_DEADMAN_TIMEOUT: Duration = Duration::from_millis(1000);
+pub const DEFAULT_TELEMETRY_TIMEOUT: Duration = Duration::from_millis(1000);
+pub const DEFAULT_TELEMETRY_LAND_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_WEAK_LINK: u8 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailsafeReason {
+    LowBattery,
+    CriticalBattery,
+    // no set_sticks() call within the deadman timeout, the manual sticks are zeroed
+    Deadman,
+    // no flight data within the telemetry timeout
+    TelemetryStale,
+    // no flight data within the telemetry land timeout
+    TelemetryLost,
+    // the wifi strength reported by the drone is at or below the weak link threshold
+    WeakLink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailsafeAction {
+    Warn,
+    // stops the autopilot and holds zero sticks, moving a stick past the takeover
+    // threshold acknowledges it and gives the control back to the pilot
+    Hover,
+    // the pilot can't take over until TelloController::acknowledge_failsafe()
+    // or the reason goes away
+    Land,
+    // flies back to the takeoff point and lands, lands when that is not possible
+    ReturnHome,
+}
+
+// Each rule can be disabled with None.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FailsafeConfig {
+    // battery percentage at or below which the rule fires, FlightData.battery_low
+    // and battery_critical fire them as well
+    pub low_battery: Option<i8>,
+    // Hover by default, ReturnHome flies back to the takeoff point instead
+    pub low_battery_action: FailsafeAction,
+    pub critical_battery: Option<i8>,
+    pub critical_battery_action: FailsafeAction,
+    pub deadman_timeout: Option<Duration>,
+    pub deadman_action: FailsafeAction,
+    pub telemetry_timeout: Option<Duration>,
+    pub telemetry_timeout_action: FailsafeAction,
+    pub telemetry_land_timeout: Option<Duration>,
+    pub telemetry_land_timeout_action: FailsafeAction,
+    // WifiData.wifi_strength at or below which the rule fires
+    pub weak_link: Option<u8>,
+    pub weak_link_action: FailsafeAction,
+}
+
+impl Default for FailsafeConfig {
+    fn default() -> Self {
+        Self {
+            low_battery: Some(DEFAULT_LOW_BATTERY),
+            low_battery_action: FailsafeAction::Hover,
+            critical_battery: Some(DEFAULT_CRITICAL_BATTERY),
+            critical_battery_action: FailsafeAction::Land,
+            deadman_timeout: Some(DEFAULT_DEADMAN_TIMEOUT),
+            deadman_action: FailsafeAction::Warn,
+            telemetry_timeout: Some(DEFAULT_TELEMETRY_TIMEOUT),
+            telemetry_timeout_action: FailsafeAction::Hover,
+            telemetry_land_timeout: Some(DEFAULT_TELEMETRY_LAND_TIMEOUT),
+            telemetry_land_timeout_action: FailsafeAction::Land,
+            weak_link: Some(DEFAULT_WEAK_LINK),
+            weak_link_action: FailsafeAction::Warn,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FailsafeChange {
+    Triggered(FailsafeReason, FailsafeAction),
+    Cleared(FailsafeReason),
+}
+
+// Evaluates the failsafe rules on every stick loop tick, each rule fires once
+// until its reason goes away (or the drone lands).
+#[derive(Debug, Default)]
+pub(crate) struct Failsafe {
+    config: FailsafeConfig,
+    // last set_sticks() call, the deadman is armed by the first one
+    last_sticks: Option<Instant>,
+    // last wifi strength reported by the drone
+    link_strength: Option<u8>,
+    active: Vec<(FailsafeReason, FailsafeAction)>,
+}
+
+impl Failsafe {
+    pub(crate) fn config(&self) -> FailsafeConfig {
+        self.config
+    }
+
+    pub(crate) fn set_config(&mut self, config: FailsafeConfig) {
+        tracing::info!(?config, "failsafe config");
+        self.config = config;
+    }
+
+    pub(crate) fn sticks_updated(&mut self, now: Instant) {
+        self.last_sticks = Some(now);
+    }
+
+    pub(crate) fn link_updated(&mut self, strength: u8) {
+        self.link_strength = Some(strength);
+    }
+
+    pub(crate) fn is_active(&self, reason: FailsafeReason) -> bool {
+        self.active.iter().any(|(r, _)| *r == reason)
+    }
+
+    // True while a fired rule wants the drone hovering or landing.
+    pub(crate) fn holds(&self) -> bool {
+        self.active
+            .iter()
+            .any(|(_, action)| matches!(action, FailsafeAction::Hover | FailsafeAction::Land))
+    }
+
+    // True while a fired rule wants the drone landing, the pilot can't take over.
+    pub(crate) fn locks_out(&self) -> bool {
+        self.active
+            .iter()
+            .any(|(_, action)| *action == FailsafeAction::Land)
+    }
+
+    // Downgrades the fired rules to warnings, they don't fire again until their reason goes away.
+    pub(crate) fn acknowledge(&mut self) {
+        for (_, action) in self.active.iter_mut() {
+            *action = FailsafeAction::Warn;
+        }
+    }
+
+    pub(crate) fn update(
+        &mut self,
+        flight: Option<&FlightData>,
+        flight_updated: Option<Instant>,
+        flying: bool,
+        now: Instant,
+    ) -> Vec<FailsafeChange> {
+        let mut changes = vec![];
+        if !flying {
+            self.active.clear();
+            return changes;
+        }
+        let c = self.config;
+        let low = flight.is_some_and(|f| {
+            f.battery_low || c.low_battery.is_some_and(|p| f.battery_percentage <= p)
+        });
+        let critical = flight.is_some_and(|f| {
+            f.battery_critical
+                || c.critical_battery
+                    .is_some_and(|p| f.battery_percentage <= p)
+        });
+        let deadman = match (c.deadman_timeout, self.last_sticks) {
+            (Some(timeout), Some(last)) => now.saturating_duration_since(last) > timeout,
+            _ => false,
+        };
+        let silence = flight_updated.map(|at| now.saturating_duration_since(at));
+        let stale = |timeout: Option<Duration>| match (timeout, silence) {
+            (Some(timeout), Some(silence)) => silence > timeout,
+            _ => false,
+        };
+        let weak_link = match (c.weak_link, self.link_strength) {
+            (Some(min), Some(strength)) => strength <= min,
+            _ => false,
+        };
+        let rules = [
+            (
+                FailsafeReason::LowBattery,
+                low && c.low_battery.is_some(),
+                c.low_battery_action,
+            ),
+            (
+                FailsafeReason::CriticalBattery,
+                critical && c.critical_battery.is_some(),
+                c.critical_battery_action,
+            ),
+            (FailsafeReason::Deadman, deadman, c.deadman_action),
+            (
+                FailsafeReason::TelemetryStale,
+                stale(c.telemetry_timeout),
+                c.telemetry_timeout_action,
+            ),
+            (
+                FailsafeReason::TelemetryLost,
+                stale(c.telemetry_land_timeout),
+                c.telemetry_land_timeout_action,
+            ),
+            (FailsafeReason::WeakLink, weak_link, c.weak_link_action),
+        ];
+        for (reason, fired, action) in rules {
+            let active = self.is_active(reason);
+            if fired && !active {
+                self.active.push((reason, action));
+                changes.push(FailsafeChange::Triggered(reason, action));
+            } else if !fired && active {
+                self.active.retain(|(r, _)| *r != reason);
+                changes.push(FailsafeChange::Cleared(reason));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flight(battery_percentage: i8) -> FlightData {
+        let mut fd = FlightData::new(&vec![0; 32]);
+        fd.battery_percentage = battery_percentage;
+        fd
+    }
+
+    #[test]
+    fn test_battery_rules_fire_once() {
+        let mut fs = Failsafe::default();
+        let now = Instant::now();
+        let fd = flight(50);
+        assert!(fs.update(Some(&fd), Some(now), true, now).is_empty());
+        let fd = flight(15);
+        assert_eq!(
+            vec![FailsafeChange::Triggered(
+                FailsafeReason::LowBattery,
+                FailsafeAction::Hover
+            )],
+            fs.update(Some(&fd), Some(now), true, now)
+        );
+        assert!(fs.update(Some(&fd), Some(now), true, now).is_empty());
+        assert!(fs.holds());
+        assert!(!fs.locks_out());
+        fs.acknowledge();
+        assert!(!fs.holds());
+        let mut fd = flight(15);
+        fd.battery_critical = true;
+        assert_eq!(
+            vec![FailsafeChange::Triggered(
+                FailsafeReason::CriticalBattery,
+                FailsafeAction::Land
+            )],
+            fs.update(Some(&fd), Some(now), true, now)
+        );
+        // landed
+        assert!(fs.update(Some(&fd), Some(now), false, now).is_empty());
+        assert!(!fs.is_active(FailsafeReason::LowBattery));
+    }
+
+    #[test]
+    fn test_low_battery_return_home() {
+        let mut fs = Failsafe::default();
+        fs.set_config(FailsafeConfig {
+            low_battery_action: FailsafeAction::ReturnHome,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let fd = flight(15);
+        assert_eq!(
+            vec![FailsafeChange::Triggered(
+                FailsafeReason::LowBattery,
+                FailsafeAction::ReturnHome
+            )],
+            fs.update(Some(&fd), Some(now), true, now)
+        );
+        // the return home flight drives the sticks, nothing to hold
+        assert!(!fs.holds());
+        assert!(fs.is_active(FailsafeReason::LowBattery));
+    }
+
+    #[test]
+    fn test_deadman_and_stale_telemetry() {
+        let mut fs = Failsafe::default();
+        let at = Instant::now();
+        let fd = flight(80);
+        // the deadman is not armed before the first set_sticks()
+        let later = at + Duration::from_secs(2);
+        assert_eq!(
+            vec![FailsafeChange::Triggered(
+                FailsafeReason::TelemetryStale,
+                FailsafeAction::Hover
+            )],
+            fs.update(Some(&fd), Some(at), true, later)
+        );
+        fs.sticks_updated(later);
+        let changes = fs.update(
+            Some(&fd),
+            Some(at),
+            true,
+            later + Duration::from_millis(3500),
+        );
+        assert_eq!(
+            vec![
+                FailsafeChange::Triggered(FailsafeReason::Deadman, FailsafeAction::Warn),
+                FailsafeChange::Triggered(FailsafeReason::TelemetryLost, FailsafeAction::Land),
+            ],
+            changes
+        );
+        // telemetry and sticks back
+        let now = later + Duration::from_secs(4);
+        fs.sticks_updated(now);
+        assert_eq!(
+            vec![
+                FailsafeChange::Cleared(FailsafeReason::Deadman),
+                FailsafeChange::Cleared(FailsafeReason::TelemetryStale),
+                FailsafeChange::Cleared(FailsafeReason::TelemetryLost),
+            ],
+            fs.update(Some(&fd), Some(now), true, now)
+        );
+    }
+    #[test]
+    fn test_weak_link() {
+        let mut fs = Failsafe::default();
+        fs.set_config(FailsafeConfig {
+            weak_link: Some(40),
+            weak_link_action: FailsafeAction::Hover,
+            telemetry_timeout_action: FailsafeAction::Warn,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let fd = flight(80);
+        // no wifi data yet
+        assert!(fs.update(Some(&fd), Some(now), true, now).is_empty());
+        fs.link_updated(60);
+        assert!(fs.update(Some(&fd), Some(now), true, now).is_empty());
+        fs.link_updated(35);
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            vec![
+                FailsafeChange::Triggered(FailsafeReason::TelemetryStale, FailsafeAction::Warn),
+                FailsafeChange::Triggered(FailsafeReason::WeakLink, FailsafeAction::Hover),
+            ],
+            fs.update(Some(&fd), Some(now), true, later)
+        );
+        assert!(fs.holds());
+        fs.link_updated(50);
+        assert_eq!(
+            vec![FailsafeChange::Cleared(FailsafeReason::WeakLink)],
+            fs.update(Some(&fd), Some(now), true, later)
+        );
+        assert!(!fs.holds());
+    }
+}
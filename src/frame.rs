@@ -0,0 +1,163 @@
+use crate::{tello::Stick, utils::Vec3};
+
+// Frame in which the manual rx/ry stick inputs are interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ControlFrame {
+    // forward is where the drone's nose points (the Tello default)
+    #[default]
+    Body,
+    // forward is the heading the drone had at takeoff ("headless" mode)
+    WorldLocked,
+    // forward is the heading set by the pilot with set_heading_reference(),
+    // falls back to the takeoff heading until a reference is set
+    PilotRelative,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FrameState {
+    frame: ControlFrame,
+    takeoff_yaw: Option<f64>,
+    pilot_yaw: Option<f64>,
+    // MVO position of the takeoff point
+    home: Option<Vec3<f32>>,
+}
+
+impl FrameState {
+    pub(crate) fn frame(&self) -> ControlFrame {
+        self.frame
+    }
+
+    pub(crate) fn set_frame(&mut self, frame: ControlFrame) {
+        self.frame = frame;
+    }
+
+    pub(crate) fn capture_takeoff_yaw(&mut self, yaw: Option<f64>) {
+        tracing::info!(?yaw, "takeoff heading captured");
+        self.takeoff_yaw = yaw;
+    }
+
+    pub(crate) fn takeoff_yaw(&self) -> Option<f64> {
+        self.takeoff_yaw
+    }
+
+    pub(crate) fn home(&self) -> Option<Vec3<f32>> {
+        self.home
+    }
+
+    pub(crate) fn clear_home(&mut self) {
+        self.home = None;
+    }
+
+    // The MVO position is often not valid yet at the takeoff, the home is captured
+    // from the first valid one after it, `height` (m) is the height above the takeoff point.
+    pub(crate) fn capture_home(&mut self, mvo: &Vec3<f32>, height: f32) {
+        if self.home.is_none() {
+            let home = Vec3::new(mvo.x, mvo.y, mvo.z + height);
+            tracing::info!(?home, "home captured");
+            self.home = Some(home);
+        }
+    }
+
+    // MVO position (z down) to the position relative to home (z up).
+    pub(crate) fn relative_to_home(&self, mvo: &Vec3<f32>) -> Option<Vec3<f32>> {
+        let home = self.home?;
+        Some(Vec3::new(mvo.x - home.x, mvo.y - home.y, home.z - mvo.z))
+    }
+
+    // Drone heading relative to the takeoff heading.
+    pub(crate) fn heading(&self, yaw: Option<f64>) -> Option<f64> {
+        Some(yaw? - self.takeoff_yaw?)
+    }
+
+    pub(crate) fn set_pilot_yaw(&mut self, yaw: f64) {
+        self.pilot_yaw = Some(yaw);
+    }
+
+    fn reference_yaw(&self) -> Option<f64> {
+        match self.frame {
+            ControlFrame::Body => None,
+            ControlFrame::WorldLocked => self.takeoff_yaw,
+            ControlFrame::PilotRelative => self.pilot_yaw.or(self.takeoff_yaw),
+        }
+    }
+
+    // Rotates the stick inputs into the body frame, without the current
+    // yaw or a reference heading the inputs are used as they are.
+    pub(crate) fn apply(&self, st: &Stick, yaw: Option<f64>) -> Stick {
+        match (self.reference_yaw(), yaw) {
+            (Some(reference), Some(yaw)) => rotate_by_heading(st, yaw - reference),
+            _ => st.clone(),
+        }
+    }
+}
+
+// Converts reference frame inputs to body frame inputs for a drone turned
+// `heading_deg` clockwise from the reference heading.
+pub(crate) fn rotate_by_heading(st: &Stick, heading_deg: f64) -> Stick {
+    let (ry, rx) = rotate_xy(st.ry, st.rx, heading_deg);
+    Stick::new((rx.clamp(-1.0, 1.0), ry.clamp(-1.0, 1.0)), (st.lx, st.ly))
+}
+
+// Same as rotate_by_heading() for a (forward, right) vector.
+pub(crate) fn rotate_xy(forward: f32, right: f32, heading_deg: f64) -> (f32, f32) {
+    let (sin, cos) = (heading_deg.to_radians() as f32).sin_cos();
+    (forward * cos + right * sin, right * cos - forward * sin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_stick(expected: (f32, f32), st: &Stick) {
+        assert!((expected.0 - st.rx).abs() < 1e-5, "rx={}", st.rx);
+        assert!((expected.1 - st.ry).abs() < 1e-5, "ry={}", st.ry);
+    }
+
+    #[test]
+    fn test_rotate_by_heading() {
+        let forward = Stick::new((0.0, 1.0), (0.3, 0.4));
+        assert_stick((0.0, 1.0), &rotate_by_heading(&forward, 0.0));
+        // drone faces right, forward in the reference frame is left for the drone
+        let st = rotate_by_heading(&forward, 90.0);
+        assert_stick((-1.0, 0.0), &st);
+        assert_eq!(0.3, st.lx);
+        assert_eq!(0.4, st.ly);
+        // drone turned around
+        assert_stick((0.0, -1.0), &rotate_by_heading(&forward, 180.0));
+        assert_stick((1.0, 0.0), &rotate_by_heading(&forward, -90.0));
+    }
+
+    #[test]
+    fn test_reference_heading() {
+        let mut state = FrameState::default();
+        let right = Stick::new((1.0, 0.0), (0.0, 0.0));
+        state.capture_takeoff_yaw(Some(30.0));
+        // body frame ignores the heading
+        assert_stick((1.0, 0.0), &state.apply(&right, Some(120.0)));
+
+        state.set_frame(ControlFrame::WorldLocked);
+        assert_stick((0.0, 1.0), &state.apply(&right, Some(120.0)));
+        // no yaw received yet
+        assert_stick((1.0, 0.0), &state.apply(&right, None));
+
+        state.set_frame(ControlFrame::PilotRelative);
+        assert_stick((0.0, 1.0), &state.apply(&right, Some(120.0)));
+        state.set_pilot_yaw(120.0);
+        assert_stick((1.0, 0.0), &state.apply(&right, Some(120.0)));
+    }
+
+    #[test]
+    fn test_home() {
+        let mut state = FrameState::default();
+        let mvo = Vec3::new(10.0, 10.0, -1.0);
+        assert_eq!(None, state.relative_to_home(&mvo));
+        // first valid position 1m above the takeoff point
+        state.capture_home(&mvo, 1.0);
+        state.capture_home(&Vec3::new(0.0, 0.0, 0.0), 0.0);
+        assert_eq!(Some(Vec3::new(10.0, 10.0, 0.0)), state.home());
+        assert_eq!(
+            Some(Vec3::new(1.0, -2.0, 1.5)),
+            state.relative_to_home(&Vec3::new(11.0, 8.0, -1.5))
+        );
+    }
+}
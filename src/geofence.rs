@@ -0,0 +1,250 @@
+use crate::{frame::rotate_xy, tello::Stick, utils::Vec3};
+
+pub const DEFAULT_MARGIN: f32 = 0.5;
+
+// Horizontal shape of the fence in meters, relative to the takeoff point
+// (x forward, y right of the takeoff heading).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FenceShape {
+    Cylinder {
+        radius: f32,
+    },
+    Box {
+        x_min: f32,
+        x_max: f32,
+        y_min: f32,
+        y_max: f32,
+    },
+}
+
+// What the library does when the drone gets outside the fence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreachAction {
+    // stops the autopilot, only the sticks pointing back inside are let through
+    Hover,
+    // flies back to the takeoff point (horizontally)
+    Return,
+    Land,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geofence {
+    pub shape: FenceShape,
+    // height above the takeoff point in meters
+    pub min_height: Option<f32>,
+    pub max_height: f32,
+    // stick inputs pushing outwards are clamped within this distance of the boundary
+    pub margin: f32,
+    pub action: BreachAction,
+}
+
+impl Geofence {
+    pub fn cylinder(radius: f32, max_height: f32) -> Self {
+        Self {
+            shape: FenceShape::Cylinder { radius },
+            min_height: None,
+            max_height,
+            margin: DEFAULT_MARGIN,
+            action: BreachAction::Hover,
+        }
+    }
+
+    pub fn contains(&self, pos: Option<&Vec3<f32>>, height: Option<f32>) -> bool {
+        let inside_xy = match (pos, self.shape) {
+            (None, _) => true,
+            (Some(p), FenceShape::Cylinder { radius }) => p.x.hypot(p.y) <= radius,
+            (
+                Some(p),
+                FenceShape::Box {
+                    x_min,
+                    x_max,
+                    y_min,
+                    y_max,
+                },
+            ) => p.x >= x_min && p.x <= x_max && p.y >= y_min && p.y <= y_max,
+        };
+        let inside_z = match height {
+            None => true,
+            Some(h) => h <= self.max_height && self.min_height.is_none_or(|min| h >= min),
+        };
+        inside_xy && inside_z
+    }
+
+    // Removes the stick components pushing outwards near the boundary. `pos` is
+    // relative to the takeoff point (z up), `heading` is the drone heading relative
+    // to the takeoff heading, without it the horizontal sticks are left alone.
+    pub(crate) fn clamp(
+        &self,
+        st: &Stick,
+        pos: Option<&Vec3<f32>>,
+        height: Option<f32>,
+        heading: Option<f64>,
+    ) -> (Stick, bool) {
+        let mut out = st.clone();
+        let mut clamped = false;
+        if let (Some(p), Some(heading)) = (pos, heading) {
+            // stick to the takeoff frame
+            let (mut f, mut r) = rotate_xy(st.ry, st.rx, -heading);
+            match self.shape {
+                FenceShape::Cylinder { radius } => {
+                    let d = p.x.hypot(p.y);
+                    if d > 0.0 && d >= radius - self.margin {
+                        let (nx, ny) = (p.x / d, p.y / d);
+                        let outward = f * nx + r * ny;
+                        if outward > 0.0 {
+                            f -= outward * nx;
+                            r -= outward * ny;
+                            clamped = true;
+                        }
+                    }
+                }
+                FenceShape::Box {
+                    x_min,
+                    x_max,
+                    y_min,
+                    y_max,
+                } => {
+                    let m = self.margin;
+                    if (p.x >= x_max - m && f > 0.0) || (p.x <= x_min + m && f < 0.0) {
+                        f = 0.0;
+                        clamped = true;
+                    }
+                    if (p.y >= y_max - m && r > 0.0) || (p.y <= y_min + m && r < 0.0) {
+                        r = 0.0;
+                        clamped = true;
+                    }
+                }
+            }
+            if clamped {
+                let (ry, rx) = rotate_xy(f, r, heading);
+                out.ry = ry;
+                out.rx = rx;
+            }
+        }
+        if let Some(h) = height {
+            let too_high = h >= self.max_height - self.margin && st.ly > 0.0;
+            let too_low = self
+                .min_height
+                .is_some_and(|min| h <= min + self.margin && st.ly < 0.0);
+            if too_high || too_low {
+                out.ly = 0.0;
+                clamped = true;
+            }
+        }
+        (out, clamped)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FenceStatus {
+    Inside,
+    // sticks are being clamped
+    Near,
+    Breached,
+}
+
+// The fence and its last status, updated by the stick loop.
+#[derive(Debug, Default)]
+pub(crate) struct GeofenceState {
+    fence: Option<Geofence>,
+    status: Option<FenceStatus>,
+}
+
+impl GeofenceState {
+    pub(crate) fn fence(&self) -> Option<Geofence> {
+        self.fence
+    }
+
+    pub(crate) fn set_fence(&mut self, fence: Option<Geofence>) {
+        self.fence = fence;
+        self.status = None;
+    }
+
+    // Clamps the sticks, returns the new status when it changed. `pos` is
+    // relative to the takeoff point, see Geofence::clamp().
+    pub(crate) fn apply(
+        &mut self,
+        st: &Stick,
+        pos: Option<&Vec3<f32>>,
+        height: Option<f32>,
+        heading: Option<f64>,
+    ) -> (Stick, Option<FenceStatus>) {
+        let fence = match self.fence {
+            Some(fence) => fence,
+            None => return (st.clone(), None),
+        };
+        let (out, clamped) = fence.clamp(st, pos, height, heading);
+        let status = if !fence.contains(pos, height) {
+            FenceStatus::Breached
+        } else if clamped {
+            FenceStatus::Near
+        } else {
+            FenceStatus::Inside
+        };
+        let changed = self.status != Some(status);
+        self.status = Some(status);
+        (out, if changed { Some(status) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cylinder_clamps_outward_sticks() {
+        let fence = Geofence::cylinder(5.0, 3.0);
+        let at_edge = Vec3::new(4.8, 0.0, 1.0);
+        // forward (outward) is removed, sideways is kept
+        let st = Stick::new((0.5, 1.0), (0.0, 0.0));
+        let (out, clamped) = fence.clamp(&st, Some(&at_edge), Some(1.0), Some(0.0));
+        assert!(clamped);
+        assert!(
+            out.ry.abs() < 1e-5 && (out.rx - 0.5).abs() < 1e-5,
+            "{:?}",
+            out
+        );
+        // drone turned around, forward is back inside
+        let (out, clamped) = fence.clamp(&st, Some(&at_edge), Some(1.0), Some(180.0));
+        assert!(!clamped);
+        assert_eq!(1.0, out.ry);
+        // climbing at the ceiling
+        let up = Stick::new((0.0, 0.0), (0.0, 0.8));
+        let (out, _) = fence.clamp(&up, None, Some(2.8), None);
+        assert_eq!(0.0, out.ly);
+        assert!(!fence.contains(Some(&Vec3::new(3.0, 4.1, 0.0)), Some(1.0)));
+    }
+
+    #[test]
+    fn test_box_and_status() {
+        let mut state = GeofenceState::default();
+        state.set_fence(Some(Geofence {
+            shape: FenceShape::Box {
+                x_min: -1.0,
+                x_max: 2.0,
+                y_min: -1.0,
+                y_max: 1.0,
+            },
+            min_height: Some(0.5),
+            max_height: 2.0,
+            margin: 0.2,
+            action: BreachAction::Land,
+        }));
+        let st = Stick::new((-1.0, 1.0), (0.0, -1.0));
+        let pos = Vec3::new(0.0, 0.0, 1.0);
+        let (out, status) = state.apply(&st, Some(&pos), Some(1.0), Some(0.0));
+        assert_eq!(Some(FenceStatus::Inside), status);
+        assert_eq!(1.0, out.ry);
+
+        let pos = Vec3::new(1.9, -0.9, 0.6);
+        let (out, status) = state.apply(&st, Some(&pos), Some(0.6), Some(0.0));
+        assert_eq!(Some(FenceStatus::Near), status);
+        assert_eq!((0.0, 0.0, 0.0), (out.rx, out.ry, out.ly));
+        let (_, status) = state.apply(&st, Some(&pos), Some(0.6), Some(0.0));
+        assert_eq!(None, status);
+
+        let pos = Vec3::new(2.5, 0.0, 1.0);
+        let (_, status) = state.apply(&st, Some(&pos), Some(1.0), Some(0.0));
+        assert_eq!(Some(FenceStatus::Breached), status);
+    }
+}
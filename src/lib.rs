@@ -1,41 +1,99 @@
 use std::{
-    io::Write,
+    io,
+    path::Path,
     sync::{
         atomic::Ordering,
         mpsc::{self, Receiver, Sender},
         Arc, RwLock,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::Instant,
 };
 
-use messages::{FlightData, LightData, LogData, WifiData};
-use tello::{Stick, Tello};
-
+use arbiter::{ControlArbiter, ControlSource, RegisterError, SourceKind};
+use autopilot::ManeuverConfig;
+use command::CommandHandle;
+use envelope::FlightEnvelope;
+use events::TelloEvent;
+use failsafe::FailsafeConfig;
+use frame::ControlFrame;
+use geofence::Geofence;
+use messages::{FlightData, FlipDirection, LightData, LogData, SmartVideoCmd, WifiData};
+use mp4::Mp4Recorder;
+use phase::{CommandError, FlightPhase};
+use recorder::StickRecorder;
+use rtsp::RtspServer;
+use scheduler::StickLoopStats;
+use tello::Tello;
+use trajectory::Trajectory;
+use velocity::{VelocityConfig, VelocityTarget};
+use video::VideoStats;
+use video_queue::{FrameReceiver, FrameSender, QueuePolicy};
+use video_sink::{Player, PlayerSink, VideoSink};
+
+pub use tello::Stick;
+pub use utils::Vec3;
+
+pub mod arbiter;
+pub mod autopilot;
+pub mod command;
 pub(crate) mod crc;
+pub mod decode;
 pub(crate) mod dump;
 pub(crate) mod env;
+pub mod envelope;
+pub mod events;
+pub mod failsafe;
+pub mod frame;
+pub mod geofence;
 pub mod messages;
+pub mod mission;
+#[cfg(feature = "mjpeg")]
+pub mod mjpeg;
+pub mod mp4;
+pub mod phase;
+pub mod pid;
+pub mod recorder;
+pub mod rtsp;
+pub mod scheduler;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+pub(crate) mod telemetry;
 pub(crate) mod tello;
+pub mod trajectory;
 pub(crate) mod utils;
+pub mod velocity;
+pub mod video;
+pub mod video_queue;
+pub mod video_sink;
 
 #[macro_use]
 extern crate lazy_static;
 
-pub type VideoRecvChannel = Receiver<Vec<u8>>;
-pub type VideoPublishChannel = Sender<Vec<u8>>;
+pub type VideoRecvChannel = FrameReceiver;
+pub type VideoPublishChannel = FrameSender;
 pub type VideoChannel = (VideoPublishChannel, VideoRecvChannel);
 
 pub type UpdateDataPublishChannel = Sender<UpdateData>;
 pub type UpdateDataRecvChannel = Receiver<UpdateData>;
 pub type UpdateDataChannel = (UpdateDataPublishChannel, UpdateDataRecvChannel);
 
+pub type EventRecvChannel = Receiver<TelloEvent>;
+
 pub fn comm_channel() -> UpdateDataChannel {
     mpsc::channel()
 }
 
+// Bounded, frames are dropped until the next keyframe when the consumer can't keep up.
 pub fn video_channel() -> VideoChannel {
-    mpsc::channel()
+    video_queue::frame_queue(
+        video_queue::DEFAULT_VIDEO_QUEUE_CAPACITY,
+        QueuePolicy::default(),
+    )
+}
+
+pub fn video_channel_with(capacity: usize, policy: QueuePolicy) -> VideoChannel {
+    video_queue::frame_queue(capacity, policy)
 }
 
 #[derive(Debug)]
@@ -44,8 +102,11 @@ pub struct UpdateData {
     pub wifi: Option<WifiData>,
     pub light: Option<LightData>,
     pub log: Option<LogData>,
+    // sent along with the wifi data, to tell link and video quality apart
+    pub video: Option<VideoStats>,
 }
 
+#[derive(Clone)]
 pub struct TelloController {
     video: Arc<RwLock<bool>>,
     inner: Arc<Tello>,
@@ -59,54 +120,332 @@ impl TelloController {
         }
     }
 
+    // Plays the video with mplayer, see start_video_sink() for the other players.
     pub fn start_mplayer(&self, video_channel: VideoRecvChannel) -> Option<JoinHandle<()>> {
         let method_name = "start_mplayer";
-        let mut err_cnt = 0;
-        let stdin = utils::start_mplayer_with_stdin(false);
-        if stdin.is_none() {
-            return None;
+        let player = Player::Mplayer;
+        match PlayerSink::new(player, &player.default_args()) {
+            Ok(sink) => Some(self.start_video_sink(video_channel, sink)),
+            Err(e) => {
+                tracing::warn!(method_name, "can't execute mplayer: {}", e);
+                None
+            }
         }
-        let mut stdin = stdin.unwrap();
-        let jh = thread::spawn(move || loop {
-            let video_data = video_channel.recv();
-            if video_data.is_err() {
-                tracing::warn!(
-                    method_name,
-                    "can't get video data: {}",
-                    video_data.err().unwrap()
-                );
-                err_cnt += 1;
-                if err_cnt > 10 {
-                    return;
+    }
+
+    // Serves the video at rtsp://<addr>/tello (rtsp::DEFAULT_RTSP_ADDR), a keyframe is
+    // requested whenever a viewer joins. Use a video_sink::FanOut of an rtsp::RtspServer
+    // to watch and serve at once.
+    pub fn start_rtsp_server(
+        &self,
+        video_channel: VideoRecvChannel,
+        addr: &str,
+    ) -> io::Result<JoinHandle<()>> {
+        let server = RtspServer::bind(addr)?;
+        let inner = self.inner.clone();
+        server.on_join(move || inner.want_keyframe());
+        Ok(self.start_video_sink(video_channel, server))
+    }
+
+    // Decodes the video to RGB pictures, see decode::DecoderSink for a callback instead.
+    #[cfg(feature = "decode")]
+    pub fn start_decoder(
+        &self,
+        video_channel: VideoRecvChannel,
+        config: decode::DecoderConfig,
+    ) -> Result<(JoinHandle<()>, Receiver<decode::DecodedFrame>), decode::DecodeError> {
+        let (sink, rx) = decode::DecoderSink::channel(config)?;
+        Ok((self.start_video_sink(video_channel, sink), rx))
+    }
+
+    // Serves the decoded video to browsers at http://<addr>/ (mjpeg::DEFAULT_MJPEG_ADDR),
+    // the video is decoded all along but JPEGs are encoded only while somebody watches.
+    #[cfg(feature = "mjpeg")]
+    pub fn start_mjpeg_server(
+        &self,
+        video_channel: VideoRecvChannel,
+        addr: &str,
+        config: mjpeg::MjpegConfig,
+    ) -> io::Result<JoinHandle<()>> {
+        let server = mjpeg::MjpegServer::bind(addr, config)?;
+        let sink = decode::DecoderSink::callback(config.decoder_config(), move |frame| {
+            server.push(&frame)
+        })
+        .map_err(io::Error::other)?;
+        Ok(self.start_video_sink(video_channel, sink))
+    }
+
+    // Feeds the video frames to the sink until the channel closes or the sink fails.
+    pub fn start_video_sink(
+        &self,
+        video_channel: VideoRecvChannel,
+        mut sink: impl VideoSink + 'static,
+    ) -> JoinHandle<()> {
+        let method_name = "video_sink";
+        thread::spawn(move || {
+            while let Ok(frame) = video_channel.recv() {
+                let r = sink.write_frame(&frame);
+                video_channel.recycle(frame);
+                if let Err(e) = r {
+                    tracing::warn!(method_name, "unable to write video: {}", e);
+                    break;
                 }
-                continue;
             }
-            err_cnt = 0; //reset error counter
-            let video_data = video_data.unwrap();
-            utils::append_to_file("video.dump", video_data.clone());
-            let r = stdin.write_all(&video_data);
-            if r.is_err() {
-                tracing::warn!(
-                    method_name,
-                    "unable to write data to maplyer: {}",
-                    r.err().unwrap()
-                );
+            if let Err(e) = sink.finish() {
+                tracing::warn!(method_name, "unable to finish video sink: {}", e);
             }
-        });
-        Some(jh)
+        })
     }
 
+    // Sets the sticks of the manual control source, the manual sticks are zeroed
+    // when not called within FailsafeConfig::deadman_timeout.
     pub fn set_sticks(&self, st: &Stick) {
-        let mut g = self.inner.stick.write().unwrap();
-        *g = st.clone();
+        self.inner.arbiter.manual().set_sticks(st);
+        self.inner.manual_sticks_updated();
+    }
+
+    pub fn failsafe_config(&self) -> FailsafeConfig {
+        self.inner.failsafe.lock().unwrap().config()
+    }
+
+    pub fn set_failsafe_config(&self, config: FailsafeConfig) {
+        self.inner.failsafe.lock().unwrap().set_config(config);
+    }
+
+    // Gives the control back after a failsafe hover (e.g. low battery), the fired
+    // rules don't fire again until their reason goes away.
+    pub fn acknowledge_failsafe(&self) {
+        self.inner.failsafe.lock().unwrap().acknowledge();
+    }
+
+    pub fn arbiter(&self) -> Arc<ControlArbiter> {
+        self.inner.arbiter.clone()
+    }
+
+    // Registers an automatic control source, see arbiter::PRIORITY_*. The names of the
+    // library's own sources (arbiter::RESERVED_SOURCES) are rejected.
+    pub fn register_control_source(
+        &self,
+        name: &str,
+        priority: u8,
+    ) -> Result<Arc<ControlSource>, RegisterError> {
+        self.inner
+            .arbiter
+            .register(name, priority, SourceKind::Automatic)
+    }
+
+    pub fn active_control_source(&self) -> Option<String> {
+        self.inner.arbiter.active_source()
+    }
+
+    // Subscribe to the library events, each call returns a new receiver.
+    pub fn events(&self) -> EventRecvChannel {
+        self.inner.events.subscribe()
+    }
+
+    // Stick update rate, clamped to MIN_STICK_RATE_HZ..=MAX_STICK_RATE_HZ.
+    // Takes effect on the next tick of the stick update loop.
+    pub fn set_stick_rate(&self, rate_hz: u32) {
+        let rate_hz = scheduler::clamp_rate(rate_hz);
+        tracing::info!(rate_hz, "set stick update rate");
+        self.inner.stick_rate_hz.store(rate_hz, Ordering::Relaxed);
+    }
+
+    pub fn stick_rate(&self) -> u32 {
+        self.inner.stick_rate_hz.load(Ordering::Relaxed)
+    }
+
+    // By default sticks are sent only while flying, enable this to send them
+    // before takeoff as well.
+    pub fn set_sticks_before_takeoff(&self, enabled: bool) {
+        self.inner
+            .sticks_on_ground
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn stick_loop_stats(&self) -> StickLoopStats {
+        self.inner.stick_metrics.snapshot()
+    }
+
+    // Switches the limits applied to every stick update and command, see
+    // FlightEnvelope::beginner(), standard() and expert() (the default).
+    pub fn set_flight_envelope(&self, envelope: FlightEnvelope) {
+        self.inner.set_flight_envelope(envelope);
+    }
+
+    pub fn flight_envelope(&self) -> FlightEnvelope {
+        self.inner.envelope.lock().unwrap().envelope()
+    }
+
+    pub fn set_bounce(&self, on: bool) -> Result<(), CommandError> {
+        self.inner.set_bounce(on)
+    }
+
+    pub fn set_sports_mode(&self, on: bool) -> Result<(), CommandError> {
+        self.inner.set_sports_mode(on)
+    }
+
+    pub fn takeoff(&self) -> Result<CommandHandle, CommandError> {
+        self.inner.takeoff()
+    }
+
+    pub fn throw_takeoff(&self) -> Result<CommandHandle, CommandError> {
+        self.inner.throw_takeoff()
+    }
+
+    pub fn land(&self) -> Result<CommandHandle, CommandError> {
+        self.inner.land()
+    }
+
+    pub fn palm_land(&self) -> Result<CommandHandle, CommandError> {
+        self.inner.palm_land()
+    }
+
+    // Flips are refused while not airborne or with battery below phase::DEFAULT_MIN_FLIP_BATTERY
+    pub fn flip(&self, direction: FlipDirection) -> Result<CommandHandle, CommandError> {
+        self.inner.flip(direction)
+    }
+
+    pub fn set_min_flip_battery(&self, battery_percentage: i8) {
+        self.inner
+            .phase
+            .lock()
+            .unwrap()
+            .set_min_flip_battery(battery_percentage);
+    }
+
+    pub fn flight_phase(&self) -> FlightPhase {
+        self.inner.flight_phase()
+    }
+
+    // Frame for the manual rx/ry stick inputs, uses the IMU yaw from the flight log.
+    pub fn set_control_frame(&self, frame: ControlFrame) {
+        tracing::info!(?frame, "set control frame");
+        self.inner.frame.lock().unwrap().set_frame(frame);
+    }
+
+    pub fn control_frame(&self) -> ControlFrame {
+        self.inner.frame.lock().unwrap().frame()
+    }
+
+    // Enforces the geofence in the stick loop, None disables it. The fence is
+    // relative to the takeoff point, see TelloEvent::GeofenceBreached.
+    pub fn set_geofence(&self, fence: Option<Geofence>) {
+        self.inner.geofence.lock().unwrap().set_fence(fence);
+    }
+
+    pub fn geofence(&self) -> Option<Geofence> {
+        self.inner.geofence.lock().unwrap().fence()
+    }
+
+    // Uses the current drone heading as "forward" for ControlFrame::PilotRelative,
+    // returns the heading or None if no IMU data were received yet.
+    pub fn set_heading_reference(&self) -> Option<f64> {
+        let yaw = self.inner.telemetry.read().unwrap().yaw()?;
+        self.set_heading_reference_deg(yaw);
+        Some(yaw)
+    }
+
+    // Holds the target velocity using the MVO velocities, the autopilot control
+    // source takes over until stop_autopilot() or until the pilot moves the sticks.
+    pub fn set_velocity(&self, target: VelocityTarget) {
+        self.inner.set_velocity(target);
+    }
+
+    // Stops the velocity control or the running maneuver (which fails with CommandError::Aborted).
+    pub fn stop_autopilot(&self) {
+        self.inner.stop_autopilot();
+    }
+
+    pub fn velocity_target(&self) -> Option<VelocityTarget> {
+        self.inner.autopilot.lock().unwrap().velocity().target()
+    }
+
+    pub fn set_velocity_config(&self, config: VelocityConfig) {
+        self.inner
+            .autopilot
+            .lock()
+            .unwrap()
+            .velocity()
+            .set_config(config);
+    }
+
+    // Moves by `offset` meters (x forward, y right, z up) of the current drone heading,
+    // using the MVO position for feedback. Fails with CommandError::FeedbackLost when
+    // the drone loses the position (e.g. over a surface without texture).
+    pub fn move_by(&self, offset: Vec3<f32>) -> Result<CommandHandle, CommandError> {
+        self.inner.move_by(offset)
+    }
+
+    // Turns to the IMU yaw in degrees, the short way round.
+    pub fn rotate_to(&self, yaw: f64) -> Result<CommandHandle, CommandError> {
+        self.inner.rotate_to(yaw)
+    }
+
+    // Turns by `deg` degrees, positive is clockwise.
+    pub fn rotate_by(&self, deg: f64) -> Result<CommandHandle, CommandError> {
+        self.inner.rotate_by(deg)
+    }
+
+    // Follows the trajectory (relative to the current position and heading) with
+    // velocity feed forward and position correction, for smooth camera moves.
+    pub fn follow_trajectory(&self, trajectory: Trajectory) -> Result<CommandHandle, CommandError> {
+        self.inner.follow_trajectory(trajectory)
+    }
+
+    // Records every stick update sent to the drone with the MVO pose as JSON lines,
+    // replay it with recorder::StickPlayer. Replaces a running recording.
+    pub fn start_recording<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let recorder = StickRecorder::create(path)?;
+        if let Some(old) = self.inner.recorder.lock().unwrap().replace(recorder) {
+            old.finish()?;
+        }
+        Ok(())
+    }
+
+    // Returns the number of recorded samples, 0 when nothing was recorded.
+    pub fn stop_recording(&self) -> io::Result<usize> {
+        match self.inner.recorder.lock().unwrap().take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(0),
+        }
+    }
+
+    // Flies back to the takeoff point at ManeuverConfig::return_height, turns to
+    // the takeoff heading and lands. See TelloEvent::ReturnHomeProgress.
+    pub fn return_home(&self) -> Result<CommandHandle, CommandError> {
+        self.inner.return_home()
+    }
+
+    // Holds the height above the takeoff point (m) until stop_autopilot(), the handle
+    // completes on the first convergence, see TelloEvent::AltitudeConverged. A height
+    // above the flight envelope max_height fails with CommandError::OutsideEnvelope.
+    pub fn hold_altitude(&self, meters: f32) -> Result<CommandHandle, CommandError> {
+        self.inner.hold_altitude(meters)
+    }
+
+    // Takes off and climbs to `meters`, holding the altitude afterwards.
+    pub fn takeoff_to(&self, meters: f32) -> Result<CommandHandle, CommandError> {
+        self.inner.takeoff_to(meters)
+    }
+
+    // The answer is used as the limit for hold_altitude() and takeoff_to(),
+    // sent automatically on connect.
+    pub fn query_height_limit(&self) {
+        self.inner.query_height_limit();
+    }
+
+    pub fn maneuver_config(&self) -> ManeuverConfig {
+        self.inner.autopilot.lock().unwrap().config()
     }
 
-    pub fn takeoff(&self) {
-        self.inner.takeoff();
+    pub fn set_maneuver_config(&self, config: ManeuverConfig) {
+        self.inner.autopilot.lock().unwrap().set_config(config);
     }
 
-    pub fn land(&self) {
-        self.inner.land();
+    pub fn set_heading_reference_deg(&self, yaw: f64) {
+        tracing::info!(yaw, "set heading reference");
+        self.inner.frame.lock().unwrap().set_pilot_yaw(yaw);
     }
 
     pub fn forward(&self, amt: f32) {
@@ -146,8 +485,7 @@ impl TelloController {
     }
 
     pub fn flying(&self) -> bool {
-        let g = self.inner.flying.read().unwrap();
-        *g
+        self.inner.flying.load(Ordering::Relaxed)
     }
 
     // Receive the control data from the tello
@@ -176,6 +514,8 @@ impl TelloController {
         *g = !*g;
     }
 
+    // Requests the SPS/PPS and keyframes while the video is on: at the start,
+    // after a loss and when the stream stalls, with backoff.
     pub fn start_video_contoller(&self) -> JoinHandle<()> {
         let self_local = self.inner.clone();
         let video = self.video.clone();
@@ -184,14 +524,73 @@ impl TelloController {
             let video_on = *g;
             drop(g);
             if video_on {
-                self_local.query_video_sps_pps();
+                self_local.request_keyframe();
             }
 
-            thread::sleep(Duration::from_millis(500));
+            thread::sleep(video::KEYFRAME_REQUEST_BACKOFF);
         });
         j
     }
 
+    // Records the video as fragmented MP4, starting at the next keyframe.
+    // Replaces a running recording.
+    pub fn start_video_recording<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let recorder = Mp4Recorder::create(path)?;
+        if let Some(mut old) = self.inner.video_recorder.lock().unwrap().replace(recorder) {
+            old.finish()?;
+        }
+        Ok(())
+    }
+
+    // Returns the number of recorded frames, 0 when nothing was recorded.
+    pub fn stop_video_recording(&self) -> io::Result<usize> {
+        match self.inner.video_recorder.lock().unwrap().take() {
+            Some(mut recorder) => recorder.finish().map(|_| recorder.frames()),
+            None => Ok(0),
+        }
+    }
+
+    // A still picture from the live video: waits for the next keyframe (one is
+    // requested right away) and decodes it at full resolution, falls back to the
+    // last keyframe when none arrives in time. Much faster than take_picture(),
+    // which transfers the 5 MP photo.
+    #[cfg(feature = "snapshot")]
+    pub fn snapshot(&self) -> Result<snapshot::Image, snapshot::SnapshotError> {
+        let method_name = "snapshot";
+        let (id, rx) = self.inner.snapshot_waiters.lock().unwrap().wait();
+        self.inner.want_keyframe();
+        let frame = match rx.recv_timeout(snapshot::SNAPSHOT_TIMEOUT) {
+            Ok(frame) => frame,
+            Err(_) => {
+                let last = self.inner.snapshot_waiters.lock().unwrap().cancel(id);
+                // the keyframe may have arrived in the meantime
+                let frame = rx.try_recv().ok().or(last);
+                tracing::warn!(
+                    method_name,
+                    fallback = frame.as_ref().map(|f| f.seq),
+                    "no keyframe in time"
+                );
+                frame.ok_or(snapshot::SnapshotError::Timeout)?
+            }
+        };
+        let metadata = snapshot::ImageMetadata::new(&frame, &self.inner.telemetry.read().unwrap());
+        // the keyframe carries its SPS/PPS, a fresh decoder is enough
+        let mut decoder = decode::FrameDecoder::new(decode::DecoderConfig::default())?;
+        let frame = decoder.decode(&frame)?.ok_or_else(|| {
+            snapshot::SnapshotError::Decode(decode::DecodeError::Decode(
+                "no picture in the keyframe".to_string(),
+            ))
+        })?;
+        Ok(snapshot::Image { frame, metadata })
+    }
+
+    // Loss, rate and quality counters of the video stream.
+    pub fn video_stats(&self) -> VideoStats {
+        let mut video = self.inner.video.lock().unwrap();
+        video.update_rates(Instant::now());
+        video.stats()
+    }
+
     pub fn is_connected(&self) -> bool {
         self.inner.connected.load(Ordering::Relaxed)
     }
@@ -206,11 +605,10 @@ impl TelloController {
         );
         let msg = messages::connect(self.inner.video_port);
         let r = self.inner.ctrl_conn.send_to(&msg, &self.inner.remote_addr);
-        if r.is_err() {
-            let errmsg = format!("can't connect to tello: {}", r.unwrap_err());
-            utils::fatal(&errmsg);
-        }
-        let sent_bytes = r.unwrap();
+        let sent_bytes = match r {
+            Ok(sent_bytes) => sent_bytes,
+            Err(e) => utils::fatal(&format!("can't connect to tello: {}", e)),
+        };
         tracing::info!(method_name, sent_bytes, "connecting...");
     }
 
@@ -219,12 +617,24 @@ impl TelloController {
         tracing::debug!(method_name, self.inner.remote_addr, "send");
         let msg = messages::take_picture(self.inner.ctrl_seq.fetch_add(1, Ordering::Relaxed));
         let r = self.inner.ctrl_conn.send_to(&msg, &self.inner.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to take picture: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to take picture: {}", e);
         }
     }
 
     pub fn query_video_sps_pps(&self) {
         self.inner.query_video_sps_pps()
     }
+
+    pub fn start_smart_video(&self, cmd: SmartVideoCmd) {
+        self.inner.smart_video(cmd, true);
+    }
+
+    pub fn stop_smart_video(&self, cmd: SmartVideoCmd) {
+        self.inner.smart_video(cmd, false);
+    }
+
+    pub fn battery_percentage(&self) -> Option<i8> {
+        self.inner.telemetry.read().unwrap().battery_percentage()
+    }
 }
@@ -1,5 +1,7 @@
 use std::{fs, path::PathBuf};
 
+use serde::Deserialize;
+
 use crate::{
     crc::{calculate_crc16, calculate_crc8},
     env, utils,
@@ -32,11 +34,24 @@ enum FlipType {
     _FlipForwardRight = 7,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlipDirection {
+    Forward,
+    Left,
+    Backward,
+    Right,
+}
+
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 pub enum SmartVideoCmd {
-    Sv360 = 1 << 2,    // Slowly rotate around 360 degrees.
+    #[serde(rename = "360")]
+    Sv360 = 1 << 2, // Slowly rotate around 360 degrees.
+    #[serde(rename = "circle")]
     SvCircle = 2 << 2, // Circle around a point in front of the drone.
-    SvUpOut = 3 << 2,  // Perform the 'Up and Out' manouvre.
+    #[serde(rename = "up_out")]
+    SvUpOut = 3 << 2, // Perform the 'Up and Out' manouvre.
 }
 
 #[repr(u8)]
@@ -435,7 +450,7 @@ impl LightData {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IMUData {
     pub roll: f64,
     pub pitch: f64,
@@ -443,7 +458,7 @@ pub struct IMUData {
     pub temperature: i16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MVOData {
     pub position: Option<utils::Vec3<f32>>,
     pub vx: Option<i16>,
@@ -579,7 +594,7 @@ impl LogData {
 // FlightData holds our current knowledge of the drone's state.
 // This data is not all sent at once from the drone, different fields may be updated
 // at varying rates.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct FlightData {
     pub battery_critical: bool,
@@ -619,7 +634,7 @@ pub struct FlightData {
 impl FlightData {
     pub fn new(pl: &Vec<u8>) -> Self {
         Self {
-            height: (pl[0] as i16) + (pl[1] as i16) << 8,
+            height: (pl[0] as i16) | (pl[1] as i16) << 8,
             north_speed: ((pl[2] as u16) | (pl[3] as u16) << 8) as i16,
             east_speed: (pl[4] as i16) | (pl[5] as i16) << 8,
             vertical_speed: (pl[6] as i16) | (pl[7] as i16) << 8,
@@ -636,8 +651,8 @@ impl FlightData {
 
             imu_calibration_state: pl[11] as i8,
             battery_percentage: pl[12] as i8,
-            drone_fly_time_left: (pl[13] as i16) + (pl[14] as i16) << 8,
-            battery_milli_volts: ((pl[15] as u16) + (pl[16] as u16) << 8) as f32 / 10.0,
+            drone_fly_time_left: (pl[13] as i16) | (pl[14] as i16) << 8,
+            battery_milli_volts: ((pl[15] as u16) | (pl[16] as u16) << 8) as f32 / 10.0,
 
             flying: (pl[17] & 1) == 1,
             on_ground: (pl[17] >> 1 & 1) == 1,
@@ -722,11 +737,33 @@ pub fn flip_right(seq: u16) -> Vec<u8> {
     TelloPacket::new(PT_FLIP, MSG_DO_FLIP, seq, Some(FlipType::FlipRight as u8)).to_buffer()
 }
 
+#[must_use]
+pub fn flip(seq: u16, direction: FlipDirection) -> Vec<u8> {
+    match direction {
+        FlipDirection::Forward => flip_forward(seq),
+        FlipDirection::Left => flip_left(seq),
+        FlipDirection::Backward => flip_backward(seq),
+        FlipDirection::Right => flip_right(seq),
+    }
+}
+
 #[must_use]
 pub fn smart_video(seq: u16, cmd: SmartVideoCmd) -> Vec<u8> {
     TelloPacket::new(PT_SET, MSG_DO_SMART_VIDEO, seq, Some(cmd as u8)).to_buffer()
 }
 
+// the lowest bit starts (1) or stops (0) the smart video
+#[must_use]
+pub fn smart_video_toggle(seq: u16, cmd: SmartVideoCmd, start: bool) -> Vec<u8> {
+    TelloPacket::new(
+        PT_SET,
+        MSG_DO_SMART_VIDEO,
+        seq,
+        Some(cmd as u8 | start as u8),
+    )
+    .to_buffer()
+}
+
 #[must_use]
 pub fn query_attitude(seq: u16) -> Vec<u8> {
     TelloPacket::new(PT_GET, MSG_QUERY_ATTITUDE, seq, None).to_buffer()
@@ -1239,6 +1276,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_flight_data_battery() {
+        let mut pl = vec![0; 24];
+        pl[12] = 87;
+        pl[13] = 0x2c;
+        pl[14] = 0x01;
+        pl[15] = 0x10;
+        pl[16] = 0x0f;
+        pl[17] = 1 << 5;
+        let fd = FlightData::new(&pl);
+        assert_eq!(87, fd.battery_percentage);
+        assert_eq!(300, fd.drone_fly_time_left);
+        assert_eq!(385.6, fd.battery_milli_volts);
+        assert!(fd.battery_low && !fd.battery_critical);
+    }
+
     #[test]
     fn test_panic_on_buffer() {
         let subscriber = tracing_subscriber::fmt()
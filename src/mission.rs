@@ -0,0 +1,865 @@
+use std::{
+    fmt, fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    command::CommandHandle,
+    envelope::FlightEnvelope,
+    events::TelloEvent,
+    geofence::Geofence,
+    messages::{FlipDirection, SmartVideoCmd},
+    phase::CommandError,
+    utils::Vec3,
+    TelloController,
+};
+
+pub const DEFAULT_MIN_BATTERY: i8 = 30;
+
+// how long the runner waits for a step when the mission doesn't say otherwise
+const TAKEOFF_STEP_TIMEOUT: Duration = Duration::from_secs(15);
+const LAND_STEP_TIMEOUT: Duration = Duration::from_secs(20);
+const MOVE_STEP_TIMEOUT: Duration = Duration::from_secs(30);
+const FLIP_STEP_TIMEOUT: Duration = Duration::from_secs(10);
+// the runner checks for pause/abort this often while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// conservative speeds used to estimate the mission duration
+const EST_SPEED: f32 = 0.5; // m/s
+const EST_TURN_RATE: f64 = 45.0; // deg/s
+const EST_TAKEOFF: Duration = Duration::from_secs(5);
+const EST_LAND: Duration = Duration::from_secs(5);
+const EST_FLIP: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissionAction {
+    Takeoff,
+    // climb (or descend) to the height above the takeoff point, meters
+    Climb {
+        height: f32,
+    },
+    // meters of the current heading, x forward, y right, z up
+    Move {
+        #[serde(default)]
+        x: f32,
+        #[serde(default)]
+        y: f32,
+        #[serde(default)]
+        z: f32,
+    },
+    // degrees, positive is clockwise
+    Rotate {
+        deg: f64,
+    },
+    TakePicture,
+    SmartVideo {
+        mode: SmartVideoCmd,
+        secs: f64,
+    },
+    Wait {
+        secs: f64,
+    },
+    Flip {
+        direction: FlipDirection,
+    },
+    Land,
+}
+
+// A step may come with its own timeout in seconds:
+//   - land
+//   - move: { x: 1.0 }
+//     timeout: 20
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "StepRepr")]
+pub struct MissionStep {
+    pub action: MissionAction,
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StepRepr {
+    Plain(MissionAction),
+    Timed {
+        #[serde(flatten)]
+        action: MissionAction,
+        timeout: f64,
+    },
+}
+
+impl From<StepRepr> for MissionStep {
+    fn from(repr: StepRepr) -> Self {
+        match repr {
+            StepRepr::Plain(action) => Self {
+                action,
+                timeout: None,
+            },
+            StepRepr::Timed { action, timeout } => Self {
+                action,
+                timeout: Some(Duration::from_secs_f64(timeout.max(0.0))),
+            },
+        }
+    }
+}
+
+impl MissionStep {
+    pub fn new(action: MissionAction) -> Self {
+        Self {
+            action,
+            timeout: None,
+        }
+    }
+}
+
+// Checked before takeoff, distances are relative to the takeoff point.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MissionLimits {
+    #[serde(default = "default_min_battery")]
+    pub min_battery: i8,
+    // seconds
+    pub max_duration: Option<f64>,
+    // meters
+    pub max_height: Option<f32>,
+    pub max_distance: Option<f32>,
+}
+
+fn default_min_battery() -> i8 {
+    DEFAULT_MIN_BATTERY
+}
+
+impl Default for MissionLimits {
+    fn default() -> Self {
+        Self {
+            min_battery: DEFAULT_MIN_BATTERY,
+            max_duration: None,
+            max_height: None,
+            max_distance: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Mission {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub limits: MissionLimits,
+    pub steps: Vec<MissionStep>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MissionError {
+    Parse(String),
+    // the mission doesn't make sense, e.g. doesn't start with a takeoff
+    Invalid {
+        step: Option<usize>,
+        reason: String,
+    },
+    LowBattery {
+        battery: i8,
+        required: i8,
+    },
+    NoTelemetry,
+    TooLong {
+        estimated: Duration,
+        limit: Duration,
+    },
+    OutsideLimits {
+        step: usize,
+        reason: String,
+    },
+    Command {
+        step: usize,
+        error: CommandError,
+    },
+    StepTimeout {
+        step: usize,
+    },
+    Aborted {
+        step: usize,
+    },
+    AlreadyRunning,
+}
+
+impl fmt::Display for MissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissionError::Parse(e) => write!(f, "can't parse mission: {}", e),
+            MissionError::Invalid {
+                step: Some(step),
+                reason,
+            } => write!(f, "invalid step {}: {}", step, reason),
+            MissionError::Invalid { step: None, reason } => {
+                write!(f, "invalid mission: {}", reason)
+            }
+            MissionError::LowBattery { battery, required } => write!(
+                f,
+                "mission needs at least {}% battery, have {}%",
+                required, battery
+            ),
+            MissionError::NoTelemetry => write!(f, "no flight data from the drone yet"),
+            MissionError::TooLong { estimated, limit } => write!(
+                f,
+                "mission takes about {:?}, the limit is {:?}",
+                estimated, limit
+            ),
+            MissionError::OutsideLimits { step, reason } => {
+                write!(f, "step {} leaves the mission limits: {}", step, reason)
+            }
+            MissionError::Command { step, error } => write!(f, "step {} failed: {}", step, error),
+            MissionError::StepTimeout { step } => write!(f, "step {} timed out", step),
+            MissionError::Aborted { step } => write!(f, "mission aborted at step {}", step),
+            MissionError::AlreadyRunning => write!(f, "mission is already running"),
+        }
+    }
+}
+
+impl std::error::Error for MissionError {}
+
+impl Mission {
+    pub fn from_json(s: &str) -> Result<Self, MissionError> {
+        serde_json::from_str(s).map_err(|e| MissionError::Parse(e.to_string()))
+    }
+
+    pub fn from_yaml(s: &str) -> Result<Self, MissionError> {
+        serde_yaml::from_str(s).map_err(|e| MissionError::Parse(e.to_string()))
+    }
+
+    // Loads a .json mission, anything else is read as YAML.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MissionError> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .map_err(|e| MissionError::Parse(format!("{}: {}", path.display(), e)))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&s),
+            _ => Self::from_yaml(&s),
+        }
+    }
+
+    // Rough duration of the mission with conservative speeds.
+    pub fn estimated_duration(&self) -> Duration {
+        let mut height = 0.0f32;
+        let mut total = Duration::ZERO;
+        for step in &self.steps {
+            total += match step.action {
+                MissionAction::Takeoff => EST_TAKEOFF,
+                MissionAction::Climb { height: h } => {
+                    let d = (h - height).abs();
+                    height = h;
+                    Duration::from_secs_f32(d / EST_SPEED)
+                }
+                MissionAction::Move { x, y, z } => {
+                    height += z;
+                    Duration::from_secs_f32((x * x + y * y + z * z).sqrt() / EST_SPEED)
+                }
+                MissionAction::Rotate { deg } => Duration::from_secs_f64(deg.abs() / EST_TURN_RATE),
+                MissionAction::TakePicture => Duration::ZERO,
+                MissionAction::SmartVideo { secs, .. } | MissionAction::Wait { secs } => {
+                    Duration::from_secs_f64(secs.max(0.0))
+                }
+                MissionAction::Flip { .. } => EST_FLIP,
+                MissionAction::Land => EST_LAND,
+            };
+        }
+        total
+    }
+
+    // Checks the mission structure and the planned path against the limits,
+    // the battery is checked by MissionRunner::validate().
+    pub fn check(&self) -> Result<(), MissionError> {
+        let invalid = |step: Option<usize>, reason: &str| MissionError::Invalid {
+            step,
+            reason: reason.to_owned(),
+        };
+        match self.steps.first() {
+            Some(step) if step.action == MissionAction::Takeoff => {}
+            _ => return Err(invalid(None, "the first step must be takeoff")),
+        }
+        if self.steps.last().map(|step| &step.action) != Some(&MissionAction::Land) {
+            return Err(invalid(None, "the last step must be land"));
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            match step.action {
+                MissionAction::Takeoff if i > 0 => {
+                    return Err(invalid(Some(i), "takeoff must be the first step"))
+                }
+                MissionAction::Land if i + 1 < self.steps.len() => {
+                    return Err(invalid(Some(i), "land must be the last step"))
+                }
+                MissionAction::Climb { height } if height <= 0.0 => {
+                    return Err(invalid(Some(i), "climb height must be positive"))
+                }
+                MissionAction::SmartVideo { secs, .. } | MissionAction::Wait { secs }
+                    if secs < 0.0 =>
+                {
+                    return Err(invalid(Some(i), "negative duration"))
+                }
+                _ => {}
+            }
+        }
+        let limits = &self.limits;
+        self.check_path("mission", limits.max_height, limits.max_distance)?;
+        if let Some(max) = limits.max_duration {
+            let limit = Duration::from_secs_f64(max.max(0.0));
+            let estimated = self.estimated_duration();
+            if estimated > limit {
+                return Err(MissionError::TooLong { estimated, limit });
+            }
+        }
+        Ok(())
+    }
+
+    // check() plus the planned path against the controller geofence and flight
+    // envelope, which would otherwise clamp the mission in flight.
+    pub fn check_within(
+        &self,
+        fence: Option<&Geofence>,
+        envelope: &FlightEnvelope,
+    ) -> Result<(), MissionError> {
+        self.check()?;
+        self.check_path("envelope", envelope.max_height, envelope.max_distance)?;
+        let fence = match fence {
+            Some(fence) => fence,
+            None => return Ok(()),
+        };
+        self.check_path("geofence", Some(fence.max_height), None)?;
+        // the fence min height is left out, the planned path starts on the ground
+        for (step, pos) in self.planned_path().iter().enumerate() {
+            if !fence.contains(Some(pos), None) {
+                return Err(MissionError::OutsideLimits {
+                    step,
+                    reason: format!("({:.1}, {:.1})m outside the geofence", pos.x, pos.y),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Planned position after each step, relative to the takeoff point and
+    // heading (x forward, y right, z up).
+    fn planned_path(&self) -> Vec<Vec3<f32>> {
+        let (mut pos, mut heading) = (Vec3::new(0.0f32, 0.0, 0.0), 0.0f64);
+        let mut path = Vec::with_capacity(self.steps.len());
+        for step in &self.steps {
+            match step.action {
+                MissionAction::Climb { height } => pos.z = height,
+                MissionAction::Move { x, y, z } => {
+                    let (sin, cos) = (heading.to_radians() as f32).sin_cos();
+                    pos.x += x * cos - y * sin;
+                    pos.y += x * sin + y * cos;
+                    pos.z += z;
+                }
+                MissionAction::Rotate { deg } => heading += deg,
+                _ => {}
+            }
+            path.push(pos);
+        }
+        path
+    }
+
+    // `limits` names the limits in the error.
+    fn check_path(
+        &self,
+        limits: &str,
+        max_height: Option<f32>,
+        max_distance: Option<f32>,
+    ) -> Result<(), MissionError> {
+        for (step, pos) in self.planned_path().iter().enumerate() {
+            if let Some(max) = max_height {
+                if pos.z > max {
+                    return Err(MissionError::OutsideLimits {
+                        step,
+                        reason: format!("height {:.1}m above the {} {:.1}m", pos.z, limits, max),
+                    });
+                }
+            }
+            if let Some(max) = max_distance {
+                let d = (pos.x * pos.x + pos.y * pos.y).sqrt();
+                if d > max {
+                    return Err(MissionError::OutsideLimits {
+                        step,
+                        reason: format!("distance {:.1}m over the {} {:.1}m", d, limits, max),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MissionAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissionAction::Takeoff => write!(f, "takeoff"),
+            MissionAction::Climb { height } => write!(f, "climb to {}m", height),
+            MissionAction::Move { x, y, z } => write!(f, "move by ({}, {}, {})m", x, y, z),
+            MissionAction::Rotate { deg } => write!(f, "rotate by {}deg", deg),
+            MissionAction::TakePicture => write!(f, "take picture"),
+            MissionAction::SmartVideo { mode, secs } => {
+                write!(f, "smart video {:?} for {}s", mode, secs)
+            }
+            MissionAction::Wait { secs } => write!(f, "wait {}s", secs),
+            MissionAction::Flip { direction } => write!(f, "flip {:?}", direction),
+            MissionAction::Land => write!(f, "land"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissionState {
+    Idle,
+    Running,
+    Paused,
+    Aborting,
+    Finished,
+}
+
+#[derive(Debug)]
+struct RunnerControl {
+    state: Mutex<MissionState>,
+    changed: Condvar,
+    abort: AtomicBool,
+}
+
+// Runs a mission on the controller in its own thread. The mission lands the
+// drone when it's aborted or when any step fails.
+pub struct MissionRunner {
+    tello: TelloController,
+    mission: Arc<Mission>,
+    control: Arc<RunnerControl>,
+}
+
+impl MissionRunner {
+    pub fn new(tello: &TelloController, mission: Mission) -> Self {
+        Self {
+            tello: tello.clone(),
+            mission: Arc::new(mission),
+            control: Arc::new(RunnerControl {
+                state: Mutex::new(MissionState::Idle),
+                changed: Condvar::new(),
+                abort: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn mission(&self) -> &Mission {
+        &self.mission
+    }
+
+    pub fn state(&self) -> MissionState {
+        *self.control.state.lock().unwrap()
+    }
+
+    // Validates the mission against its limits, the controller geofence and
+    // flight envelope, and the battery level.
+    pub fn validate(&self) -> Result<(), MissionError> {
+        let fence = self.tello.geofence();
+        self.mission
+            .check_within(fence.as_ref(), &self.tello.flight_envelope())?;
+        let battery = self
+            .tello
+            .battery_percentage()
+            .ok_or(MissionError::NoTelemetry)?;
+        let required = self.mission.limits.min_battery;
+        if battery < required {
+            return Err(MissionError::LowBattery { battery, required });
+        }
+        Ok(())
+    }
+
+    pub fn start(&self) -> Result<JoinHandle<Result<(), MissionError>>, MissionError> {
+        let method_name = "mission_start";
+        self.validate()?;
+        {
+            let mut state = self.control.state.lock().unwrap();
+            if *state != MissionState::Idle {
+                return Err(MissionError::AlreadyRunning);
+            }
+            *state = MissionState::Running;
+        }
+        tracing::info!(method_name, name = self.mission.name, "mission started");
+        let run = Run {
+            tello: self.tello.clone(),
+            mission: self.mission.clone(),
+            control: self.control.clone(),
+        };
+        Ok(thread::spawn(move || run.run()))
+    }
+
+    // Pauses the mission, the drone hovers. A running move, rotate or climb is
+    // stopped and resume() flies the rest of it, a running wait is paused as
+    // well, the other steps pause once they're done.
+    pub fn pause(&self) {
+        self.set_state(MissionState::Running, MissionState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.set_state(MissionState::Paused, MissionState::Running);
+    }
+
+    // Stops the running step and lands.
+    pub fn abort(&self) {
+        tracing::warn!(name = self.mission.name, "mission abort requested");
+        self.control.abort.store(true, Ordering::SeqCst);
+        self.control.changed.notify_all();
+    }
+
+    fn set_state(&self, from: MissionState, to: MissionState) {
+        let mut state = self.control.state.lock().unwrap();
+        if *state == from {
+            tracing::info!(?from, ?to, "mission state");
+            *state = to;
+            self.control.changed.notify_all();
+        }
+    }
+}
+
+struct Run {
+    tello: TelloController,
+    mission: Arc<Mission>,
+    control: Arc<RunnerControl>,
+}
+
+impl Run {
+    fn run(&self) -> Result<(), MissionError> {
+        let method_name = "mission_run";
+        let events = &self.tello.inner.events;
+        let mut result = Ok(());
+        for (index, step) in self.mission.steps.iter().enumerate() {
+            if let Err(e) = self.wait_while_paused(index) {
+                result = Err(e);
+                break;
+            }
+            let name = step.action.to_string();
+            tracing::info!(method_name, index, step = name, "step started");
+            events.publish(TelloEvent::MissionStepStarted {
+                index,
+                step: name.clone(),
+            });
+            let r = self.run_step(index, step);
+            if let Err(ref e) = r {
+                tracing::warn!(method_name, index, step = name, "step failed: {}", e);
+            }
+            events.publish(TelloEvent::MissionStepFinished {
+                index,
+                step: name,
+                error: r.as_ref().err().map(|e| e.to_string()),
+            });
+            if r.is_err() {
+                result = r;
+                break;
+            }
+        }
+        let completed = result.is_ok();
+        if !completed {
+            self.land_now();
+        }
+        *self.control.state.lock().unwrap() = MissionState::Finished;
+        events.publish(TelloEvent::MissionEnded { completed });
+        result
+    }
+
+    fn aborted(&self) -> bool {
+        self.control.abort.load(Ordering::SeqCst)
+    }
+
+    fn wait_while_paused(&self, step: usize) -> Result<(), MissionError> {
+        let mut state = self.control.state.lock().unwrap();
+        while *state == MissionState::Paused && !self.aborted() {
+            state = self.control.changed.wait(state).unwrap();
+        }
+        if self.aborted() {
+            return Err(MissionError::Aborted { step });
+        }
+        Ok(())
+    }
+
+    fn run_step(&self, index: usize, step: &MissionStep) -> Result<(), MissionError> {
+        let tello = &self.tello;
+        let command = |r: Result<CommandHandle, CommandError>| {
+            r.map_err(|error| MissionError::Command { step: index, error })
+        };
+        match step.action {
+            MissionAction::Takeoff => {
+                let handle = command(tello.takeoff())?;
+                self.wait_handle(index, &handle, step.timeout.unwrap_or(TAKEOFF_STEP_TIMEOUT))
+            }
+            MissionAction::Climb { height } => {
+                let handle = command(tello.hold_altitude(height))?;
+                self.wait_maneuver(index, handle, step.timeout.unwrap_or(MOVE_STEP_TIMEOUT))
+            }
+            MissionAction::Move { x, y, z } => {
+                let handle = command(tello.move_by(Vec3::new(x, y, z)))?;
+                self.wait_maneuver(index, handle, step.timeout.unwrap_or(MOVE_STEP_TIMEOUT))
+            }
+            MissionAction::Rotate { deg } => {
+                let handle = command(tello.rotate_by(deg))?;
+                self.wait_maneuver(index, handle, step.timeout.unwrap_or(MOVE_STEP_TIMEOUT))
+            }
+            MissionAction::TakePicture => {
+                tello.take_picture();
+                Ok(())
+            }
+            MissionAction::SmartVideo { mode, secs } => {
+                tello.start_smart_video(mode);
+                let r = self.sleep(index, Duration::from_secs_f64(secs));
+                tello.stop_smart_video(mode);
+                r
+            }
+            MissionAction::Wait { secs } => self.sleep(index, Duration::from_secs_f64(secs)),
+            MissionAction::Flip { direction } => {
+                let handle = command(tello.flip(direction))?;
+                self.wait_handle(index, &handle, step.timeout.unwrap_or(FLIP_STEP_TIMEOUT))
+            }
+            MissionAction::Land => {
+                tello.stop_autopilot();
+                let handle = command(tello.land())?;
+                self.wait_handle(index, &handle, step.timeout.unwrap_or(LAND_STEP_TIMEOUT))
+            }
+        }
+    }
+
+    fn wait_handle(
+        &self,
+        step: usize,
+        handle: &CommandHandle,
+        timeout: Duration,
+    ) -> Result<(), MissionError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(r) = handle.wait_timeout(POLL_INTERVAL) {
+                return r.map_err(|error| MissionError::Command { step, error });
+            }
+            if self.aborted() {
+                return Err(MissionError::Aborted { step });
+            }
+            if Instant::now() >= deadline {
+                return Err(MissionError::StepTimeout { step });
+            }
+        }
+    }
+
+    // Waits for an autopilot step. Pausing stops the maneuver (the drone hovers),
+    // resuming flies the rest of it, the time spent paused doesn't count.
+    fn wait_maneuver(
+        &self,
+        step: usize,
+        mut handle: CommandHandle,
+        timeout: Duration,
+    ) -> Result<(), MissionError> {
+        let method_name = "mission_wait";
+        let mut deadline = Instant::now() + timeout;
+        loop {
+            if let Some(r) = handle.wait_timeout(POLL_INTERVAL) {
+                return r.map_err(|error| MissionError::Command { step, error });
+            }
+            if self.aborted() {
+                return Err(MissionError::Aborted { step });
+            }
+            if *self.control.state.lock().unwrap() == MissionState::Paused {
+                let paused_at = Instant::now();
+                let paused = self.tello.inner.pause_maneuver();
+                tracing::info!(method_name, step, "step paused");
+                self.wait_while_paused(step)?;
+                deadline += paused_at.elapsed();
+                if let Some(paused) = paused {
+                    tracing::info!(method_name, step, "step resumed");
+                    handle = self
+                        .tello
+                        .inner
+                        .resume_maneuver(paused)
+                        .map_err(|error| MissionError::Command { step, error })?;
+                }
+                continue;
+            }
+            if Instant::now() >= deadline {
+                return Err(MissionError::StepTimeout { step });
+            }
+        }
+    }
+
+    // Sleeps for `duration` of running time, the time spent paused doesn't count.
+    fn sleep(&self, step: usize, duration: Duration) -> Result<(), MissionError> {
+        let mut left = duration;
+        let mut state = self.control.state.lock().unwrap();
+        while !left.is_zero() {
+            if self.aborted() {
+                return Err(MissionError::Aborted { step });
+            }
+            if *state == MissionState::Paused {
+                state = self.control.changed.wait(state).unwrap();
+                continue;
+            }
+            let started = Instant::now();
+            let (s, _) = self
+                .control
+                .changed
+                .wait_timeout(state, left.min(POLL_INTERVAL))
+                .unwrap();
+            state = s;
+            left = left.saturating_sub(started.elapsed());
+        }
+        Ok(())
+    }
+
+    // Lands whatever the flight phase is, used when the mission doesn't finish.
+    fn land_now(&self) {
+        let method_name = "mission_land";
+        *self.control.state.lock().unwrap() = MissionState::Aborting;
+        self.tello.stop_autopilot();
+        if !self.tello.flight_phase().is_airborne() {
+            return;
+        }
+        tracing::warn!(method_name, "landing");
+        match self.tello.land() {
+            Ok(handle) => {
+                if let Err(e) = handle.wait() {
+                    tracing::warn!(method_name, "land failed: {}", e);
+                }
+            }
+            Err(e) => tracing::warn!(method_name, "can't land: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+name: demo
+limits:
+  max_height: 3.0
+  max_distance: 2.0
+  max_duration: 120
+steps:
+  - takeoff
+  - climb: { height: 1.5 }
+  - move: { x: 1.0 }
+    timeout: 20
+  - rotate: { deg: 90 }
+  - take_picture
+  - smart_video: { mode: circle, secs: 10 }
+  - wait: { secs: 2 }
+  - flip: { direction: forward }
+  - land
+"#;
+
+    #[test]
+    fn test_parse_yaml_and_json() {
+        let mission = Mission::from_yaml(YAML).unwrap();
+        assert_eq!(9, mission.steps.len());
+        assert_eq!(DEFAULT_MIN_BATTERY, mission.limits.min_battery);
+        assert_eq!(
+            MissionStep {
+                action: MissionAction::Move {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0
+                },
+                timeout: Some(Duration::from_secs(20)),
+            },
+            mission.steps[2]
+        );
+        assert_eq!(
+            MissionAction::SmartVideo {
+                mode: SmartVideoCmd::SvCircle,
+                secs: 10.0
+            },
+            mission.steps[5].action
+        );
+        assert_eq!(Ok(()), mission.check());
+
+        let json = r#"{"steps": ["takeoff", {"flip": {"direction": "left"}}, "land"]}"#;
+        let mission = Mission::from_json(json).unwrap();
+        assert_eq!(
+            MissionAction::Flip {
+                direction: FlipDirection::Left
+            },
+            mission.steps[1].action
+        );
+        assert!(Mission::from_json(r#"{"steps": ["hover"]}"#).is_err());
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let step = MissionStep::new;
+        let mut mission = Mission {
+            name: String::new(),
+            limits: MissionLimits {
+                max_distance: Some(2.0),
+                ..Default::default()
+            },
+            steps: vec![
+                step(MissionAction::Takeoff),
+                step(MissionAction::Move {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                }),
+                step(MissionAction::Rotate { deg: 90.0 }),
+                // to the right of the takeoff point now
+                step(MissionAction::Move {
+                    x: 1.5,
+                    y: 0.0,
+                    z: 0.0,
+                }),
+                step(MissionAction::Land),
+            ],
+        };
+        assert_eq!(Ok(()), mission.check());
+        mission.steps[2] = step(MissionAction::Wait { secs: 1.0 });
+        assert!(matches!(
+            mission.check(),
+            Err(MissionError::OutsideLimits { step: 3, .. })
+        ));
+
+        // within the mission limits, but not the beginner envelope or the fence
+        mission.limits.max_distance = None;
+        mission.steps[1] = step(MissionAction::Climb { height: 3.0 });
+        assert_eq!(Ok(()), mission.check());
+        assert!(matches!(
+            mission.check_within(None, &FlightEnvelope::beginner()),
+            Err(MissionError::OutsideLimits { step: 1, .. })
+        ));
+        let fence = Geofence::cylinder(1.0, 5.0);
+        assert!(matches!(
+            mission.check_within(Some(&fence), &FlightEnvelope::expert()),
+            Err(MissionError::OutsideLimits { step: 3, .. })
+        ));
+        let fence = Geofence::cylinder(2.0, 5.0);
+        assert_eq!(
+            Ok(()),
+            mission.check_within(Some(&fence), &FlightEnvelope::expert())
+        );
+
+        mission.steps.pop();
+        assert!(matches!(
+            mission.check(),
+            Err(MissionError::Invalid { step: None, .. })
+        ));
+
+        let mission = Mission {
+            name: String::new(),
+            limits: MissionLimits {
+                max_duration: Some(10.0),
+                ..Default::default()
+            },
+            steps: vec![
+                step(MissionAction::Takeoff),
+                step(MissionAction::Wait { secs: 30.0 }),
+                step(MissionAction::Land),
+            ],
+        };
+        assert!(matches!(mission.check(), Err(MissionError::TooLong { .. })));
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::decode::{DecodedFrame, DecoderConfig};
+
+pub const DEFAULT_MJPEG_ADDR: &str = "0.0.0.0:8080";
+const BOUNDARY: &str = "tellofame";
+// a client that can't take a picture within this long is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MjpegConfig {
+    // pictures per second sent to the browsers, None for every decoded one
+    pub max_fps: Option<f32>,
+    // the resolution, see DecoderConfig::downscale
+    pub downscale: u32,
+    pub quality: u8,
+}
+
+impl Default for MjpegConfig {
+    fn default() -> Self {
+        Self {
+            max_fps: Some(10.0),
+            downscale: 2,
+            quality: 75,
+        }
+    }
+}
+
+impl MjpegConfig {
+    pub fn decoder_config(&self) -> DecoderConfig {
+        DecoderConfig {
+            downscale: self.downscale,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Latest {
+    jpeg: Option<Arc<Vec<u8>>>,
+    // bumped on every new picture
+    generation: u64,
+    // pts of the last encoded picture
+    sent: Option<Duration>,
+    clients: usize,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    latest: Mutex<Latest>,
+    changed: Condvar,
+}
+
+// Serves the decoded video as multipart/x-mixed-replace JPEGs on any path.
+// Pictures are encoded only while a browser is connected.
+#[derive(Debug, Clone)]
+pub struct MjpegServer {
+    shared: Arc<Shared>,
+    config: MjpegConfig,
+    local_addr: SocketAddr,
+}
+
+impl MjpegServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: MjpegConfig) -> io::Result<Self> {
+        let method_name = "mjpeg_server";
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+        tracing::info!(method_name, "serving http://{}/", local_addr);
+        let shared_local = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let shared = shared_local.clone();
+                        thread::spawn(move || serve(&shared, stream));
+                    }
+                    Err(e) => tracing::warn!(method_name, "accept failed: {}", e),
+                }
+            }
+        });
+        Ok(Self {
+            shared,
+            config,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn clients(&self) -> usize {
+        self.shared.latest.lock().unwrap().clients
+    }
+
+    // Encodes the picture for the connected browsers, at most MjpegConfig::max_fps.
+    pub fn push(&self, frame: &DecodedFrame) {
+        let method_name = "mjpeg_push";
+        {
+            let latest = self.shared.latest.lock().unwrap();
+            if latest.clients == 0 {
+                return;
+            }
+            let interval = self
+                .config
+                .max_fps
+                .filter(|fps| *fps > 0.0)
+                .map(|fps| Duration::from_secs_f32(1.0 / fps));
+            let due = match (interval, latest.sent) {
+                (Some(interval), Some(sent)) => frame.pts.saturating_sub(sent) >= interval,
+                _ => true,
+            };
+            if !due {
+                return;
+            }
+        }
+        let jpeg = match frame.to_jpeg(self.config.quality) {
+            Ok(jpeg) => jpeg,
+            Err(e) => {
+                tracing::warn!(method_name, "{}", e);
+                return;
+            }
+        };
+        let mut latest = self.shared.latest.lock().unwrap();
+        latest.jpeg = Some(Arc::new(jpeg));
+        latest.generation += 1;
+        latest.sent = Some(frame.pts);
+        self.shared.changed.notify_all();
+    }
+}
+
+fn serve(shared: &Shared, mut stream: TcpStream) {
+    let method_name = "mjpeg_serve";
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(_) => return,
+    };
+    // the request itself doesn't matter
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
+        Err(_) => return,
+    };
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line.trim() != "" {
+        line.clear();
+    }
+    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+    let header = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    );
+    if stream.write_all(header.as_bytes()).is_err() {
+        return;
+    }
+    tracing::info!(method_name, %peer, "browser connected");
+    let mut generation = {
+        let mut latest = shared.latest.lock().unwrap();
+        latest.clients += 1;
+        latest.generation
+    };
+    let r = loop {
+        let jpeg = {
+            let mut latest = shared.latest.lock().unwrap();
+            while latest.generation == generation {
+                latest = shared.changed.wait(latest).unwrap();
+            }
+            generation = latest.generation;
+            latest.jpeg.clone()
+        };
+        let jpeg = match jpeg {
+            Some(jpeg) => jpeg,
+            None => continue,
+        };
+        let part = format!(
+            "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg.len()
+        );
+        let r = stream
+            .write_all(part.as_bytes())
+            .and_then(|_| stream.write_all(&jpeg))
+            .and_then(|_| stream.write_all(b"\r\n"));
+        if r.is_err() {
+            break r;
+        }
+    };
+    shared.latest.lock().unwrap().clients -= 1;
+    tracing::info!(method_name, %peer, "browser disconnected: {:?}", r.err());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn gray(ms: u64) -> DecodedFrame {
+        DecodedFrame {
+            width: 16,
+            height: 8,
+            rgb: vec![128; 16 * 8 * 3],
+            pts: Duration::from_millis(ms),
+        }
+    }
+
+    #[test]
+    fn test_stream_to_browser() {
+        let config = MjpegConfig {
+            max_fps: Some(10.0),
+            ..Default::default()
+        };
+        let server = MjpegServer::bind("127.0.0.1:0", config).unwrap();
+        // nobody is watching
+        server.push(&gray(0));
+        assert!(server.shared.latest.lock().unwrap().jpeg.is_none());
+
+        let mut conn = TcpStream::connect(server.local_addr()).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        conn.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(conn);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.0 200 OK"));
+        while server.clients() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        server.push(&gray(100));
+        // too soon for 10 fps
+        server.push(&gray(150));
+        assert_eq!(1, server.shared.latest.lock().unwrap().generation);
+
+        let mut length = 0;
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if let Some(n) = line.strip_prefix("Content-Length: ") {
+                length = n.trim().parse().unwrap();
+            }
+            if line == "\r\n" && length > 0 {
+                break;
+            }
+        }
+        let mut jpeg = vec![0; length];
+        reader.read_exact(&mut jpeg).unwrap();
+        assert_eq!(&[0xff, 0xd8], &jpeg[..2]);
+    }
+}
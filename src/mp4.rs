@@ -0,0 +1,432 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    video::{self, VideoFrame, NAL_PPS, NAL_SPS},
+    video_sink::VideoSink,
+};
+
+pub const TIMESCALE: u32 = 90_000;
+// a fragment is written at every keyframe, or when it gets this long
+pub const MAX_FRAGMENT_DURATION: Duration = Duration::from_secs(1);
+// of the last frame, and of frames with a non increasing pts
+const DEFAULT_FRAME_DURATION: u32 = TIMESCALE / 30;
+
+const TRACK_ID: u32 = 1;
+// access unit delimiter, dropped with the parameter sets
+const NAL_AUD: u8 = 9;
+
+const SAMPLE_SYNC: u32 = 0x0200_0000;
+const SAMPLE_NON_SYNC: u32 = 0x0101_0000;
+
+#[derive(Debug)]
+struct Sample {
+    // AVCC, 4 byte length prefixed NAL units
+    data: Vec<u8>,
+    pts: Duration,
+    is_keyframe: bool,
+}
+
+// Writes the video as fragmented MP4: the header once the first keyframe
+// brings the SPS/PPS, then a fragment per GOP. A crash loses at most the
+// last fragment. Frames before the first keyframe are skipped.
+pub struct Mp4Recorder {
+    out: Box<dyn Write + Send>,
+    header_written: bool,
+    // pts of the first frame, the decode time 0
+    start: Option<Duration>,
+    pending: Vec<Sample>,
+    // decode time of the first pending sample (TIMESCALE units)
+    decode_time: u64,
+    sequence: u32,
+    frames: usize,
+}
+
+impl Mp4Recorder {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Box::new(out),
+            header_written: false,
+            start: None,
+            pending: vec![],
+            decode_time: 0,
+            sequence: 0,
+            frames: 0,
+        }
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    // Frames written so far, including the pending fragment.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn duration(&self) -> Duration {
+        match (self.start, self.pending.last()) {
+            (Some(start), Some(last)) => last.pts.saturating_sub(start),
+            _ => Duration::from_secs(self.decode_time) / TIMESCALE,
+        }
+    }
+
+    fn write_header(&mut self, frame: &VideoFrame) -> io::Result<bool> {
+        let method_name = "mp4_header";
+        let nals = frame.nal_units();
+        let sps = nals.iter().find(|n| video::nal_type(n) == NAL_SPS);
+        let pps = nals.iter().find(|n| video::nal_type(n) == NAL_PPS);
+        let (sps, pps) = match (sps, pps) {
+            (Some(sps), Some(pps)) => (sps, pps),
+            _ => return Ok(false),
+        };
+        let info = match video::parse_sps(sps) {
+            Some(info) => info,
+            None => {
+                tracing::warn!(method_name, "unparsable sps");
+                return Ok(false);
+            }
+        };
+        tracing::info!(
+            method_name,
+            info.width,
+            info.height,
+            "mp4 recording started"
+        );
+        self.out.write_all(&ftyp())?;
+        self.out
+            .write_all(&moov(sps, pps, info.width, info.height))?;
+        self.header_written = true;
+        Ok(true)
+    }
+
+    fn ticks(&self, pts: Duration) -> u64 {
+        let since = pts.saturating_sub(self.start.unwrap_or_default());
+        (since.as_nanos() * TIMESCALE as u128 / 1_000_000_000) as u64
+    }
+
+    // Writes the pending samples as one moof + mdat, `next` is the pts of the
+    // frame after them, which gives the last sample its duration.
+    fn write_fragment(&mut self, next: Option<Duration>) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut entries = Vec::with_capacity(self.pending.len());
+        let mut time = self.decode_time;
+        for (i, s) in self.pending.iter().enumerate() {
+            let next = self.pending.get(i + 1).map(|s| s.pts).or(next);
+            let duration = next.map_or(0, |next| self.ticks(next).saturating_sub(time));
+            let duration = match duration {
+                0 => DEFAULT_FRAME_DURATION as u64,
+                d => d,
+            };
+            let flags = if s.is_keyframe {
+                SAMPLE_SYNC
+            } else {
+                SAMPLE_NON_SYNC
+            };
+            entries.push((duration as u32, s.data.len() as u32, flags));
+            time += duration;
+        }
+        self.sequence += 1;
+        let moof_len = moof(self.sequence, self.decode_time, &entries, 0).len();
+        let moof = moof(
+            self.sequence,
+            self.decode_time,
+            &entries,
+            moof_len as i32 + 8,
+        );
+        let size: usize = self.pending.iter().map(|s| s.data.len()).sum();
+        self.out.write_all(&moof)?;
+        self.out.write_all(&(size as u32 + 8).to_be_bytes())?;
+        self.out.write_all(b"mdat")?;
+        for s in self.pending.drain(..) {
+            self.out.write_all(&s.data)?;
+        }
+        self.out.flush()?;
+        self.decode_time = time;
+        Ok(())
+    }
+}
+
+impl VideoSink for Mp4Recorder {
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        if !self.header_written {
+            if !frame.is_keyframe || !self.write_header(frame)? {
+                return Ok(());
+            }
+            self.start = Some(frame.pts);
+        }
+        let fragment_full = self
+            .pending
+            .first()
+            .is_some_and(|first| frame.pts.saturating_sub(first.pts) >= MAX_FRAGMENT_DURATION);
+        if frame.is_keyframe || fragment_full {
+            self.write_fragment(Some(frame.pts))?;
+        }
+        let mut data = Vec::with_capacity(frame.data.len());
+        for nal in frame.nal_units() {
+            if matches!(video::nal_type(nal), NAL_SPS | NAL_PPS | NAL_AUD) {
+                continue;
+            }
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        self.pending.push(Sample {
+            data,
+            pts: frame.pts,
+            is_keyframe: frame.is_keyframe,
+        });
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_fragment(None)?;
+        self.out.flush()
+    }
+}
+
+impl fmt::Debug for Mp4Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mp4Recorder")
+            .field("header_written", &self.header_written)
+            .field("frames", &self.frames)
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
+
+fn mp4_box(kind: &[u8; 4], payload: &[&[u8]]) -> Vec<u8> {
+    let len: usize = payload.iter().map(|p| p.len()).sum();
+    let mut b = Vec::with_capacity(len + 8);
+    b.extend_from_slice(&(len as u32 + 8).to_be_bytes());
+    b.extend_from_slice(kind);
+    for p in payload {
+        b.extend_from_slice(p);
+    }
+    b
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[&[u8]]) -> Vec<u8> {
+    let header = (version as u32) << 24 | flags & 0x00ff_ffff;
+    let mut parts: Vec<&[u8]> = vec![];
+    let header = header.to_be_bytes();
+    parts.push(&header);
+    parts.extend_from_slice(payload);
+    mp4_box(kind, &parts)
+}
+
+fn be32(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn ftyp() -> Vec<u8> {
+    mp4_box(b"ftyp", &[b"isom", &be32(&[0x200]), b"isomiso6avc1mp41"])
+}
+
+fn moov(sps: &[u8], pps: &[u8], width: u32, height: u32) -> Vec<u8> {
+    // fragmented, the durations are 0
+    let mvhd = full_box(
+        b"mvhd",
+        0,
+        0,
+        &[
+            &be32(&[0, 0, 1000, 0, 0x0001_0000]),
+            &[0x01, 0x00, 0, 0],
+            &be32(&[0, 0]),
+            &be32(&MATRIX),
+            &be32(&[0; 6]),
+            &be32(&[TRACK_ID + 1]),
+        ],
+    );
+    let tkhd = full_box(
+        b"tkhd",
+        0,
+        0x3,
+        &[
+            &be32(&[0, 0, TRACK_ID, 0, 0, 0, 0, 0, 0]),
+            &be32(&MATRIX),
+            &be32(&[width << 16, height << 16]),
+        ],
+    );
+    let mdhd = full_box(
+        b"mdhd",
+        0,
+        0,
+        &[&be32(&[0, 0, TIMESCALE, 0]), &[0x55, 0xc4, 0, 0]],
+    );
+    let hdlr = full_box(
+        b"hdlr",
+        0,
+        0,
+        &[&be32(&[0]), b"vide", &be32(&[0, 0, 0]), b"VideoHandler\0"],
+    );
+    let vmhd = full_box(b"vmhd", 0, 1, &[&[0; 8]]);
+    let dref = full_box(b"dref", 0, 0, &[&be32(&[1]), &full_box(b"url ", 0, 1, &[])]);
+    let dinf = mp4_box(b"dinf", &[&dref]);
+    let stsd = full_box(
+        b"stsd",
+        0,
+        0,
+        &[&be32(&[1]), &avc1(sps, pps, width, height)],
+    );
+    let empty = be32(&[0]);
+    let stbl = mp4_box(
+        b"stbl",
+        &[
+            &stsd,
+            &full_box(b"stts", 0, 0, &[&empty]),
+            &full_box(b"stsc", 0, 0, &[&empty]),
+            &full_box(b"stsz", 0, 0, &[&be32(&[0, 0])]),
+            &full_box(b"stco", 0, 0, &[&empty]),
+        ],
+    );
+    let minf = mp4_box(b"minf", &[&vmhd, &dinf, &stbl]);
+    let mdia = mp4_box(b"mdia", &[&mdhd, &hdlr, &minf]);
+    let trak = mp4_box(b"trak", &[&tkhd, &mdia]);
+    let trex = full_box(b"trex", 0, 0, &[&be32(&[TRACK_ID, 1, 0, 0, 0])]);
+    let mvex = mp4_box(b"mvex", &[&trex]);
+    mp4_box(b"moov", &[&mvhd, &trak, &mvex])
+}
+
+fn avc1(sps: &[u8], pps: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let mut avcc = vec![1, sps[1], sps[2], sps[3], 0xff, 0xe1];
+    avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(sps);
+    avcc.push(1);
+    avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(pps);
+    let mut entry = vec![0; 6];
+    // data reference index
+    entry.extend_from_slice(&[0, 1]);
+    entry.extend_from_slice(&[0; 16]);
+    entry.extend_from_slice(&(width as u16).to_be_bytes());
+    entry.extend_from_slice(&(height as u16).to_be_bytes());
+    // 72 dpi, reserved, 1 frame per sample
+    entry.extend_from_slice(&be32(&[0x0048_0000, 0x0048_0000, 0]));
+    entry.extend_from_slice(&[0, 1]);
+    entry.extend_from_slice(&[0; 32]);
+    // depth, pre defined -1
+    entry.extend_from_slice(&[0x00, 0x18, 0xff, 0xff]);
+    mp4_box(b"avc1", &[&entry, &mp4_box(b"avcC", &[&avcc])])
+}
+
+// `entries` are (duration, size, flags) per sample.
+fn moof(sequence: u32, decode_time: u64, entries: &[(u32, u32, u32)], data_offset: i32) -> Vec<u8> {
+    let mfhd = full_box(b"mfhd", 0, 0, &[&be32(&[sequence])]);
+    // default base is moof
+    let tfhd = full_box(b"tfhd", 0, 0x02_0000, &[&be32(&[TRACK_ID])]);
+    let tfdt = full_box(b"tfdt", 1, 0, &[&decode_time.to_be_bytes()]);
+    let samples: Vec<u32> = entries
+        .iter()
+        .flat_map(|&(duration, size, flags)| [duration, size, flags])
+        .collect();
+    // data offset, sample duration, size and flags present
+    let trun = full_box(
+        b"trun",
+        0,
+        0x00_0701,
+        &[
+            &be32(&[entries.len() as u32]),
+            &data_offset.to_be_bytes(),
+            &be32(&samples),
+        ],
+    );
+    let traf = mp4_box(b"traf", &[&tfhd, &tfdt, &trun]);
+    mp4_box(b"moof", &[&mfhd, &traf])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // (kind, offset, size) of the boxes in `data`
+    fn boxes(data: &[u8]) -> Vec<(String, usize, usize)> {
+        let mut out = vec![];
+        let mut at = 0;
+        while at + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+            out.push((
+                String::from_utf8_lossy(&data[at + 4..at + 8]).into(),
+                at,
+                size,
+            ));
+            at += size;
+        }
+        out
+    }
+
+    fn frame(is_keyframe: bool, ms: u64) -> VideoFrame {
+        // 960x720 main profile
+        let mut data = vec![];
+        if is_keyframe {
+            data.extend([
+                0, 0, 0, 1, 0x67, 0x4d, 0x40, 0x28, 0xda, 0x03, 0xc0, 0x5b, 0x90,
+            ]);
+            data.extend([0, 0, 0, 1, 0x68, 0xee, 0x3c, 0x80]);
+        }
+        data.extend([0, 0, 0, 1, if is_keyframe { 0x65 } else { 0x41 }, 1, 2, 3]);
+        VideoFrame {
+            data,
+            is_keyframe,
+            seq: ms / 33,
+            pts: Duration::from_millis(ms),
+        }
+    }
+
+    #[test]
+    fn test_fragments_per_gop() {
+        let out = Shared::default();
+        let mut rec = Mp4Recorder::new(out.clone());
+        // skipped until the first keyframe
+        rec.write_frame(&frame(false, 0)).unwrap();
+        for (key, ms) in [(true, 100), (false, 140), (false, 170), (true, 200)] {
+            rec.write_frame(&frame(key, ms)).unwrap();
+        }
+        // only the first GOP is out before finish()
+        let kinds: Vec<String> = boxes(&out.0.lock().unwrap())
+            .into_iter()
+            .map(|b| b.0)
+            .collect();
+        assert_eq!(vec!["ftyp", "moov", "moof", "mdat"], kinds);
+        rec.finish().unwrap();
+        assert_eq!(4, rec.frames());
+
+        let data = out.0.lock().unwrap().clone();
+        let all = boxes(&data);
+        assert_eq!(6, all.len());
+        assert_eq!(data.len(), all[5].1 + all[5].2);
+        // second fragment starts at 100ms
+        let (_, moof, _) = all[4];
+        let tfdt = data[moof..].windows(4).position(|w| w == b"tfdt").unwrap() + moof;
+        let time = u64::from_be_bytes(data[tfdt + 8..tfdt + 16].try_into().unwrap());
+        assert_eq!(9000, time);
+        // sample data is length prefixed, without the parameter sets
+        let (_, mdat, size) = all[3];
+        assert_eq!(8 + 3 * 8, size);
+        assert_eq!(&[0, 0, 0, 4, 0x65, 1, 2, 3], &data[mdat + 8..mdat + 16]);
+    }
+}
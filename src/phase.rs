@@ -0,0 +1,428 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    envelope::EnvelopeViolation,
+    messages::{FlightData, FlipDirection},
+    trajectory::TrajectoryError,
+};
+
+// fly_mode values reported in the flight status while the drone is
+// taking off and landing (as observed on the Tello firmware)
+const FLY_MODE_TAKEOFF: u8 = 11;
+const FLY_MODE_LANDING: u8 = 12;
+
+// how long a command stays "in progress" before the flight status confirms it
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the firmware refuses flips with a low battery, we refuse them early
+pub const DEFAULT_MIN_FLIP_BATTERY: i8 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlightPhase {
+    Grounded,
+    TakingOff,
+    Hovering,
+    Flying,
+    Landing,
+    ThrowArmed,
+    PalmLanding,
+    Emergency,
+}
+
+impl FlightPhase {
+    pub fn is_airborne(&self) -> bool {
+        matches!(
+            self,
+            FlightPhase::TakingOff
+                | FlightPhase::Hovering
+                | FlightPhase::Flying
+                | FlightPhase::Landing
+                | FlightPhase::PalmLanding
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Takeoff,
+    ThrowTakeoff,
+    Land,
+    PalmLand,
+    Flip(FlipDirection),
+    // autopilot maneuvers, see autopilot::Autopilot
+    MoveBy,
+    Rotate,
+    HoldAltitude,
+    TakeoffTo,
+    FollowTrajectory,
+    ReturnHome,
+    // replay of a stick recording, see recorder::StickPlayer
+    Replay,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    // the command is not allowed in the current flight phase
+    InvalidPhase {
+        command: Command,
+        phase: FlightPhase,
+    },
+    // the battery is below the level required by the command
+    LowBattery {
+        command: Command,
+        battery: i8,
+        required: i8,
+    },
+    // the command needs flight data we didn't receive yet
+    NoTelemetry {
+        command: Command,
+    },
+    // the flight status didn't confirm the command in time
+    Timeout {
+        command: Command,
+    },
+    // the position or heading feedback went away during a maneuver
+    FeedbackLost {
+        command: Command,
+    },
+    // replaced by another autopilot command or stopped by the pilot
+    Aborted {
+        command: Command,
+    },
+    // the target height is above the drone height limit (meters)
+    AboveHeightLimit {
+        command: Command,
+        limit: u8,
+    },
+    // refused by the flight envelope
+    OutsideEnvelope {
+        violation: EnvelopeViolation,
+    },
+    // the recorded poses don't make a trajectory (e.g. recorded without MVO)
+    InvalidTrajectory {
+        command: Command,
+        error: TrajectoryError,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::InvalidPhase { command, phase } => {
+                write!(f, "{:?} is not allowed while {:?}", command, phase)
+            }
+            CommandError::LowBattery {
+                command,
+                battery,
+                required,
+            } => write!(
+                f,
+                "{:?} needs at least {}% battery, have {}%",
+                command, required, battery
+            ),
+            CommandError::NoTelemetry { command } => {
+                write!(f, "{:?} needs flight data from the drone", command)
+            }
+            CommandError::Timeout { command } => {
+                write!(f, "{:?} not confirmed by the drone in time", command)
+            }
+            CommandError::FeedbackLost { command } => {
+                write!(f, "{:?} lost the position feedback", command)
+            }
+            CommandError::Aborted { command } => write!(f, "{:?} aborted", command),
+            CommandError::AboveHeightLimit { command, limit } => {
+                write!(f, "{:?} above the height limit of {}m", command, limit)
+            }
+            CommandError::OutsideEnvelope { violation } => {
+                write!(f, "{:?} not allowed by the flight envelope", violation)
+            }
+            CommandError::InvalidTrajectory { command, error } => {
+                write!(f, "{:?} has no valid trajectory: {}", command, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+// Tracks the flight phase from the flight status updates and the commands we sent.
+// A command moves the drone into a "pending" phase (e.g. TakingOff), which is kept
+// until the flight status confirms the outcome or the pending phase times out.
+#[derive(Debug)]
+pub(crate) struct PhaseTracker {
+    phase: FlightPhase,
+    pending: Option<(FlightPhase, Instant)>,
+    min_flip_battery: i8,
+}
+
+impl PhaseTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            phase: FlightPhase::Grounded,
+            pending: None,
+            min_flip_battery: DEFAULT_MIN_FLIP_BATTERY,
+        }
+    }
+
+    pub(crate) fn phase(&self) -> FlightPhase {
+        self.phase
+    }
+
+    pub(crate) fn set_min_flip_battery(&mut self, battery: i8) {
+        self.min_flip_battery = battery;
+    }
+
+    // Checks the command preconditions, returns the phase we enter by sending it.
+    pub(crate) fn check(
+        &self,
+        command: Command,
+        battery: Option<i8>,
+    ) -> Result<Option<FlightPhase>, CommandError> {
+        let phase = self.phase;
+        let invalid = Err(CommandError::InvalidPhase { command, phase });
+        match command {
+            Command::Takeoff | Command::ThrowTakeoff | Command::TakeoffTo => {
+                if phase != FlightPhase::Grounded {
+                    return invalid;
+                }
+                if command == Command::ThrowTakeoff {
+                    Ok(Some(FlightPhase::ThrowArmed))
+                } else {
+                    Ok(Some(FlightPhase::TakingOff))
+                }
+            }
+            Command::Land => match phase {
+                FlightPhase::TakingOff
+                | FlightPhase::Hovering
+                | FlightPhase::Flying
+                | FlightPhase::Emergency => Ok(Some(FlightPhase::Landing)),
+                _ => invalid,
+            },
+            Command::PalmLand => match phase {
+                FlightPhase::Hovering | FlightPhase::Flying => Ok(Some(FlightPhase::PalmLanding)),
+                _ => invalid,
+            },
+            Command::Flip(_) => {
+                if phase != FlightPhase::Hovering && phase != FlightPhase::Flying {
+                    return invalid;
+                }
+                let battery = battery.ok_or(CommandError::NoTelemetry { command })?;
+                if battery < self.min_flip_battery {
+                    return Err(CommandError::LowBattery {
+                        command,
+                        battery,
+                        required: self.min_flip_battery,
+                    });
+                }
+                Ok(None)
+            }
+            Command::MoveBy
+            | Command::Rotate
+            | Command::HoldAltitude
+            | Command::FollowTrajectory
+            | Command::ReturnHome
+            | Command::Replay => match phase {
+                FlightPhase::Hovering | FlightPhase::Flying => Ok(None),
+                _ => invalid,
+            },
+        }
+    }
+
+    // Records the command was sent, returns the transition if the phase changed.
+    pub(crate) fn on_command(
+        &mut self,
+        pending: Option<FlightPhase>,
+    ) -> Option<(FlightPhase, FlightPhase)> {
+        let pending = pending?;
+        self.pending = Some((pending, Instant::now()));
+        self.transition(pending)
+    }
+
+    pub(crate) fn on_flight_data(&mut self, fd: &FlightData) -> Option<(FlightPhase, FlightPhase)> {
+        if let Some((_, since)) = self.pending {
+            if since.elapsed() > PENDING_TIMEOUT {
+                tracing::warn!(?self.pending, "pending flight phase timed out");
+                self.pending = None;
+            }
+        }
+        let pending = self.pending.map(|(phase, _)| phase);
+        let phase = derive_phase(fd, pending);
+        if Some(phase) != pending && is_settled(pending, phase) {
+            self.pending = None;
+        }
+        self.transition(phase)
+    }
+
+    fn transition(&mut self, phase: FlightPhase) -> Option<(FlightPhase, FlightPhase)> {
+        if phase == self.phase {
+            return None;
+        }
+        let from = self.phase;
+        self.phase = phase;
+        Some((from, phase))
+    }
+}
+
+// The flight status confirmed (or contradicted) the pending phase.
+fn is_settled(pending: Option<FlightPhase>, phase: FlightPhase) -> bool {
+    match pending {
+        Some(FlightPhase::TakingOff) | Some(FlightPhase::ThrowArmed) => {
+            matches!(phase, FlightPhase::Hovering | FlightPhase::Flying)
+        }
+        Some(FlightPhase::Landing) | Some(FlightPhase::PalmLanding) => {
+            phase == FlightPhase::Grounded
+        }
+        _ => true,
+    }
+}
+
+fn derive_phase(fd: &FlightData, pending: Option<FlightPhase>) -> FlightPhase {
+    if fd.error_state {
+        return FlightPhase::Emergency;
+    }
+    if !fd.flying {
+        if fd.throw_fly_timer > 0 || pending == Some(FlightPhase::ThrowArmed) {
+            return FlightPhase::ThrowArmed;
+        }
+        return match pending {
+            Some(FlightPhase::TakingOff) => FlightPhase::TakingOff,
+            Some(p @ FlightPhase::Landing) | Some(p @ FlightPhase::PalmLanding)
+                if !fd.on_ground =>
+            {
+                p
+            }
+            _ => FlightPhase::Grounded,
+        };
+    }
+    match fd.fly_mode {
+        FLY_MODE_TAKEOFF => FlightPhase::TakingOff,
+        FLY_MODE_LANDING => {
+            if pending == Some(FlightPhase::PalmLanding) {
+                FlightPhase::PalmLanding
+            } else {
+                FlightPhase::Landing
+            }
+        }
+        _ => match pending {
+            // the land command was accepted, but the flight status doesn't show it yet
+            Some(p @ FlightPhase::Landing) | Some(p @ FlightPhase::PalmLanding) => p,
+            _ if fd.drone_hover => FlightPhase::Hovering,
+            _ => FlightPhase::Flying,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flight_data(
+        flying: bool,
+        on_ground: bool,
+        hover: bool,
+        fly_mode: u8,
+        battery: u8,
+    ) -> FlightData {
+        let mut pl = vec![0u8; 24];
+        pl[12] = battery;
+        pl[17] = (flying as u8) | (on_ground as u8) << 1 | (hover as u8) << 3;
+        pl[18] = fly_mode;
+        FlightData::new(&pl)
+    }
+
+    #[test]
+    fn test_takeoff_and_land_transitions() {
+        let mut tracker = PhaseTracker::new();
+        let grounded = flight_data(false, true, false, 6, 90);
+        assert_eq!(None, tracker.on_flight_data(&grounded));
+
+        let next = tracker.check(Command::Takeoff, Some(90)).unwrap();
+        assert_eq!(
+            Some((FlightPhase::Grounded, FlightPhase::TakingOff)),
+            tracker.on_command(next)
+        );
+        // the status didn't catch up yet, we stay in TakingOff
+        assert_eq!(None, tracker.on_flight_data(&grounded));
+        assert_eq!(
+            None,
+            tracker.on_flight_data(&flight_data(true, false, false, FLY_MODE_TAKEOFF, 90))
+        );
+        assert_eq!(
+            Some((FlightPhase::TakingOff, FlightPhase::Hovering)),
+            tracker.on_flight_data(&flight_data(true, false, true, 6, 90))
+        );
+        assert_eq!(
+            Some((FlightPhase::Hovering, FlightPhase::Flying)),
+            tracker.on_flight_data(&flight_data(true, false, false, 6, 90))
+        );
+
+        let next = tracker.check(Command::Land, Some(90)).unwrap();
+        tracker.on_command(next);
+        assert_eq!(FlightPhase::Landing, tracker.phase());
+        assert_eq!(
+            None,
+            tracker.on_flight_data(&flight_data(true, false, false, FLY_MODE_LANDING, 90))
+        );
+        assert_eq!(
+            Some((FlightPhase::Landing, FlightPhase::Grounded)),
+            tracker.on_flight_data(&grounded)
+        );
+    }
+
+    #[test]
+    fn test_command_preconditions() {
+        let mut tracker = PhaseTracker::new();
+        let flip = Command::Flip(FlipDirection::Left);
+        assert_eq!(
+            Err(CommandError::InvalidPhase {
+                command: flip,
+                phase: FlightPhase::Grounded
+            }),
+            tracker.check(flip, Some(90))
+        );
+        assert!(tracker.check(Command::Land, Some(90)).is_err());
+
+        tracker.on_flight_data(&flight_data(true, false, true, 6, 30));
+        assert_eq!(FlightPhase::Hovering, tracker.phase());
+        assert_eq!(
+            Err(CommandError::InvalidPhase {
+                command: Command::Takeoff,
+                phase: FlightPhase::Hovering
+            }),
+            tracker.check(Command::Takeoff, Some(30))
+        );
+        assert_eq!(
+            Err(CommandError::LowBattery {
+                command: flip,
+                battery: 30,
+                required: DEFAULT_MIN_FLIP_BATTERY
+            }),
+            tracker.check(flip, Some(30))
+        );
+        assert_eq!(Ok(None), tracker.check(flip, Some(80)));
+        assert_eq!(
+            Ok(Some(FlightPhase::PalmLanding)),
+            tracker.check(Command::PalmLand, Some(80))
+        );
+    }
+
+    #[test]
+    fn test_throw_and_emergency() {
+        let mut tracker = PhaseTracker::new();
+        let mut fd = flight_data(false, false, false, 6, 90);
+        fd.throw_fly_timer = 5;
+        assert_eq!(
+            Some((FlightPhase::Grounded, FlightPhase::ThrowArmed)),
+            tracker.on_flight_data(&fd)
+        );
+        let mut fd = flight_data(true, false, false, 6, 90);
+        fd.error_state = true;
+        assert_eq!(
+            Some((FlightPhase::ThrowArmed, FlightPhase::Emergency)),
+            tracker.on_flight_data(&fd)
+        );
+    }
+}
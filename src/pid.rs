@@ -0,0 +1,79 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+impl PidGains {
+    pub fn new(kp: f32, ki: f32, kd: f32) -> Self {
+        Self { kp, ki, kd }
+    }
+}
+
+// PID loop with the output clamped to +-limit.
+#[derive(Debug, Clone)]
+pub(crate) struct Pid {
+    gains: PidGains,
+    limit: f32,
+    integral: f32,
+    prev_error: Option<f32>,
+}
+
+impl Pid {
+    pub(crate) fn new(gains: PidGains, limit: f32) -> Self {
+        Self {
+            gains,
+            limit,
+            integral: 0.0,
+            prev_error: None,
+        }
+    }
+
+    pub(crate) fn set_gains(&mut self, gains: PidGains, limit: f32) {
+        self.gains = gains;
+        self.limit = limit;
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.integral = 0.0;
+        self.prev_error = None;
+    }
+
+    pub(crate) fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let derivative = match self.prev_error {
+            Some(prev) if dt > 0.0 => (error - prev) / dt,
+            _ => 0.0,
+        };
+        self.prev_error = Some(error);
+        let pd = self.gains.kp * error + self.gains.kd * derivative;
+        // anti-windup: stop integrating while the output is saturated,
+        // unless the error pulls it back from the limit
+        let integral = self.integral + error * dt;
+        let out = pd + self.gains.ki * integral;
+        if out.abs() <= self.limit || out.signum() != error.signum() {
+            self.integral = integral;
+        }
+        (pd + self.gains.ki * self.integral).clamp(-self.limit, self.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anti_windup() {
+        let mut pid = Pid::new(PidGains::new(1.0, 1.0, 0.0), 1.0);
+        // saturated for a long time, the integral must not grow
+        for _ in 0..100 {
+            assert_eq!(1.0, pid.update(5.0, 0.1));
+        }
+        assert!(pid.integral < 1.0, "integral={}", pid.integral);
+        // once the error changes sign the output follows immediately
+        assert!(pid.update(-0.5, 0.1) < 0.5);
+
+        pid.reset();
+        assert_eq!(0.0, pid.update(0.0, 0.1));
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arbiter::{self, SourceKind},
+    command::CommandHandle,
+    frame::rotate_xy,
+    phase::{Command, CommandError},
+    tello::Stick,
+    trajectory::{Interpolation, Trajectory, TrajectoryError, Waypoint},
+    utils::Vec3,
+    TelloController,
+};
+
+// added to the recording duration
+pub const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// closed-loop replay keeps a waypoint this often, the MVO noise in between is dropped
+const WAYPOINT_INTERVAL: Duration = Duration::from_millis(200);
+
+// One stick update, stored as a JSON line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StickSample {
+    // since the start of the recording
+    pub time_ms: u64,
+    pub stick: Stick,
+    // pose relative to the recording start: meters (x forward, y right, z up of
+    // the start heading) and yaw in degrees, None without MVO or IMU data
+    pub position: Option<Vec3<f32>>,
+    pub yaw: Option<f64>,
+}
+
+// Captures the sticks sent by the stick loop before the geofence and flight envelope
+// limits, see TelloController::start_recording().
+// The samples are written by a writer thread, the stick loop only queues them.
+pub struct StickRecorder {
+    // None once the writer stopped on an error
+    tx: Option<Sender<StickSample>>,
+    writer: Option<JoinHandle<io::Result<usize>>>,
+    started: Option<Instant>,
+    // MVO position and heading (relative to the takeoff) of the first pose
+    origin: Option<(Vec3<f32>, f64)>,
+    start_yaw: Option<f64>,
+    samples: usize,
+}
+
+impl StickRecorder {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let writer = thread::spawn(move || write_samples(out, rx));
+        Self {
+            tx: Some(tx),
+            writer: Some(writer),
+            started: None,
+            origin: None,
+            start_yaw: None,
+            samples: 0,
+        }
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    // Number of samples recorded so far, some may still be queued for writing.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    // `mvo` is the MVO position, `heading` the drone heading relative to the takeoff heading.
+    pub(crate) fn sample(
+        &mut self,
+        st: &Stick,
+        mvo: Option<Vec3<f32>>,
+        heading: Option<f64>,
+        yaw: Option<f64>,
+        now: Instant,
+    ) -> StickSample {
+        let started = *self.started.get_or_insert(now);
+        if let (None, Some(mvo), Some(heading)) = (self.origin, mvo, heading) {
+            self.origin = Some((mvo, heading));
+        }
+        if self.start_yaw.is_none() {
+            self.start_yaw = yaw;
+        }
+        let position = match (self.origin, mvo) {
+            (Some((origin, heading)), Some(mvo)) => {
+                let (forward, right) = rotate_xy(mvo.x - origin.x, mvo.y - origin.y, heading);
+                Some(Vec3::new(forward, right, origin.z - mvo.z))
+            }
+            _ => None,
+        };
+        let yaw = match (self.start_yaw, yaw) {
+            (Some(start), Some(yaw)) => Some((yaw - start + 180.0).rem_euclid(360.0) - 180.0),
+            _ => None,
+        };
+        StickSample {
+            time_ms: now.saturating_duration_since(started).as_millis() as u64,
+            stick: st.clone(),
+            position,
+            yaw,
+        }
+    }
+
+    // Called by the stick loop, the first write error stops the recording.
+    pub(crate) fn record(
+        &mut self,
+        st: &Stick,
+        mvo: Option<Vec3<f32>>,
+        heading: Option<f64>,
+        yaw: Option<f64>,
+        now: Instant,
+    ) {
+        if self.tx.is_none() {
+            return;
+        }
+        let sample = self.sample(st, mvo, heading, yaw, now);
+        match self.tx.as_ref().map(|tx| tx.send(sample)) {
+            Some(Ok(())) => self.samples += 1,
+            // the writer failed, finish() returns its error
+            _ => self.tx = None,
+        }
+    }
+
+    // Flushes the recording, returns the number of samples written.
+    pub fn finish(mut self) -> io::Result<usize> {
+        self.tx = None;
+        match self.writer.take().map(|writer| writer.join()) {
+            Some(Ok(r)) => r,
+            Some(Err(_)) => Err(io::Error::other("recording writer panicked")),
+            None => Ok(0),
+        }
+    }
+}
+
+impl fmt::Debug for StickRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StickRecorder")
+            .field("started", &self.started)
+            .field("samples", &self.samples)
+            .field("stopped", &self.tx.is_none())
+            .finish()
+    }
+}
+
+// Writes the samples as JSON lines until the recorder is finished or dropped.
+fn write_samples(mut out: impl Write, rx: Receiver<StickSample>) -> io::Result<usize> {
+    let mut written = 0;
+    for sample in rx {
+        let r = serde_json::to_writer(&mut out, &sample)
+            .map_err(io::Error::from)
+            .and_then(|_| out.write_all(b"\n"));
+        if let Err(e) = r {
+            tracing::warn!(method_name = "record", "recording stopped: {}", e);
+            return Err(e);
+        }
+        written += 1;
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackMode {
+    // sends the recorded sticks as they are
+    OpenLoop,
+    // follows the recorded poses with the autopilot
+    ClosedLoop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StickPlayer {
+    samples: Vec<StickSample>,
+}
+
+impl StickPlayer {
+    pub fn new(samples: Vec<StickSample>) -> Self {
+        Self { samples }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader(r: impl BufRead) -> io::Result<Self> {
+        let mut samples = vec![];
+        for line in r.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            samples.push(serde_json::from_str(&line).map_err(io::Error::from)?);
+        }
+        Ok(Self { samples })
+    }
+
+    pub fn samples(&self) -> &[StickSample] {
+        &self.samples
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.samples
+            .last()
+            .map_or(Duration::ZERO, |s| Duration::from_millis(s.time_ms))
+    }
+
+    // The recorded poses as a trajectory relative to the drone pose when the replay starts.
+    pub fn trajectory(&self) -> Result<Trajectory, TrajectoryError> {
+        let mut waypoints: Vec<Waypoint> = vec![];
+        let mut poses = self
+            .samples
+            .iter()
+            .filter_map(|s| {
+                s.position
+                    .map(|p| (Duration::from_millis(s.time_ms), p, s.yaw))
+            })
+            .peekable();
+        while let Some((time, position, yaw)) = poses.next() {
+            let last = poses.peek().is_none();
+            let due = waypoints
+                .last()
+                .is_none_or(|wp| time >= wp.time + WAYPOINT_INTERVAL || (last && time > wp.time));
+            if due {
+                waypoints.push(Waypoint::new(time, position, yaw));
+            }
+        }
+        if let Some(first) = waypoints.first().map(|wp| wp.time) {
+            // the recording may start without a pose
+            for wp in waypoints.iter_mut() {
+                wp.time -= first;
+            }
+        }
+        Trajectory::new(waypoints, Interpolation::CatmullRom)
+    }
+
+    // Replays the recording from the current drone pose. The open-loop replay
+    // runs on the arbiter::PLAYER_SOURCE control source, moving the sticks takes over.
+    // The closed-loop replay of a recording without poses fails with InvalidTrajectory.
+    pub fn play(
+        &self,
+        tello: &TelloController,
+        mode: PlaybackMode,
+    ) -> Result<CommandHandle, CommandError> {
+        let command = Command::Replay;
+        tello.inner.check_command(command)?;
+        match mode {
+            PlaybackMode::ClosedLoop => {
+                let trajectory = self
+                    .trajectory()
+                    .map_err(|error| CommandError::InvalidTrajectory { command, error })?;
+                tello.follow_trajectory(trajectory)
+            }
+            PlaybackMode::OpenLoop => Ok(self.play_open_loop(tello)),
+        }
+    }
+
+    fn play_open_loop(&self, tello: &TelloController) -> CommandHandle {
+        let method_name = "replay";
+        let command = Command::Replay;
+        let (handle, completer) = CommandHandle::new(command, self.duration() + REPLAY_TIMEOUT);
+        let source = tello.arbiter().register_internal(
+            arbiter::PLAYER_SOURCE,
+            arbiter::PRIORITY_PLAYER,
+            SourceKind::Automatic,
+        );
+        let samples = self.samples.clone();
+        tello.stop_autopilot();
+        source.engage();
+        thread::spawn(move || {
+            let started = Instant::now();
+            for sample in samples.iter() {
+                let at = started + Duration::from_millis(sample.time_ms);
+                thread::sleep(at.saturating_duration_since(Instant::now()));
+                if !source.is_engaged() || completer.is_resolved() {
+                    tracing::info!(method_name, "replay stopped");
+                    source.release();
+                    completer.fail(CommandError::Aborted { command });
+                    return;
+                }
+                source.set_sticks(&sample.stick);
+            }
+            source.release();
+            tracing::info!(method_name, samples = samples.len(), "replay finished");
+            completer.complete();
+        });
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{EnvelopeState, FlightEnvelope};
+
+    #[test]
+    fn test_poses_relative_to_start() {
+        let mut rec = StickRecorder::new(io::sink());
+        let at = Instant::now();
+        let st = Stick::new((0.0, 0.5), (0.0, 0.0));
+        // drone turned right at the start, MVO z points down
+        let s = rec.sample(
+            &st,
+            Some(Vec3::new(1.0, 1.0, -1.0)),
+            Some(90.0),
+            Some(100.0),
+            at,
+        );
+        assert_eq!(Some(Vec3::new(0.0, 0.0, 0.0)), s.position);
+        assert_eq!(Some(0.0), s.yaw);
+        let s = rec.sample(
+            &st,
+            Some(Vec3::new(1.0, 3.0, -1.5)),
+            Some(90.0),
+            Some(-170.0),
+            at + Duration::from_millis(250),
+        );
+        assert_eq!(250, s.time_ms);
+        let p = s.position.unwrap();
+        // 2m along the takeoff y is straight ahead of the start heading
+        assert!((p.x - 2.0).abs() < 1e-5 && p.y.abs() < 1e-5, "{:?}", p);
+        assert_eq!(0.5, p.z);
+        assert_eq!(Some(90.0), s.yaw);
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_writer_thread() {
+        let st = Stick::new((0.0, 0.0), (0.0, 0.0));
+        let at = Instant::now();
+        let mut rec = StickRecorder::new(io::sink());
+        for _ in 0..3 {
+            rec.record(&st, None, None, None, at);
+        }
+        assert_eq!(3, rec.finish().unwrap());
+        // the write error comes back from finish()
+        let mut rec = StickRecorder::new(Broken);
+        rec.record(&st, None, None, None, at);
+        rec.record(&st, None, None, None, at);
+        assert!(rec.finish().is_err());
+    }
+
+    #[test]
+    fn test_load_and_trajectory() {
+        let lines: String = (0..=10)
+            .map(|i| {
+                let sample = StickSample {
+                    time_ms: 100 * i,
+                    stick: Stick::new((0.0, 0.3), (0.0, 0.0)),
+                    // no pose in the first sample
+                    position: (i > 0).then(|| Vec3::new(0.1 * i as f32, 0.0, 0.0)),
+                    yaw: None,
+                };
+                serde_json::to_string(&sample).unwrap() + "\n"
+            })
+            .collect();
+        let player = StickPlayer::from_reader(lines.as_bytes()).unwrap();
+        assert_eq!(11, player.samples().len());
+        assert_eq!(Duration::from_secs(1), player.duration());
+        let tr = player.trajectory().unwrap();
+        let times: Vec<u64> = tr
+            .waypoints()
+            .iter()
+            .map(|wp| wp.time.as_millis() as u64)
+            .collect();
+        assert_eq!(vec![0, 200, 400, 600, 800, 900], times);
+        assert!((tr.waypoints()[0].position.x - 0.1).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_trajectory_without_poses() {
+        let sample = StickSample {
+            time_ms: 0,
+            stick: Stick::new((0.0, 0.0), (0.0, 0.0)),
+            position: None,
+            yaw: None,
+        };
+        let player = StickPlayer::new(vec![sample.clone(), sample]);
+        assert_eq!(Err(TrajectoryError::TooFewWaypoints), player.trajectory());
+    }
+
+    #[test]
+    fn test_open_loop_replay_under_envelope() {
+        let mut envelope = EnvelopeState::default();
+        envelope.set_envelope(FlightEnvelope::beginner());
+        let pilot = Stick::new((0.5, 1.0), (0.0, 0.4));
+        // the stick loop records the sticks before limiting them
+        let mut rec = StickRecorder::new(io::sink());
+        let sample = rec.sample(&pilot, None, None, None, Instant::now());
+        let (sent, _) = envelope.apply(&pilot, None, Some(1.0), None);
+        let line = serde_json::to_string(&sample).unwrap();
+        let player = StickPlayer::from_reader(line.as_bytes()).unwrap();
+        // and the replay goes through the envelope once
+        let (replayed, _) = envelope.apply(&player.samples()[0].stick, None, Some(1.0), None);
+        assert_eq!(sent, replayed);
+        assert_eq!(0.4, replayed.ry);
+    }
+}
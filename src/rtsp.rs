@@ -0,0 +1,594 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    video::{self, VideoFrame, NAL_PPS, NAL_SPS},
+    video_sink::VideoSink,
+};
+
+pub const DEFAULT_RTSP_ADDR: &str = "0.0.0.0:8554";
+pub const RTSP_PATH: &str = "/tello";
+// RTP payload per packet, larger NAL units are split into FU-A fragments
+pub const RTP_MTU: usize = 1400;
+
+const PAYLOAD_TYPE: u8 = 96;
+const CLOCK_RATE: u64 = 90_000;
+const NAL_FU_A: u8 = 28;
+const SESSION_TIMEOUT_SECS: u32 = 60;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// RFC 6184 payloads of one access unit: single NAL unit packets, FU-A for
+// the large ones. The bool is the marker, set on the last packet.
+pub fn packetize(frame: &VideoFrame, mtu: usize) -> Vec<(Vec<u8>, bool)> {
+    let mut packets = vec![];
+    for nal in frame.nal_units() {
+        if nal.len() <= mtu {
+            packets.push((nal.to_vec(), false));
+            continue;
+        }
+        let indicator = nal[0] & 0xe0 | NAL_FU_A;
+        let chunks: Vec<&[u8]> = nal[1..].chunks(mtu - 2).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut header = nal[0] & 0x1f;
+            if i == 0 {
+                header |= 0x80;
+            }
+            if i == chunks.len() - 1 {
+                header |= 0x40;
+            }
+            let mut payload = Vec::with_capacity(chunk.len() + 2);
+            payload.extend_from_slice(&[indicator, header]);
+            payload.extend_from_slice(chunk);
+            packets.push((payload, false));
+        }
+    }
+    if let Some(last) = packets.last_mut() {
+        last.1 = true;
+    }
+    packets
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let v = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(v >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[derive(Debug)]
+enum Transport {
+    Udp {
+        addr: SocketAddr,
+    },
+    // interleaved on the RTSP connection
+    Tcp {
+        stream: Arc<Mutex<TcpStream>>,
+        channel: u8,
+    },
+}
+
+#[derive(Debug)]
+struct Session {
+    id: String,
+    transport: Transport,
+    playing: bool,
+    // viewers join at a keyframe
+    waiting_keyframe: bool,
+    seq: u16,
+    ssrc: u32,
+    // random start of the RTP timestamps
+    ts_offset: u32,
+}
+
+impl Session {
+    fn send(
+        &mut self,
+        rtp_socket: &UdpSocket,
+        payload: &[u8],
+        marker: bool,
+        ts: u32,
+    ) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(payload.len() + 16);
+        let transport_header = match self.transport {
+            Transport::Tcp { channel, .. } => {
+                let len = (payload.len() + 12) as u16;
+                vec![b'$', channel, (len >> 8) as u8, len as u8]
+            }
+            Transport::Udp { .. } => vec![],
+        };
+        packet.extend_from_slice(&transport_header);
+        packet.extend_from_slice(&[0x80, PAYLOAD_TYPE | if marker { 0x80 } else { 0 }]);
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&ts.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.seq = self.seq.wrapping_add(1);
+        match &self.transport {
+            Transport::Udp { addr } => rtp_socket.send_to(&packet, addr).map(|_| ()),
+            Transport::Tcp { stream, .. } => stream.lock().unwrap().write_all(&packet),
+        }
+    }
+}
+
+type JoinCallback = Box<dyn Fn() + Send + Sync>;
+
+struct Shared {
+    sessions: Mutex<Vec<Session>>,
+    // SPS and PPS for the SDP, from the last keyframe
+    parameters: Mutex<Option<(Vec<u8>, Vec<u8>)>>,
+    rtp_socket: UdpSocket,
+    next_session: AtomicU32,
+    on_join: Mutex<Option<JoinCallback>>,
+}
+
+// Serves the live video at rtsp://<addr>/tello, RTP over UDP or interleaved
+// on the RTSP connection. Feed it as a video sink, see TelloController::start_rtsp_server().
+#[derive(Clone)]
+pub struct RtspServer {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+}
+
+impl RtspServer {
+    // Starts accepting connections.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let method_name = "rtsp_server";
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let shared = Arc::new(Shared {
+            sessions: Mutex::new(vec![]),
+            parameters: Mutex::new(None),
+            rtp_socket: UdpSocket::bind("0.0.0.0:0")?,
+            next_session: AtomicU32::new(seed),
+            on_join: Mutex::new(None),
+        });
+        tracing::info!(method_name, "serving rtsp://{}{}", local_addr, RTSP_PATH);
+        let shared_local = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let shared = shared_local.clone();
+                        thread::spawn(move || serve(shared, stream));
+                    }
+                    Err(e) => tracing::warn!(method_name, "accept failed: {}", e),
+                }
+            }
+        });
+        Ok(Self { shared, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Called when a viewer starts playing, e.g. to ask the drone for a keyframe.
+    pub fn on_join(&self, callback: impl Fn() + Send + Sync + 'static) {
+        *self.shared.on_join.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    pub fn viewers(&self) -> usize {
+        let sessions = self.shared.sessions.lock().unwrap();
+        sessions.iter().filter(|s| s.playing).count()
+    }
+}
+
+impl VideoSink for RtspServer {
+    // Never fails, viewers whose connection broke are dropped.
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        let method_name = "rtsp_send";
+        if frame.is_keyframe {
+            let nals = frame.nal_units();
+            let sps = nals.iter().find(|n| video::nal_type(n) == NAL_SPS);
+            let pps = nals.iter().find(|n| video::nal_type(n) == NAL_PPS);
+            if let (Some(sps), Some(pps)) = (sps, pps) {
+                *self.shared.parameters.lock().unwrap() = Some((sps.to_vec(), pps.to_vec()));
+            }
+        }
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        if !sessions.iter().any(|s| s.playing) {
+            return Ok(());
+        }
+        let packets = packetize(frame, RTP_MTU);
+        let ts = (frame.pts.as_micros() as u64 * CLOCK_RATE / 1_000_000) as u32;
+        let socket = &self.shared.rtp_socket;
+        sessions.retain_mut(|s| {
+            if !s.playing || (s.waiting_keyframe && !frame.is_keyframe) {
+                return true;
+            }
+            s.waiting_keyframe = false;
+            for (payload, marker) in packets.iter() {
+                if let Err(e) = s.send(socket, payload, *marker, ts.wrapping_add(s.ts_offset)) {
+                    tracing::info!(method_name, s.id, "viewer dropped: {}", e);
+                    return false;
+                }
+            }
+            true
+        });
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RtspServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RtspServer")
+            .field("local_addr", &self.local_addr)
+            .field("viewers", &self.viewers())
+            .finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    url: String,
+    cseq: String,
+    transport: Option<String>,
+    session: Option<String>,
+}
+
+// Reads one request, skipping the interleaved RTCP the viewers send. None on EOF.
+fn read_request(r: &mut BufReader<TcpStream>) -> io::Result<Option<Request>> {
+    loop {
+        let buf = r.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if buf[0] != b'$' {
+            break;
+        }
+        let mut header = [0; 4];
+        r.read_exact(&mut header)?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as u64;
+        io::copy(&mut r.by_ref().take(len), &mut io::sink())?;
+    }
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let mut req = Request {
+        method: parts.next().unwrap_or_default().to_owned(),
+        url: parts.next().unwrap_or_default().to_owned(),
+        ..Default::default()
+    };
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim().to_owned()),
+            None => continue,
+        };
+        match name.as_str() {
+            "cseq" => req.cseq = value,
+            "transport" => req.transport = Some(value),
+            "session" => req.session = value.split(';').next().map(str::to_owned),
+            "content-length" => content_length = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    io::copy(&mut r.by_ref().take(content_length), &mut io::sink())?;
+    Ok(Some(req))
+}
+
+fn path_of(url: &str) -> &str {
+    let rest = url
+        .strip_prefix("rtsp://")
+        .map_or(url, |rest| rest.find('/').map_or("", |i| &rest[i..]));
+    rest.trim_end_matches('/')
+}
+
+fn serve(shared: Arc<Shared>, stream: TcpStream) {
+    let method_name = "rtsp_serve";
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(_) => return,
+    };
+    // a stalled viewer must not hold up the others
+    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+    let (reader, writer) = match stream.try_clone() {
+        Ok(reader) => (reader, Arc::new(Mutex::new(stream))),
+        Err(_) => return,
+    };
+    tracing::info!(method_name, %peer, "viewer connected");
+    let mut reader = BufReader::new(reader);
+    let mut own_sessions = vec![];
+    while let Ok(Some(req)) = read_request(&mut reader) {
+        tracing::debug!(method_name, %peer, req.method, req.url);
+        let (status, headers, body) = respond(&shared, &req, peer, &writer, &mut own_sessions);
+        let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\n", status, req.cseq);
+        for header in headers {
+            response += &header;
+            response += "\r\n";
+        }
+        if !body.is_empty() {
+            response += &format!("Content-Length: {}\r\n", body.len());
+        }
+        response += "\r\n";
+        response += &body;
+        if writer
+            .lock()
+            .unwrap()
+            .write_all(response.as_bytes())
+            .is_err()
+        {
+            break;
+        }
+    }
+    shared
+        .sessions
+        .lock()
+        .unwrap()
+        .retain(|s| !own_sessions.contains(&s.id));
+    tracing::info!(method_name, %peer, "viewer disconnected");
+}
+
+fn respond(
+    shared: &Shared,
+    req: &Request,
+    peer: SocketAddr,
+    writer: &Arc<Mutex<TcpStream>>,
+    own_sessions: &mut Vec<String>,
+) -> (&'static str, Vec<String>, String) {
+    let path = path_of(&req.url);
+    let track = format!("{}/trackID=0", RTSP_PATH);
+    if !matches!(req.method.as_str(), "OPTIONS" | "GET_PARAMETER")
+        && path != RTSP_PATH
+        && path != track
+        && req.url != "*"
+    {
+        return ("404 Not Found", vec![], String::new());
+    }
+    match req.method.as_str() {
+        "OPTIONS" => (
+            "200 OK",
+            vec!["Public: OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_owned()],
+            String::new(),
+        ),
+        "DESCRIBE" => {
+            let mut fmtp = "packetization-mode=1".to_owned();
+            if let Some((sps, pps)) = shared.parameters.lock().unwrap().as_ref() {
+                if sps.len() >= 4 {
+                    fmtp += &format!(
+                        ";profile-level-id={:02x}{:02x}{:02x}",
+                        sps[1], sps[2], sps[3]
+                    );
+                }
+                fmtp += &format!(";sprop-parameter-sets={},{}", base64(sps), base64(pps));
+            }
+            let sdp = format!(
+                "v=0\r\no=- 0 0 IN IP4 0.0.0.0\r\ns=Tello\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\n\
+                 a=control:*\r\nm=video 0 RTP/AVP {pt}\r\na=rtpmap:{pt} H264/{CLOCK_RATE}\r\n\
+                 a=fmtp:{pt} {fmtp}\r\na=control:trackID=0\r\n",
+                pt = PAYLOAD_TYPE,
+            );
+            let base = format!("Content-Base: {}/", req.url.trim_end_matches('/'));
+            (
+                "200 OK",
+                vec![base, "Content-Type: application/sdp".to_owned()],
+                sdp,
+            )
+        }
+        "SETUP" => {
+            let spec = req.transport.clone().unwrap_or_default();
+            let param = |name: &str| {
+                spec.split(';')
+                    .find_map(|p| p.strip_prefix(name))
+                    .and_then(|v| v.split('-').next())
+                    .and_then(|v| v.parse::<u16>().ok())
+            };
+            // the RTCP channel / port follows the RTP one, out of range values are rejected
+            let setup = if spec.contains("RTP/AVP/TCP") {
+                let channel = match param("interleaved=") {
+                    Some(channel) => u8::try_from(channel).ok(),
+                    None => Some(0),
+                };
+                channel.and_then(|channel| {
+                    let reply = format!(
+                        "RTP/AVP/TCP;unicast;interleaved={}-{}",
+                        channel,
+                        channel.checked_add(1)?
+                    );
+                    let stream = writer.clone();
+                    Some((Transport::Tcp { stream, channel }, reply))
+                })
+            } else {
+                param("client_port=").and_then(|port| {
+                    let server_port = shared.rtp_socket.local_addr().map_or(0, |a| a.port());
+                    let reply = format!(
+                        "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                        port,
+                        port.checked_add(1)?,
+                        server_port,
+                        server_port.checked_add(1)?
+                    );
+                    let addr = SocketAddr::new(peer.ip(), port);
+                    Some((Transport::Udp { addr }, reply))
+                })
+            };
+            let (transport, reply) = match setup {
+                Some(setup) => setup,
+                None => return ("461 Unsupported Transport", vec![], String::new()),
+            };
+            let n = shared.next_session.fetch_add(1, Ordering::Relaxed);
+            let id = format!("{:08X}", n);
+            shared.sessions.lock().unwrap().push(Session {
+                id: id.clone(),
+                transport,
+                playing: false,
+                waiting_keyframe: true,
+                seq: n as u16,
+                ssrc: n.rotate_left(16),
+                ts_offset: n.rotate_left(8),
+            });
+            own_sessions.push(id.clone());
+            (
+                "200 OK",
+                vec![
+                    format!("Transport: {}", reply),
+                    format!("Session: {};timeout={}", id, SESSION_TIMEOUT_SECS),
+                ],
+                String::new(),
+            )
+        }
+        "PLAY" => {
+            let mut sessions = shared.sessions.lock().unwrap();
+            let session = sessions
+                .iter_mut()
+                .find(|s| Some(&s.id) == req.session.as_ref());
+            let session = match session {
+                Some(session) => session,
+                None => return ("454 Session Not Found", vec![], String::new()),
+            };
+            session.playing = true;
+            session.waiting_keyframe = true;
+            let id = session.id.clone();
+            drop(sessions);
+            if let Some(on_join) = shared.on_join.lock().unwrap().as_ref() {
+                on_join();
+            }
+            ("200 OK", vec![format!("Session: {}", id)], String::new())
+        }
+        "TEARDOWN" => {
+            if let Some(id) = req.session.as_ref() {
+                shared.sessions.lock().unwrap().retain(|s| &s.id != id);
+                own_sessions.retain(|s| s != id);
+            }
+            ("200 OK", vec![], String::new())
+        }
+        "GET_PARAMETER" => ("200 OK", vec![], String::new()),
+        _ => ("405 Method Not Allowed", vec![], String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(nals: &[Vec<u8>], is_keyframe: bool) -> VideoFrame {
+        let mut data = vec![];
+        for nal in nals {
+            data.extend([0, 0, 0, 1]);
+            data.extend(nal);
+        }
+        VideoFrame {
+            data,
+            is_keyframe,
+            seq: 0,
+            pts: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_packetize_fu_a() {
+        let mut idr = vec![0x65];
+        idr.extend(std::iter::repeat(7).take(3000));
+        let f = frame(&[vec![0x67, 1, 2], vec![0x68, 3], idr], true);
+        let packets = packetize(&f, 1400);
+        assert_eq!(5, packets.len());
+        assert_eq!((vec![0x67, 1, 2], false), packets[0]);
+        // 3000 bytes after the NAL header in 1398 byte fragments
+        assert_eq!(&[0x7c, 0x85], &packets[2].0[..2]);
+        assert_eq!(&[0x7c, 0x05], &packets[3].0[..2]);
+        assert_eq!(&[0x7c, 0x45], &packets[4].0[..2]);
+        assert_eq!(3000 - 2 * 1398 + 2, packets[4].0.len());
+        assert_eq!(
+            vec![false, false, false, false, true],
+            packets.iter().map(|p| p.1).collect::<Vec<_>>()
+        );
+        assert_eq!("Z0I=", base64(&[0x67, 0x42]));
+    }
+
+    #[test]
+    fn test_play_over_udp() {
+        let mut server = RtspServer::bind("127.0.0.1:0").unwrap();
+        let rtp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        rtp.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let port = rtp.local_addr().unwrap().port();
+        let mut conn = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reader = BufReader::new(conn.try_clone().unwrap());
+        let url = format!("rtsp://{}{}", server.local_addr(), RTSP_PATH);
+        let mut call = |request: String| {
+            conn.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                response += &line;
+            }
+            response
+        };
+        for transport in [
+            "RTP/AVP/TCP;unicast;interleaved=255-256",
+            "RTP/AVP/TCP;unicast;interleaved=300-301",
+            "RTP/AVP;unicast;client_port=65535",
+        ] {
+            let r = call(format!(
+                "SETUP {url}/trackID=0 RTSP/1.0\r\nCSeq: 1\r\nTransport: {transport}\r\n\r\n"
+            ));
+            assert!(r.starts_with("RTSP/1.0 461"), "{}", r);
+        }
+        let r = call(format!("SETUP {url}/trackID=0 RTSP/1.0\r\nCSeq: 1\r\nTransport: RTP/AVP;unicast;client_port={port}-{}\r\n\r\n", port + 1));
+        assert!(r.starts_with("RTSP/1.0 200 OK"), "{}", r);
+        let session = r
+            .lines()
+            .find_map(|l| l.strip_prefix("Session: "))
+            .and_then(|s| s.split(';').next())
+            .unwrap()
+            .to_owned();
+        let r = call(format!(
+            "PLAY {url} RTSP/1.0\r\nCSeq: 2\r\nSession: {session}\r\n\r\n"
+        ));
+        assert!(r.contains("CSeq: 2"), "{}", r);
+        assert_eq!(1, server.viewers());
+
+        // joins at the keyframe
+        server.write_frame(&frame(&[vec![0x41, 9]], false)).unwrap();
+        server
+            .write_frame(&frame(
+                &[vec![0x67, 1, 2, 3], vec![0x68, 4], vec![0x65, 5]],
+                true,
+            ))
+            .unwrap();
+        let mut buf = [0; 2048];
+        let n = rtp.recv(&mut buf).unwrap();
+        assert_eq!(&[0x80, PAYLOAD_TYPE], &buf[..2]);
+        assert_eq!(&[0x67, 1, 2, 3], &buf[12..n]);
+        rtp.recv(&mut buf).unwrap();
+        let n = rtp.recv(&mut buf).unwrap();
+        assert_eq!(0x80 | PAYLOAD_TYPE, buf[1]);
+        assert_eq!(&[0x65, 5], &buf[12..n]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_STICK_RATE_HZ: u32 = 20;
+pub const MIN_STICK_RATE_HZ: u32 = 10;
+pub const MAX_STICK_RATE_HZ: u32 = 50;
+
+// upper bounds (in microseconds) of the jitter histogram buckets, the last bucket
+// collects everything above the last bound
+pub const JITTER_BUCKET_BOUNDS_US: [u64; 7] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+const JITTER_BUCKETS: usize = JITTER_BUCKET_BOUNDS_US.len() + 1;
+
+pub(crate) fn clamp_rate(rate_hz: u32) -> u32 {
+    rate_hz.clamp(MIN_STICK_RATE_HZ, MAX_STICK_RATE_HZ)
+}
+
+fn period_for(rate_hz: u32) -> Duration {
+    Duration::from_nanos(1_000_000_000 / clamp_rate(rate_hz) as u64)
+}
+
+fn jitter_bucket(jitter_us: u64) -> usize {
+    JITTER_BUCKET_BOUNDS_US
+        .iter()
+        .position(|bound| jitter_us <= *bound)
+        .unwrap_or(JITTER_BUCKET_BOUNDS_US.len())
+}
+
+// Counters updated by the stick update loop, readable from any thread.
+#[derive(Debug, Default)]
+pub(crate) struct StickLoopMetrics {
+    ticks: AtomicU64,
+    overruns: AtomicU64,
+    missed_ticks: AtomicU64,
+    max_jitter_us: AtomicU64,
+    jitter_histogram: [AtomicU64; JITTER_BUCKETS],
+}
+
+impl StickLoopMetrics {
+    fn record_tick(&self, jitter: Duration) {
+        let jitter_us = jitter.as_micros() as u64;
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.max_jitter_us.fetch_max(jitter_us, Ordering::Relaxed);
+        self.jitter_histogram[jitter_bucket(jitter_us)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_overrun(&self, missed: u64) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+        self.missed_ticks.fetch_add(missed, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StickLoopStats {
+        let mut jitter_histogram = [0; JITTER_BUCKETS];
+        for (i, bucket) in self.jitter_histogram.iter().enumerate() {
+            jitter_histogram[i] = bucket.load(Ordering::Relaxed);
+        }
+        StickLoopStats {
+            ticks: self.ticks.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            missed_ticks: self.missed_ticks.load(Ordering::Relaxed),
+            max_jitter_us: self.max_jitter_us.load(Ordering::Relaxed),
+            jitter_histogram,
+        }
+    }
+}
+
+// Point in time copy of the stick loop metrics.
+// `jitter_histogram[i]` counts ticks woken up at most `JITTER_BUCKET_BOUNDS_US[i]`
+// after their deadline, the last element counts the rest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StickLoopStats {
+    pub ticks: u64,
+    pub overruns: u64,
+    pub missed_ticks: u64,
+    pub max_jitter_us: u64,
+    pub jitter_histogram: [u64; JITTER_BUCKETS],
+}
+
+// Sleeps until absolute deadlines (start + n * period), so the time spent
+// in the loop body and the sleep inaccuracy don't accumulate into drift.
+pub(crate) struct DeadlineScheduler {
+    period: Duration,
+    next: Instant,
+}
+
+impl DeadlineScheduler {
+    pub(crate) fn new(rate_hz: u32) -> Self {
+        Self {
+            period: period_for(rate_hz),
+            next: Instant::now(),
+        }
+    }
+
+    pub(crate) fn set_rate(&mut self, rate_hz: u32) {
+        self.period = period_for(rate_hz);
+    }
+
+    pub(crate) fn wait(&mut self, metrics: &StickLoopMetrics) {
+        let sleep = self.advance(Instant::now(), metrics);
+        if !sleep.is_zero() {
+            thread::sleep(sleep);
+        }
+        self.woke(Instant::now(), metrics);
+    }
+
+    // Moves to the next deadline, returns how long to sleep until it.
+    fn advance(&mut self, now: Instant, metrics: &StickLoopMetrics) -> Duration {
+        self.next += self.period;
+        if now > self.next {
+            // the deadline already passed, skip the missed ticks but keep the phase
+            let behind = now - self.next;
+            let missed = (behind.as_nanos() / self.period.as_nanos()) as u32;
+            metrics.record_overrun(missed as u64);
+            self.next += self.period * missed;
+            return Duration::ZERO;
+        }
+        self.next - now
+    }
+
+    fn woke(&self, now: Instant, metrics: &StickLoopMetrics) {
+        metrics.record_tick(now.saturating_duration_since(self.next));
+    }
+}
+
+// Wall clock for the stick packets derived from a monotonic clock, so we query
+// the local time only once instead of on every tick.
+pub(crate) struct StickClock {
+    base: chrono::DateTime<chrono::Local>,
+    start: Instant,
+}
+
+impl StickClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            base: chrono::Local::now(),
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn now(&self) -> chrono::DateTime<chrono::Local> {
+        let elapsed = chrono::Duration::from_std(self.start.elapsed()).unwrap_or_default();
+        self.base + elapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_is_clamped() {
+        assert_eq!(MIN_STICK_RATE_HZ, clamp_rate(1));
+        assert_eq!(MAX_STICK_RATE_HZ, clamp_rate(1000));
+        assert_eq!(Duration::from_millis(50), period_for(20));
+    }
+
+    #[test]
+    fn test_jitter_buckets() {
+        assert_eq!(0, jitter_bucket(0));
+        assert_eq!(0, jitter_bucket(100));
+        assert_eq!(1, jitter_bucket(101));
+        assert_eq!(6, jitter_bucket(10_000));
+        assert_eq!(7, jitter_bucket(10_001));
+    }
+
+    #[test]
+    fn test_overrun_is_counted() {
+        let metrics = StickLoopMetrics::default();
+        let mut scheduler = DeadlineScheduler::new(MAX_STICK_RATE_HZ);
+        let start = scheduler.next;
+        let ms = Duration::from_millis;
+        assert_eq!(ms(20), scheduler.advance(start, &metrics));
+        scheduler.woke(start + ms(20) + Duration::from_micros(50), &metrics);
+        let stats = metrics.snapshot();
+        assert_eq!(1, stats.ticks);
+        assert_eq!(0, stats.overruns);
+        assert_eq!(50, stats.max_jitter_us);
+
+        // a loop body that takes more than 3 periods
+        let now = start + ms(90);
+        assert_eq!(Duration::ZERO, scheduler.advance(now, &metrics));
+        scheduler.woke(now, &metrics);
+        let stats = metrics.snapshot();
+        assert_eq!(2, stats.ticks);
+        assert_eq!(1, stats.overruns);
+        assert_eq!(2, stats.missed_ticks);
+        // the deadline moved to 80ms, keeping the phase
+        assert_eq!(10_000, stats.max_jitter_us);
+        assert_eq!(2, stats.jitter_histogram.iter().sum::<u64>());
+    }
+}
@@ -0,0 +1,217 @@
+use std::{fmt, fs, io, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decode::{DecodeError, DecodedFrame},
+    telemetry::Telemetry,
+    utils::Vec3,
+    video::VideoFrame,
+};
+
+// How long TelloController::snapshot() waits for a keyframe.
+pub const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(3);
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+// the metadata is stored as JSON in a PNG tEXt chunk and a JPEG APP segment
+const METADATA_KEY: &str = "tello";
+const JPEG_APP_SEGMENT: u8 = 15;
+
+// Drone state at the time of the picture, None for what wasn't reported yet.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    // RFC 3339 local time
+    pub taken_at: String,
+    pub seq: u64,
+    pub pts: Duration,
+    pub battery_percentage: Option<i8>,
+    // meters
+    pub height: Option<f32>,
+    pub north_speed: Option<i16>,
+    pub east_speed: Option<i16>,
+    pub roll: Option<f64>,
+    pub pitch: Option<f64>,
+    pub yaw: Option<f64>,
+    pub position: Option<Vec3<f32>>,
+}
+
+impl ImageMetadata {
+    pub(crate) fn new(frame: &VideoFrame, telemetry: &Telemetry) -> Self {
+        let flight = telemetry.flight.as_ref();
+        let imu = telemetry.imu.as_ref();
+        Self {
+            taken_at: chrono::Local::now().to_rfc3339(),
+            seq: frame.seq,
+            pts: frame.pts,
+            battery_percentage: flight.map(|f| f.battery_percentage),
+            height: flight.map(|f| f.height as f32 / 10.0),
+            north_speed: flight.map(|f| f.north_speed),
+            east_speed: flight.map(|f| f.east_speed),
+            roll: imu.map(|imu| imu.roll),
+            pitch: imu.map(|imu| imu.pitch),
+            yaw: imu.map(|imu| imu.yaw),
+            position: telemetry.mvo.as_ref().and_then(|mvo| mvo.position),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg { quality: u8 },
+}
+
+impl ImageFormat {
+    // By the file extension, .jpg and .jpeg for JPEG, PNG otherwise.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let ext = path
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("jpg") | Some("jpeg") => ImageFormat::Jpeg {
+                quality: DEFAULT_JPEG_QUALITY,
+            },
+            _ => ImageFormat::Png,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    // no keyframe within SNAPSHOT_TIMEOUT, is the video on?
+    Timeout,
+    Decode(DecodeError),
+    Encode(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Timeout => write!(f, "no video keyframe received"),
+            SnapshotError::Decode(e) => write!(f, "{}", e),
+            SnapshotError::Encode(e) => write!(f, "can't encode the picture: {}", e),
+            SnapshotError::Io(e) => write!(f, "can't save the picture: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(e: DecodeError) -> Self {
+        SnapshotError::Decode(e)
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+// A still picture from the live video, see TelloController::snapshot().
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub frame: DecodedFrame,
+    pub metadata: ImageMetadata,
+}
+
+impl Image {
+    pub fn width(&self) -> u32 {
+        self.frame.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.frame.height
+    }
+
+    pub fn encode(&self, format: ImageFormat) -> Result<Vec<u8>, SnapshotError> {
+        let metadata = serde_json::to_string(&self.metadata)
+            .map_err(|e| SnapshotError::Encode(e.to_string()))?;
+        let encode_err = |e: &dyn fmt::Display| SnapshotError::Encode(e.to_string());
+        let mut out = Vec::new();
+        match format {
+            ImageFormat::Png => {
+                let mut encoder = png::Encoder::new(&mut out, self.frame.width, self.frame.height);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder
+                    .add_text_chunk(METADATA_KEY.to_string(), metadata)
+                    .map_err(|e| encode_err(&e))?;
+                let mut writer = encoder.write_header().map_err(|e| encode_err(&e))?;
+                writer
+                    .write_image_data(&self.frame.rgb)
+                    .map_err(|e| encode_err(&e))?;
+            }
+            ImageFormat::Jpeg { quality } => {
+                let mut encoder = jpeg_encoder::Encoder::new(&mut out, quality.clamp(1, 100));
+                let segment = format!("{}\0{}", METADATA_KEY, metadata);
+                encoder
+                    .add_app_segment(JPEG_APP_SEGMENT, segment.as_bytes())
+                    .map_err(|e| encode_err(&e))?;
+                encoder
+                    .encode(
+                        &self.frame.rgb,
+                        self.frame.width as u16,
+                        self.frame.height as u16,
+                        jpeg_encoder::ColorType::Rgb,
+                    )
+                    .map_err(|e| encode_err(&e))?;
+            }
+        }
+        Ok(out)
+    }
+
+    // PNG or JPEG by the file extension.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        let data = self.encode(ImageFormat::from_path(&path))?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_with_metadata() {
+        let image = Image {
+            frame: DecodedFrame {
+                width: 8,
+                height: 4,
+                rgb: vec![200; 8 * 4 * 3],
+                pts: Duration::from_millis(40),
+            },
+            metadata: ImageMetadata {
+                seq: 7,
+                battery_percentage: Some(81),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            ImageFormat::Jpeg { quality: 90 },
+            ImageFormat::from_path("shot.JPG")
+        );
+        assert_eq!(ImageFormat::Png, ImageFormat::from_path("shot"));
+
+        let png = image.encode(ImageFormat::Png).unwrap();
+        assert_eq!(b"\x89PNG", &png[..4]);
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let text = &reader.info().uncompressed_latin1_text[0];
+        assert_eq!(METADATA_KEY, text.keyword);
+        let metadata: ImageMetadata = serde_json::from_str(&text.text).unwrap();
+        assert_eq!(image.metadata, metadata);
+
+        let jpeg = image.encode(ImageFormat::Jpeg { quality: 80 }).unwrap();
+        assert_eq!(&[0xff, 0xd8], &jpeg[..2]);
+        let marker = jpeg.windows(6).position(|w| w == b"tello\0").unwrap();
+        assert_eq!(
+            &[0xff, 0xe0 + JPEG_APP_SEGMENT],
+            &jpeg[marker - 4..marker - 2]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Instant;
+
+use crate::messages::{FlightData, IMUData, LogData, MVOData};
+
+// Latest state reported by the drone, shared between the receiver and the control threads.
+#[derive(Debug, Default)]
+pub(crate) struct Telemetry {
+    pub flight: Option<FlightData>,
+    pub flight_updated: Option<Instant>,
+    pub imu: Option<IMUData>,
+    pub imu_updated: Option<Instant>,
+    pub mvo: Option<MVOData>,
+    pub mvo_updated: Option<Instant>,
+    // meters, as configured on the drone
+    pub height_limit: Option<u8>,
+}
+
+impl Telemetry {
+    pub(crate) fn update_flight(&mut self, flight: &FlightData) {
+        self.flight = Some(flight.clone());
+        self.flight_updated = Some(Instant::now());
+    }
+
+    pub(crate) fn update_log(&mut self, log: &LogData) {
+        if let Some(ref imu) = log.imu {
+            self.imu = Some(imu.clone());
+            self.imu_updated = Some(Instant::now());
+        }
+        if let Some(ref mvo) = log.mvo {
+            self.mvo = Some(mvo.clone());
+            self.mvo_updated = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn yaw_sample(&self) -> Option<(f64, Instant)> {
+        Some((self.imu.as_ref()?.yaw, self.imu_updated?))
+    }
+
+    pub(crate) fn mvo(&self) -> Option<(&MVOData, Instant)> {
+        Some((self.mvo.as_ref()?, self.mvo_updated?))
+    }
+
+    pub(crate) fn yaw(&self) -> Option<f64> {
+        self.imu.as_ref().map(|imu| imu.yaw)
+    }
+
+    pub(crate) fn battery_percentage(&self) -> Option<i8> {
+        self.flight.as_ref().map(|fd| fd.battery_percentage)
+    }
+}
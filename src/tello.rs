@@ -24,50 +24,67 @@
 // THE SOFTWARE.
 // ---------------------------snip-----------------------------
 use chrono::{Datelike, Timelike};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     net::UdpSocket,
     sync::{
-        atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
-        Arc, RwLock,
+        atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
     },
-    thread::{self},
+    thread,
     time::{Duration, Instant},
 };
 
 use crate::{
+    arbiter::{self, ControlArbiter, ControlSource, SourceKind},
+    autopilot::{self, Autopilot, PausedManeuver},
+    command::{
+        self, CommandCompleter, CommandHandle, CommandResult, CommandWatcher, FlightPredicate,
+    },
     dump::ConnDumper,
     env,
+    envelope::{EnvelopeState, EnvelopeViolation, FlightEnvelope},
+    events::{EventBus, TelloEvent},
+    failsafe::{Failsafe, FailsafeAction, FailsafeChange, FailsafeReason},
+    frame::{self, FrameState},
+    geofence::{BreachAction, FenceStatus, GeofenceState},
     messages::{
-        self, FileChunk, FileInternal, FilePiece, FileType, FlightData, LightData, LogData,
-        TelloPacket, WifiData,
+        self, FileChunk, FileInternal, FilePiece, FileType, FlightData, FlipDirection, LightData,
+        LogData, SmartVideoCmd, TelloPacket, WifiData,
     },
-    utils, UpdateData, UpdateDataPublishChannel, VideoPublishChannel,
+    mp4::Mp4Recorder,
+    phase::{Command, CommandError, FlightPhase, PhaseTracker},
+    recorder::StickRecorder,
+    scheduler::{self, DeadlineScheduler, StickClock, StickLoopMetrics},
+    telemetry::Telemetry,
+    trajectory::Trajectory,
+    utils::{self, Vec3},
+    velocity::VelocityTarget,
+    video::{StreamChange, VideoAssembler, VideoFrame, VideoStats},
+    video_sink::VideoSink,
+    UpdateData, UpdateDataPublishChannel, VideoPublishChannel,
 };
 
+#[cfg(feature = "snapshot")]
+use crate::video::KeyframeWaiters;
+
 const RC_VAL_MIN: i16 = 364;
 const RC_VAL_MAX: i16 = 1684;
 
+const RETURN_HOME_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
 // pub type VideoFrameHandler = Arc<dyn Fn(usize, &Vec<u8>) -> () + Send + Sync>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Stick {
-    rx: f32,
-    ry: f32,
-    lx: f32,
-    ly: f32,
+    pub rx: f32,
+    pub ry: f32,
+    pub lx: f32,
+    pub ly: f32,
 }
 
 impl Stick {
-    fn default() -> Self {
-        Self {
-            rx: 0.0,
-            ry: 0.0,
-            lx: 0.0,
-            ly: 0.0,
-        }
-    }
-
     pub fn new(r: (f32, f32), l: (f32, f32)) -> Self {
         Self {
             rx: r.0,
@@ -76,6 +93,58 @@ impl Stick {
             ly: l.1,
         }
     }
+
+    pub(crate) fn max_deflection(&self) -> f32 {
+        self.rx
+            .abs()
+            .max(self.ry.abs())
+            .max(self.lx.abs())
+            .max(self.ly.abs())
+    }
+}
+
+// Stick state shared between the setters and the stick update loop,
+// each axis is stored as f32 bits so neither side has to take a lock.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicStick {
+    rx: AtomicU32,
+    ry: AtomicU32,
+    lx: AtomicU32,
+    ly: AtomicU32,
+}
+
+impl AtomicStick {
+    pub(crate) fn load(&self) -> Stick {
+        Stick {
+            rx: f32::from_bits(self.rx.load(Ordering::Relaxed)),
+            ry: f32::from_bits(self.ry.load(Ordering::Relaxed)),
+            lx: f32::from_bits(self.lx.load(Ordering::Relaxed)),
+            ly: f32::from_bits(self.ly.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn store(&self, st: &Stick) {
+        self.set_rx(st.rx);
+        self.set_ry(st.ry);
+        self.set_lx(st.lx);
+        self.set_ly(st.ly);
+    }
+
+    pub(crate) fn set_rx(&self, v: f32) {
+        self.rx.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_ry(&self, v: f32) {
+        self.ry.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_lx(&self, v: f32) {
+        self.lx.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_ly(&self, v: f32) {
+        self.ly.store(v.to_bits(), Ordering::Relaxed);
+    }
 }
 
 #[derive(Debug)]
@@ -91,9 +160,28 @@ pub(crate) struct Tello {
     pub ctrl_dumper: Option<ConnDumper>,
     pub(crate) ctrl_seq: &'static AtomicU16,
     files: Arc<RwLock<HashMap<u16, FileInternal>>>,
-    pub(crate) stick: Arc<RwLock<Stick>>,
-    pub(crate) flying: Arc<RwLock<bool>>,
-    video_dump_file: String,
+    pub(crate) events: EventBus,
+    pub(crate) arbiter: Arc<ControlArbiter>,
+    pub(crate) flying: Arc<AtomicBool>,
+    pub(crate) telemetry: Arc<RwLock<Telemetry>>,
+    pub(crate) phase: Arc<Mutex<PhaseTracker>>,
+    pub(crate) commands: Arc<CommandWatcher>,
+    pub(crate) frame: Arc<Mutex<FrameState>>,
+    pub(crate) geofence: Arc<Mutex<GeofenceState>>,
+    pub(crate) autopilot_source: Arc<ControlSource>,
+    pub(crate) autopilot: Arc<Mutex<Autopilot>>,
+    pub(crate) failsafe_source: Arc<ControlSource>,
+    pub(crate) failsafe: Arc<Mutex<Failsafe>>,
+    pub(crate) recorder: Arc<Mutex<Option<StickRecorder>>>,
+    pub(crate) envelope: Arc<Mutex<EnvelopeState>>,
+    pub(crate) video: Arc<Mutex<VideoAssembler>>,
+    pub(crate) video_recorder: Arc<Mutex<Option<Mp4Recorder>>>,
+    // snapshot() callers waiting for the next keyframe
+    #[cfg(feature = "snapshot")]
+    pub(crate) snapshot_waiters: Arc<Mutex<KeyframeWaiters>>,
+    pub(crate) stick_rate_hz: Arc<AtomicU32>,
+    pub(crate) sticks_on_ground: Arc<AtomicBool>,
+    pub(crate) stick_metrics: Arc<StickLoopMetrics>,
 }
 
 impl Clone for Tello {
@@ -110,9 +198,27 @@ impl Clone for Tello {
             ctrl_dumper: self.ctrl_dumper.clone(),
             ctrl_seq: self.ctrl_seq,
             files: self.files.clone(),
-            stick: self.stick.clone(),
+            events: self.events.clone(),
+            arbiter: self.arbiter.clone(),
             flying: self.flying.clone(),
-            video_dump_file: self.video_dump_file.clone(),
+            telemetry: self.telemetry.clone(),
+            phase: self.phase.clone(),
+            commands: self.commands.clone(),
+            frame: self.frame.clone(),
+            geofence: self.geofence.clone(),
+            autopilot_source: self.autopilot_source.clone(),
+            autopilot: self.autopilot.clone(),
+            failsafe_source: self.failsafe_source.clone(),
+            failsafe: self.failsafe.clone(),
+            recorder: self.recorder.clone(),
+            envelope: self.envelope.clone(),
+            video: self.video.clone(),
+            video_recorder: self.video_recorder.clone(),
+            #[cfg(feature = "snapshot")]
+            snapshot_waiters: self.snapshot_waiters.clone(),
+            stick_rate_hz: self.stick_rate_hz.clone(),
+            sticks_on_ground: self.sticks_on_ground.clone(),
+            stick_metrics: self.stick_metrics.clone(),
         }
     }
 }
@@ -123,7 +229,6 @@ static TELLO_CTRL_PACKET_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 impl Tello {
     pub fn new() -> Self {
-        let video_dump_file = format!("./video-{}", utils::now_secs());
         let ctrl_port = *env::ENV_TELLO_CTRL_PORT;
         let local_port = *env::ENV_TELLO_LOCAL_PORT;
         let video_port = *env::ENV_TELLO_VIDEO_PORT;
@@ -132,6 +237,8 @@ impl Tello {
         let local_addr = format!("0.0.0.0:{local_port}");
         let video_addr = format!("0.0.0.0:{video_port}");
         // let (tx, rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
+        let events = EventBus::default();
+        let arbiter = ControlArbiter::new(events.clone());
         Self {
             ctrl_conn: utils::udp_sock("0.0.0.0:0"),
             recv_conn: utils::udp_sock(&local_addr),
@@ -144,106 +251,813 @@ impl Tello {
             ctrl_dumper: Some(ConnDumper::new("ctrl_comm", &TELLO_CTRL_PACKET_COUNTER)),
             ctrl_seq: &TELLO_CTRL_SEQ,
             files: Arc::new(RwLock::new(HashMap::new())),
-            stick: Arc::new(RwLock::new(Stick::default())),
-            flying: Arc::new(RwLock::new(false)),
-            video_dump_file,
+            autopilot_source: arbiter.register_internal(
+                arbiter::AUTOPILOT_SOURCE,
+                arbiter::PRIORITY_AUTOPILOT,
+                SourceKind::Automatic,
+            ),
+            failsafe_source: arbiter.register_internal(
+                arbiter::FAILSAFE_SOURCE,
+                arbiter::PRIORITY_FAILSAFE,
+                SourceKind::Automatic,
+            ),
+            failsafe: Arc::new(Mutex::new(Failsafe::default())),
+            recorder: Arc::new(Mutex::new(None)),
+            envelope: Arc::new(Mutex::new(EnvelopeState::default())),
+            video: Arc::new(Mutex::new(VideoAssembler::new())),
+            video_recorder: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "snapshot")]
+            snapshot_waiters: Arc::new(Mutex::new(KeyframeWaiters::default())),
+            arbiter: Arc::new(arbiter),
+            autopilot: Arc::new(Mutex::new(Autopilot::new(events.clone()))),
+            events,
+            flying: Arc::new(AtomicBool::new(false)),
+            telemetry: Arc::new(RwLock::new(Telemetry::default())),
+            phase: Arc::new(Mutex::new(PhaseTracker::new())),
+            commands: Arc::new(CommandWatcher::default()),
+            frame: Arc::new(Mutex::new(FrameState::default())),
+            geofence: Arc::new(Mutex::new(GeofenceState::default())),
+            stick_rate_hz: Arc::new(AtomicU32::new(scheduler::DEFAULT_STICK_RATE_HZ)),
+            sticks_on_ground: Arc::new(AtomicBool::new(false)),
+            stick_metrics: Arc::new(StickLoopMetrics::default()),
+        }
+    }
+
+    // Refuses the command if the current flight phase (or battery) doesn't allow it,
+    // returns the phase we enter once the command is sent.
+    pub(crate) fn check_command(
+        &self,
+        command: Command,
+    ) -> Result<Option<FlightPhase>, CommandError> {
+        let method_name = "check_command";
+        let allowed = self.envelope.lock().unwrap().envelope().check(command);
+        if let Err(violation) = allowed {
+            tracing::warn!(method_name, ?command, ?violation, "command refused");
+            self.envelope_violated(violation);
+            return Err(CommandError::OutsideEnvelope { violation });
+        }
+        let battery = self.telemetry.read().unwrap().battery_percentage();
+        let r = self.phase.lock().unwrap().check(command, battery);
+        if let Err(ref e) = r {
+            tracing::warn!(method_name, "command refused: {}", e);
+        }
+        r
+    }
+
+    // Records the sent command and returns a handle resolved by the flight status.
+    fn command_sent(
+        &self,
+        command: Command,
+        next: Option<FlightPhase>,
+        done: FlightPredicate,
+        timeout: Duration,
+    ) -> CommandHandle {
+        let handle = self.commands.watch(command, done, timeout);
+        let transition = self.phase.lock().unwrap().on_command(next);
+        if let Some((from, to)) = transition {
+            self.phase_changed(from, to);
+        }
+        handle
+    }
+
+    fn phase_changed(&self, from: FlightPhase, to: FlightPhase) {
+        let method_name = "phase_changed";
+        tracing::info!(method_name, ?from, ?to, "flight phase transition");
+        if !from.is_airborne() && to.is_airborne() {
+            let yaw = self.telemetry.read().unwrap().yaw();
+            let mut frame = self.frame.lock().unwrap();
+            frame.capture_takeoff_yaw(yaw);
+            // captured again from the first valid MVO position
+            frame.clear_home();
+        }
+        self.events.publish(TelloEvent::PhaseChanged { from, to });
+    }
+
+    // Evaluates the failsafe rules, the failsafe source holds the drone while a rule
+    // wants it hovering or landing.
+    fn run_failsafe(&self) {
+        let changes = {
+            let telemetry = self.telemetry.read().unwrap();
+            self.failsafe.lock().unwrap().update(
+                telemetry.flight.as_ref(),
+                telemetry.flight_updated,
+                self.flying.load(Ordering::Relaxed),
+                Instant::now(),
+            )
+        };
+        for change in changes {
+            self.failsafe_changed(change);
+        }
+        let mut failsafe = self.failsafe.lock().unwrap();
+        if failsafe.holds() && !self.failsafe_source.is_engaged() {
+            // released by the arbiter, the pilot took over a hovering failsafe
+            tracing::info!(method_name = "failsafe", "pilot took over the failsafe");
+            failsafe.acknowledge();
+        } else if !failsafe.holds() && self.failsafe_source.is_engaged() {
+            tracing::info!(method_name = "failsafe", "failsafe released");
+            self.failsafe_source.release();
+        }
+    }
+
+    fn failsafe_changed(&self, change: FailsafeChange) {
+        let method_name = "failsafe";
+        match change {
+            FailsafeChange::Triggered(reason, action) => {
+                tracing::warn!(method_name, ?reason, ?action, "failsafe triggered");
+                if reason == FailsafeReason::Deadman {
+                    self.arbiter.manual().set_sticks(&Stick::default());
+                }
+                match action {
+                    FailsafeAction::Warn => {}
+                    FailsafeAction::Hover => self.failsafe_hover(),
+                    FailsafeAction::Land => self.failsafe_land(),
+                    FailsafeAction::ReturnHome => self.failsafe_return_home(),
+                }
+                self.events
+                    .publish(TelloEvent::FailsafeTriggered { reason, action });
+            }
+            FailsafeChange::Cleared(reason) => {
+                tracing::info!(method_name, ?reason, "failsafe cleared");
+                self.events.publish(TelloEvent::FailsafeCleared { reason });
+            }
+        }
+    }
+
+    fn failsafe_hover(&self) {
+        self.stop_autopilot();
+        let locks_out = self.failsafe.lock().unwrap().locks_out();
+        self.failsafe_source.set_overridable(!locks_out);
+        self.failsafe_source.set_sticks(&Stick::default());
+        self.failsafe_source.engage();
+    }
+
+    fn failsafe_land(&self) {
+        self.failsafe_hover();
+        if let Err(e) = self.land() {
+            tracing::warn!(method_name = "failsafe", "unable to land: {}", e);
+        }
+    }
+
+    // Lands when the return home can't start or fails, unless the pilot took over.
+    fn failsafe_return_home(&self) {
+        let method_name = "failsafe";
+        let handle = match self.return_home() {
+            Ok(handle) => handle,
+            Err(e) => {
+                tracing::warn!(method_name, "unable to return home, landing: {}", e);
+                self.failsafe_land();
+                return;
+            }
+        };
+        let tello = self.clone();
+        thread::spawn(move || match handle.wait() {
+            Ok(()) | Err(CommandError::Aborted { .. }) => {}
+            Err(e) => {
+                tracing::warn!(method_name, "return home failed, landing: {}", e);
+                if tello.flying.load(Ordering::Relaxed) {
+                    tello.failsafe_land();
+                }
+            }
+        });
+    }
+
+    // Queues the sample for the recorder's writer thread, the telemetry and frame
+    // locks are released before taking the recorder lock.
+    fn record_sticks(&self, st: &Stick) {
+        if self.recorder.lock().unwrap().is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let (mvo, yaw) = {
+            let telemetry = self.telemetry.read().unwrap();
+            let mvo = telemetry
+                .mvo()
+                .filter(|(_, at)| {
+                    now.saturating_duration_since(*at) <= autopilot::DEFAULT_FEEDBACK_TIMEOUT
+                })
+                .and_then(|(mvo, _)| mvo.position);
+            (mvo, telemetry.yaw())
+        };
+        let heading = self.frame.lock().unwrap().heading(yaw);
+        if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+            recorder.record(st, mvo, heading, yaw, now);
+        }
+    }
+
+    // Captures the home from the first valid MVO position after the takeoff.
+    fn capture_home(&self) {
+        if !self.flying.load(Ordering::Relaxed) {
+            return;
+        }
+        let telemetry = self.telemetry.read().unwrap();
+        if let Some(pos) = telemetry.mvo.as_ref().and_then(|mvo| mvo.position) {
+            let height = telemetry
+                .flight
+                .as_ref()
+                .map_or(0.0, |fd| fd.height as f32 / 10.0);
+            self.frame.lock().unwrap().capture_home(&pos, height);
+        }
+    }
+
+    // Runs the autopilot controllers, their output goes to the autopilot control source.
+    fn run_autopilot(&self) {
+        let method_name = "run_autopilot";
+        let mut autopilot = self.autopilot.lock().unwrap();
+        if autopilot.is_active() && !self.autopilot_source.is_engaged() {
+            // released by the pilot taking over
+            tracing::info!(method_name, "autopilot released, stopping");
+            autopilot.stop();
+            return;
+        }
+        let telemetry = self.telemetry.read().unwrap();
+        let heading = self.frame.lock().unwrap().heading(telemetry.yaw());
+        match autopilot.update(&telemetry, heading, Instant::now()) {
+            Some(st) => self.autopilot_source.set_sticks(&st),
+            None => {
+                if self.autopilot_source.is_engaged() {
+                    tracing::info!(method_name, "autopilot done, releasing the sticks");
+                    self.autopilot_source.release();
+                }
+            }
+        }
+    }
+
+    // The autopilot source is engaged while holding the autopilot lock,
+    // so the stick loop doesn't see an active controller without it.
+    pub(crate) fn set_velocity(&self, target: VelocityTarget) {
+        let mut autopilot = self.autopilot.lock().unwrap();
+        self.autopilot_source.engage();
+        autopilot.set_velocity(target);
+    }
+
+    // Position relative to the takeoff point, height and heading for the
+    // geofence and the flight envelope.
+    fn pose(&self) -> (Option<Vec3<f32>>, Option<f32>, Option<f64>) {
+        let telemetry = self.telemetry.read().unwrap();
+        let frame = self.frame.lock().unwrap();
+        let now = Instant::now();
+        let pos = telemetry
+            .mvo()
+            .filter(|(_, at)| {
+                now.saturating_duration_since(*at) <= autopilot::DEFAULT_FEEDBACK_TIMEOUT
+            })
+            .and_then(|(mvo, _)| mvo.position)
+            .and_then(|p| frame.relative_to_home(&p));
+        let height = telemetry.flight.as_ref().map(|f| f.height as f32 / 10.0);
+        (pos, height, frame.heading(telemetry.yaw()))
+    }
+
+    // Clamps the sticks at the fence boundary and runs the breach action.
+    fn apply_geofence(
+        &self,
+        st: &Stick,
+        pos: Option<Vec3<f32>>,
+        height: Option<f32>,
+        heading: Option<f64>,
+    ) -> Stick {
+        let method_name = "geofence";
+        let (st, status, fence) = {
+            let mut geofence = self.geofence.lock().unwrap();
+            let (st, status) = geofence.apply(st, pos.as_ref(), height, heading);
+            (st, status, geofence.fence())
+        };
+        let (status, fence) = match (status, fence) {
+            (Some(status), Some(fence)) => (status, fence),
+            _ => return st,
+        };
+        tracing::info!(method_name, ?status, "geofence status changed");
+        match status {
+            FenceStatus::Inside => self.events.publish(TelloEvent::GeofenceCleared),
+            FenceStatus::Near => self.events.publish(TelloEvent::GeofenceNear),
+            FenceStatus::Breached => {
+                self.events.publish(TelloEvent::GeofenceBreached {
+                    action: fence.action,
+                });
+                if self.flying.load(Ordering::Relaxed) {
+                    self.geofence_breached(fence.action, pos, heading);
+                }
+            }
+        }
+        st
+    }
+
+    fn apply_envelope(
+        &self,
+        st: &Stick,
+        pos: Option<Vec3<f32>>,
+        height: Option<f32>,
+        heading: Option<f64>,
+    ) -> Stick {
+        let (st, violations) =
+            self.envelope
+                .lock()
+                .unwrap()
+                .apply(st, pos.as_ref(), height, heading);
+        for violation in violations {
+            self.envelope_violated(violation);
+        }
+        st
+    }
+
+    fn envelope_violated(&self, violation: EnvelopeViolation) {
+        tracing::warn!(
+            method_name = "envelope",
+            ?violation,
+            "flight envelope violation"
+        );
+        self.events
+            .publish(TelloEvent::EnvelopeViolation { violation });
+    }
+
+    pub(crate) fn set_flight_envelope(&self, envelope: FlightEnvelope) {
+        let bounce_off = self.envelope.lock().unwrap().set_envelope(envelope);
+        if bounce_off {
+            self.send_bounce(false);
+        }
+    }
+
+    pub(crate) fn set_bounce(&self, on: bool) -> Result<(), CommandError> {
+        let r = self.envelope.lock().unwrap().set_bounce(on);
+        if let Err(violation) = r {
+            self.envelope_violated(violation);
+            return Err(CommandError::OutsideEnvelope { violation });
+        }
+        self.send_bounce(on);
+        Ok(())
+    }
+
+    fn send_bounce(&self, on: bool) {
+        let method_name = "bounce";
+        tracing::debug!(method_name, on, "send");
+        let seq = self.ctrl_seq.fetch_add(1, Ordering::Relaxed);
+        let msg = if on {
+            messages::bounce_on(seq)
+        } else {
+            messages::bounce_off(seq)
+        };
+        let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to send bounce: {}", e);
+        }
+    }
+
+    pub(crate) fn set_sports_mode(&self, on: bool) -> Result<(), CommandError> {
+        let r = self.envelope.lock().unwrap().set_sports_mode(on);
+        if let Err(violation) = r {
+            self.envelope_violated(violation);
+            return Err(CommandError::OutsideEnvelope { violation });
+        }
+        Ok(())
+    }
+
+    fn geofence_breached(
+        &self,
+        action: BreachAction,
+        pos: Option<Vec3<f32>>,
+        heading: Option<f64>,
+    ) {
+        let method_name = "geofence";
+        tracing::warn!(method_name, ?action, ?pos, "geofence breached");
+        match action {
+            BreachAction::Hover => self.stop_autopilot(),
+            BreachAction::Return => {
+                let r = match (pos, heading) {
+                    (Some(pos), Some(heading)) => {
+                        // back above the takeoff point, in the drone heading
+                        let (forward, right) = frame::rotate_xy(-pos.x, -pos.y, heading);
+                        self.move_by(Vec3::new(forward, right, 0.0)).map(|_| ())
+                    }
+                    _ => Err(CommandError::NoTelemetry {
+                        command: Command::MoveBy,
+                    }),
+                };
+                if let Err(e) = r {
+                    tracing::warn!(method_name, "unable to return, hovering: {}", e);
+                    self.stop_autopilot();
+                }
+            }
+            BreachAction::Land => {
+                self.stop_autopilot();
+                if let Err(e) = self.land() {
+                    tracing::warn!(method_name, "unable to land: {}", e);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn stop_autopilot(&self) {
+        let mut autopilot = self.autopilot.lock().unwrap();
+        autopilot.stop();
+        self.autopilot_source.release();
+    }
+
+    // Stops the running maneuver and hovers, resume_maneuver() flies the rest of it.
+    pub(crate) fn pause_maneuver(&self) -> Option<PausedManeuver> {
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let paused = autopilot.pause(Instant::now());
+        self.autopilot_source.release();
+        paused
+    }
+
+    pub(crate) fn resume_maneuver(
+        &self,
+        paused: PausedManeuver,
+    ) -> Result<CommandHandle, CommandError> {
+        self.check_command(paused.command())?;
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let telemetry = self.telemetry.read().unwrap();
+        let handle = autopilot.resume(paused, &telemetry, Instant::now())?;
+        self.autopilot_source.engage();
+        Ok(handle)
+    }
+
+    pub(crate) fn move_by(&self, offset: Vec3<f32>) -> Result<CommandHandle, CommandError> {
+        self.check_command(Command::MoveBy)?;
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let telemetry = self.telemetry.read().unwrap();
+        let heading = self.frame.lock().unwrap().heading(telemetry.yaw());
+        let handle = autopilot.move_by(offset, &telemetry, heading, Instant::now())?;
+        self.autopilot_source.engage();
+        Ok(handle)
+    }
+
+    fn fly_to(&self, command: Command, target: Vec3<f32>) -> Result<CommandHandle, CommandError> {
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let telemetry = self.telemetry.read().unwrap();
+        let handle = autopilot.fly_to(command, target, &telemetry, Instant::now())?;
+        self.autopilot_source.engage();
+        Ok(handle)
+    }
+
+    // Climbs to the return height, flies straight back above the takeoff point,
+    // turns to the takeoff heading and lands.
+    pub(crate) fn return_home(&self) -> Result<CommandHandle, CommandError> {
+        let method_name = "return_home";
+        let command = Command::ReturnHome;
+        self.check_command(command)?;
+        let (home, yaw) = {
+            let frame = self.frame.lock().unwrap();
+            (frame.home(), frame.takeoff_yaw())
+        };
+        let home = home.ok_or(CommandError::NoTelemetry { command })?;
+        tracing::info!(method_name, ?home, ?yaw, "returning home");
+        let (handle, completer) = CommandHandle::new(command, autopilot::RETURN_HOME_TIMEOUT);
+        let tello = self.clone();
+        thread::spawn(move || match tello.fly_home(home, yaw, &completer) {
+            Ok(()) => {
+                tracing::info!(method_name, "home");
+                completer.complete();
+            }
+            Err(e) => {
+                tracing::warn!(method_name, "return home failed: {}", e);
+                completer.fail(e);
+            }
+        });
+        Ok(handle)
+    }
+
+    fn fly_home(
+        &self,
+        home: Vec3<f32>,
+        yaw: Option<f64>,
+        completer: &CommandCompleter,
+    ) -> CommandResult {
+        let command = Command::ReturnHome;
+        let (height, pos) = {
+            let autopilot = self.autopilot.lock().unwrap();
+            let telemetry = self.telemetry.read().unwrap();
+            let pos = autopilot.position(&telemetry, Instant::now());
+            (autopilot.config().return_height, pos)
+        };
+        let pos = pos.ok_or(CommandError::NoTelemetry { command })?;
+        // MVO z points down, never descend before flying back
+        let z = pos.z.min(home.z - height);
+        for target in [Vec3::new(pos.x, pos.y, z), Vec3::new(home.x, home.y, z)] {
+            let step = self.fly_to(command, target)?;
+            self.wait_home_step(&step, &home, completer)?;
+        }
+        if let Some(yaw) = yaw {
+            let step = self.rotate_to(yaw)?;
+            self.wait_home_step(&step, &home, completer)?;
+        }
+        let step = self.land()?;
+        self.wait_home_step(&step, &home, completer)
+    }
+
+    // Waits for one return home step, publishing the remaining horizontal distance.
+    fn wait_home_step(
+        &self,
+        step: &CommandHandle,
+        home: &Vec3<f32>,
+        completer: &CommandCompleter,
+    ) -> CommandResult {
+        loop {
+            if let Some(r) = step.wait_timeout(RETURN_HOME_PROGRESS_INTERVAL) {
+                return r;
+            }
+            if completer.is_resolved() {
+                // timed out
+                self.stop_autopilot();
+                return Err(CommandError::Timeout {
+                    command: Command::ReturnHome,
+                });
+            }
+            let pos = self
+                .telemetry
+                .read()
+                .unwrap()
+                .mvo()
+                .and_then(|(mvo, _)| mvo.position);
+            if let Some(pos) = pos {
+                let distance = (home.x - pos.x).hypot(home.y - pos.y);
+                self.events
+                    .publish(TelloEvent::ReturnHomeProgress { distance });
+            }
+        }
+    }
+
+    pub(crate) fn rotate_to(&self, yaw: f64) -> Result<CommandHandle, CommandError> {
+        self.check_command(Command::Rotate)?;
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let telemetry = self.telemetry.read().unwrap();
+        let handle = autopilot.rotate_to(yaw, &telemetry, Instant::now())?;
+        self.autopilot_source.engage();
+        Ok(handle)
+    }
+
+    pub(crate) fn rotate_by(&self, deg: f64) -> Result<CommandHandle, CommandError> {
+        self.check_command(Command::Rotate)?;
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let telemetry = self.telemetry.read().unwrap();
+        let handle = autopilot.rotate_by(deg, &telemetry, Instant::now())?;
+        self.autopilot_source.engage();
+        Ok(handle)
+    }
+
+    // Checks the target height against the envelope and the drone height limit.
+    fn check_height_limit(&self, command: Command, height: f32) -> Result<(), CommandError> {
+        let method_name = "check_height_limit";
+        let allowed = self
+            .envelope
+            .lock()
+            .unwrap()
+            .envelope()
+            .check_height(height);
+        if let Err(violation) = allowed {
+            tracing::warn!(method_name, ?command, height, "above the envelope height");
+            self.envelope_violated(violation);
+            return Err(CommandError::OutsideEnvelope { violation });
+        }
+        let limit = self.telemetry.read().unwrap().height_limit;
+        let limit = limit.unwrap_or(autopilot::DEFAULT_HEIGHT_LIMIT);
+        if height > limit as f32 {
+            tracing::warn!(
+                method_name,
+                ?command,
+                height,
+                limit,
+                "above the height limit"
+            );
+            return Err(CommandError::AboveHeightLimit { command, limit });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn follow_trajectory(
+        &self,
+        trajectory: Trajectory,
+    ) -> Result<CommandHandle, CommandError> {
+        self.check_command(Command::FollowTrajectory)?;
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let telemetry = self.telemetry.read().unwrap();
+        let heading = self.frame.lock().unwrap().heading(telemetry.yaw());
+        let handle = autopilot.follow(trajectory, &telemetry, heading, Instant::now())?;
+        self.autopilot_source.engage();
+        Ok(handle)
+    }
+
+    pub(crate) fn hold_altitude(&self, height: f32) -> Result<CommandHandle, CommandError> {
+        self.check_command(Command::HoldAltitude)?;
+        self.check_height_limit(Command::HoldAltitude, height)?;
+        let mut autopilot = self.autopilot.lock().unwrap();
+        let telemetry = self.telemetry.read().unwrap();
+        let handle = autopilot.hold_altitude(height, &telemetry, Instant::now())?;
+        self.autopilot_source.engage();
+        Ok(handle)
+    }
+
+    // Takes off and climbs to `height` once the takeoff is confirmed.
+    pub(crate) fn takeoff_to(&self, height: f32) -> Result<CommandHandle, CommandError> {
+        let method_name = "takeoff_to";
+        let command = Command::TakeoffTo;
+        self.check_height_limit(command, height)?;
+        let takeoff = self.takeoff()?;
+        let (handle, completer) = CommandHandle::new(
+            command,
+            command::TAKEOFF_TIMEOUT + autopilot::ALTITUDE_TIMEOUT,
+        );
+        let tello = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = takeoff.wait() {
+                tracing::warn!(method_name, "takeoff failed: {}", e);
+                completer.fail(e);
+                return;
+            }
+            if completer.is_resolved() {
+                return;
+            }
+            let mut autopilot = tello.autopilot.lock().unwrap();
+            tello.autopilot_source.engage();
+            autopilot.climb_to(command, height, completer);
+        });
+        Ok(handle)
+    }
+
+    pub(crate) fn smart_video(&self, cmd: SmartVideoCmd, start: bool) {
+        let method_name = "smart_video";
+        tracing::debug!(method_name, ?cmd, start, "send");
+        let seq = self.ctrl_seq.fetch_add(1, Ordering::Relaxed);
+        let msg = messages::smart_video_toggle(seq, cmd, start);
+        let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to send smart video: {}", e);
+        }
+    }
+
+    pub(crate) fn query_height_limit(&self) {
+        let method_name = "query_height_limit";
+        tracing::debug!(method_name, self.remote_addr, "send");
+        let msg = messages::query_height_limit(self.ctrl_seq.fetch_add(1, Ordering::Relaxed));
+        let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to query height limit: {}", e);
         }
     }
 
-    pub(crate) fn takeoff(&self) {
+    pub(crate) fn flight_phase(&self) -> FlightPhase {
+        self.phase.lock().unwrap().phase()
+    }
+
+    pub(crate) fn takeoff(&self) -> Result<CommandHandle, CommandError> {
         let method_name = "takeoff";
+        let next = self.check_command(Command::Takeoff)?;
         tracing::debug!(method_name, "send");
         let msg = messages::do_takeoff(self.ctrl_seq.fetch_add(1, Ordering::Relaxed));
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to take off: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to take off: {}", e);
+        }
+        Ok(self.command_sent(
+            Command::Takeoff,
+            next,
+            command::takeoff_done(),
+            command::TAKEOFF_TIMEOUT,
+        ))
+    }
+
+    pub(crate) fn throw_takeoff(&self) -> Result<CommandHandle, CommandError> {
+        let method_name = "throw_takeoff";
+        let next = self.check_command(Command::ThrowTakeoff)?;
+        tracing::debug!(method_name, "send");
+        let msg = messages::throw_takeoff(self.ctrl_seq.fetch_add(1, Ordering::Relaxed));
+        let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to throw take off: {}", e);
         }
+        Ok(self.command_sent(
+            Command::ThrowTakeoff,
+            next,
+            command::throw_takeoff_done(),
+            command::THROW_TAKEOFF_TIMEOUT,
+        ))
     }
 
-    pub(crate) fn land(&self) {
+    pub(crate) fn land(&self) -> Result<CommandHandle, CommandError> {
         let method_name = "land";
+        let next = self.check_command(Command::Land)?;
         tracing::debug!(method_name, "send");
         let msg = messages::do_land(self.ctrl_seq.fetch_add(1, Ordering::Relaxed));
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to land: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to land: {}", e);
+        }
+        Ok(self.command_sent(
+            Command::Land,
+            next,
+            command::land_done(),
+            command::LAND_TIMEOUT,
+        ))
+    }
+
+    pub(crate) fn palm_land(&self) -> Result<CommandHandle, CommandError> {
+        let method_name = "palm_land";
+        let next = self.check_command(Command::PalmLand)?;
+        tracing::debug!(method_name, "send");
+        let msg = messages::palm_land(self.ctrl_seq.fetch_add(1, Ordering::Relaxed));
+        let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to palm land: {}", e);
         }
+        Ok(self.command_sent(
+            Command::PalmLand,
+            next,
+            command::palm_land_done(),
+            command::LAND_TIMEOUT,
+        ))
+    }
+
+    pub(crate) fn flip(&self, direction: FlipDirection) -> Result<CommandHandle, CommandError> {
+        let method_name = "flip";
+        let next = self.check_command(Command::Flip(direction))?;
+        tracing::debug!(method_name, ?direction, "send");
+        let msg = messages::flip(self.ctrl_seq.fetch_add(1, Ordering::Relaxed), direction);
+        let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to flip: {}", e);
+        }
+        Ok(self.command_sent(
+            Command::Flip(direction),
+            next,
+            command::flip_done(),
+            command::FLIP_TIMEOUT,
+        ))
     }
 
     pub(crate) fn forward(&self, amt: f32) {
         let method_name = "forward";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((0.0, amt), (0.0, 0.0));
-        let mut g = self.stick.write().unwrap();
-        g.ry = amt;
+        self.arbiter.manual().stick.set_ry(amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn backward(&self, amt: f32) {
         let method_name = "backward";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((0.0, -amt), (0.0, 0.0));
-        let mut g = self.stick.write().unwrap();
-        g.ry = -amt;
+        self.arbiter.manual().stick.set_ry(-amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn left(&self, amt: f32) {
         let method_name = "left";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((-amt, 0.0), (0.0, 0.0));
-        let mut g = self.stick.write().unwrap();
-        g.rx = -amt;
+        self.arbiter.manual().stick.set_rx(-amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn right(&self, amt: f32) {
         let method_name = "right";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((amt, 0.0), (0.0, 0.0));
-        let mut g = self.stick.write().unwrap();
-        g.rx = amt;
+        self.arbiter.manual().stick.set_rx(amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn up(&self, amt: f32) {
         let method_name = "up";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((0.0, 0.0), (0.0, amt));
-        let mut g = self.stick.write().unwrap();
-        g.ly = amt;
-        // *g = st.clone();
+        self.arbiter.manual().stick.set_ly(amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn down(&self, amt: f32) {
         let method_name = "down";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((0.0, 0.0), (0.0, -amt));
-        let mut g = self.stick.write().unwrap();
-        // *g = st.clone();
-        g.ly = -amt;
+        self.arbiter.manual().stick.set_ly(-amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn turn_clockwise(&self, amt: f32) {
         let method_name = "turn_clockwise";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((0.0, 0.0), (amt, 0.0));
-        let mut g = self.stick.write().unwrap();
-        // *g = st.clone();
-        g.lx = amt;
+        self.arbiter.manual().stick.set_lx(amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn turn_counter_clockwise(&self, amt: f32) {
         let method_name = "turn_counter_clockwise";
         tracing::debug!(method_name, amt, "update");
         // let st = Stick::new((0.0, 0.0), (-amt, 0.0));
-        let mut g = self.stick.write().unwrap();
-        // *g = st.clone();
-        g.lx = -amt;
+        self.arbiter.manual().stick.set_lx(-amt);
+        self.manual_sticks_updated();
     }
 
     pub(crate) fn hover(&self) {
         let method_name = "hover";
         tracing::debug!(method_name, "update");
         let st = Stick::new((0.0, 0.0), (0.0, 0.0));
-        let mut g = self.stick.write().unwrap();
-        *g = st.clone();
+        self.arbiter.manual().set_sticks(&st);
+        self.manual_sticks_updated();
+    }
+
+    // Feeds the failsafe deadman on every change of the manual sticks.
+    pub(crate) fn manual_sticks_updated(&self) {
+        self.failsafe.lock().unwrap().sticks_updated(Instant::now());
     }
 
     pub(crate) fn send_file_size(&self) {
@@ -251,8 +1065,8 @@ impl Tello {
         tracing::debug!(method_name, "send");
         let msg = messages::ack_file_size(self.ctrl_seq.fetch_add(1, Ordering::Relaxed));
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to ack file size: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to ack file size: {}", e);
         }
     }
 
@@ -266,8 +1080,8 @@ impl Tello {
             piece_no,
         );
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to ack file piece: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to ack file piece: {}", e);
         }
     }
 
@@ -276,8 +1090,8 @@ impl Tello {
         tracing::debug!(method_name, self.remote_addr, f_id, size, "send");
         let msg = messages::file_done(self.ctrl_seq.fetch_add(1, Ordering::Relaxed), f_id, size);
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to ack file done: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to ack file done: {}", e);
         }
     }
 
@@ -288,8 +1102,8 @@ impl Tello {
         let msg =
             TelloPacket::ack_log(self.ctrl_seq.fetch_add(1, Ordering::Relaxed), &buf).to_buffer();
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to ack log header: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to ack log header: {}", e);
         }
     }
 
@@ -309,8 +1123,8 @@ impl Tello {
             ms,
         );
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(method_name, "unable to ack log header: {}", r.unwrap_err());
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to ack log header: {}", e);
         }
     }
 
@@ -319,12 +1133,8 @@ impl Tello {
         tracing::debug!(method_name, self.remote_addr, "send");
         let msg = messages::query_video_spsfps();
         let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-        if r.is_err() {
-            tracing::warn!(
-                method_name,
-                "unable to send query for sps/pps: {}",
-                r.unwrap_err()
-            );
+        if let Err(e) = r {
+            tracing::warn!(method_name, "unable to send query for sps/pps: {}", e);
         }
     }
 
@@ -396,12 +1206,16 @@ impl Tello {
                 tracing::info!(method_name, "flight status received");
                 let flight_data = FlightData::new(&pkt.payload);
                 tracing::info!(method_name, "flight_data: {:?}", flight_data);
-                let mut g = self.flying.write().unwrap();
-                *g = flight_data.flying;
-                drop(g);
+                self.flying.store(flight_data.flying, Ordering::Relaxed);
+                self.telemetry.write().unwrap().update_flight(&flight_data);
+                let transition = self.phase.lock().unwrap().on_flight_data(&flight_data);
+                if let Some((from, to)) = transition {
+                    self.phase_changed(from, to);
+                }
+                self.commands.on_flight_data(&flight_data);
                 let r = tx.send(UpdateData::from_flight_data(flight_data));
-                if r.is_err() {
-                    tracing::error!("unable to send flight data: {}", r.err().unwrap());
+                if let Err(e) = r {
+                    tracing::error!("unable to send flight data: {}", e);
                 }
             }
             messages::MSG_LIGHT_STRENGTH => {
@@ -409,8 +1223,8 @@ impl Tello {
                 let light_strength = LightData::new(&pkt.payload);
                 tracing::info!(method_name, "light data: {:?}", light_strength);
                 let r = tx.send(UpdateData::from_light_data(light_strength));
-                if r.is_err() {
-                    tracing::error!("unable to send light health data: {}", r.err().unwrap());
+                if let Err(e) = r {
+                    tracing::error!("unable to send light health data: {}", e);
                 }
             }
             messages::MSG_LOG_CONFIG => {
@@ -424,15 +1238,22 @@ impl Tello {
                 tracing::info!(method_name, "log data received");
                 let log_data = LogData::new(&pkt.payload);
                 tracing::info!("log_data={:?}", log_data);
+                self.telemetry.write().unwrap().update_log(&log_data);
+                self.capture_home();
                 if log_data.imu.is_some() || log_data.mvo.is_some() {
                     let r = tx.send(UpdateData::from_log_data(log_data));
-                    if r.is_err() {
-                        tracing::error!("unable to send flight log data: {}", r.err().unwrap());
+                    if let Err(e) = r {
+                        tracing::error!("unable to send flight log data: {}", e);
                     }
                 }
             }
             messages::MSG_QUERY_HEIGHT_LIMIT => {
                 tracing::info!(method_name, "max height received");
+                if pkt.payload.len() > 1 {
+                    let limit = pkt.payload[1];
+                    tracing::info!(method_name, limit, "height limit");
+                    self.telemetry.write().unwrap().height_limit = Some(limit);
+                }
             }
             messages::MSG_QUERY_LOW_BATT_THRESH => {
                 tracing::info!(method_name, "low battery threshold received");
@@ -463,9 +1284,18 @@ impl Tello {
                 tracing::info!(method_name, "wifi strength info received");
                 let info = WifiData::new(&pkt.payload);
                 tracing::info!(method_name, "wifi data: {:?}", info);
-                let r = tx.send(UpdateData::from_wifi_data(info));
-                if r.is_err() {
-                    tracing::error!("unable to send wifi data: {}", r.err().unwrap());
+                self.failsafe
+                    .lock()
+                    .unwrap()
+                    .link_updated(info.wifi_strength);
+                let video = {
+                    let mut video = self.video.lock().unwrap();
+                    video.update_rates(Instant::now());
+                    video.stats()
+                };
+                let r = tx.send(UpdateData::from_wifi_data(info, video));
+                if let Err(e) = r {
+                    tracing::error!("unable to send wifi data: {}", e);
                 }
             }
             _ => {
@@ -480,18 +1310,20 @@ impl Tello {
         let mut buff: [u8; 4096] = [0; 4096];
 
         loop {
-            let r = self.ctrl_conn.recv(&mut buff);
-            if r.is_err() {
-                tracing::warn!(method_name, "udp read error: {}", r.unwrap_err());
-                continue;
-            }
+            let nread = match self.ctrl_conn.recv(&mut buff) {
+                Ok(nread) => nread,
+                Err(e) => {
+                    tracing::warn!(method_name, "udp read error: {}", e);
+                    continue;
+                }
+            };
             if let Some(ref dumper) = &self.ctrl_dumper {
                 dumper.dump(&buff);
             }
-            let nread = r.unwrap();
             if !self.connected.load(Ordering::Relaxed) && nread == 11 {
                 if utils::contains_any(&buff, "conn_ack:".as_bytes()).is_some() {
                     self.connected.store(true, Ordering::Relaxed);
+                    self.query_height_limit();
                 } else {
                     tracing::warn!(method_name, "unexpected response to connect request");
                 }
@@ -509,30 +1341,91 @@ impl Tello {
     pub(crate) fn video_receiver(&self, video_channel: VideoPublishChannel) {
         let method_name = "video_recv";
         let mut buff: [u8; 2048] = [0; 2048];
+        self.video.lock().unwrap().set_pool(video_channel.pool());
 
         loop {
-            let r = self.video_conn.recv(&mut buff);
-            if r.is_err() {
-                tracing::warn!(method_name, "udp read error: {}", r.unwrap_err());
+            let nread = match self.video_conn.recv(&mut buff) {
+                Ok(nread) => nread,
+                Err(e) => {
+                    tracing::warn!(method_name, "udp read error: {}", e);
+                    continue;
+                }
+            };
+
+            tracing::debug!(method_name, nread, "read video stream data");
+            if nread < 2 {
                 continue;
             }
+            let (frame, changes) = {
+                let mut assembler = self.video.lock().unwrap();
+                let frame = assembler.push(&buff[..nread], Instant::now());
+                (frame, assembler.changes())
+            };
+            for change in changes {
+                self.video_stream_changed(change);
+            }
+            if let Some(frame) = frame {
+                self.record_video(&frame);
+                #[cfg(feature = "snapshot")]
+                self.snapshot_video(&frame);
+                let (seq, video_data_len) = (frame.seq, frame.data.len());
+                let r = video_channel.send(frame);
+                if let Err(e) = r {
+                    tracing::error!(method_name, "error sending video data: {}", e);
+                }
+                tracing::debug!(method_name, seq, video_data_len);
+            }
+        }
+    }
 
-            let nread = r.unwrap();
-            tracing::debug!(method_name, nread, "read video stream data");
-            let video_packet = buff[2..nread].to_vec();
-
-            // dump all video to file
-            utils::append_to_file(&self.video_dump_file, video_packet.clone());
-            let video_data_len = video_packet.len();
-            let r = video_channel.send(video_packet);
-            if r.is_err() {
-                tracing::error!(
+    fn record_video(&self, frame: &VideoFrame) {
+        let method_name = "record_video";
+        let mut recorder = self.video_recorder.lock().unwrap();
+        let r = match recorder.as_mut() {
+            Some(recorder) => recorder.write_frame(frame),
+            None => return,
+        };
+        if let Err(e) = r {
+            tracing::warn!(method_name, "video recording stopped: {}", e);
+            let _ = recorder.take().unwrap().finish();
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn snapshot_video(&self, frame: &VideoFrame) {
+        self.snapshot_waiters.lock().unwrap().push(frame);
+    }
+
+    fn video_stream_changed(&self, change: StreamChange) {
+        let method_name = "video_stream_changed";
+        match change {
+            StreamChange::Lost { frames } => {
+                tracing::info!(
                     method_name,
-                    "error sending video data: {}",
-                    r.err().unwrap()
+                    frames,
+                    "video frames lost, waiting for a keyframe"
                 );
+                self.events.publish(TelloEvent::VideoLost { frames });
+            }
+            StreamChange::Recovered { after } => {
+                tracing::info!(method_name, ?after, "video recovered");
+                self.events.publish(TelloEvent::VideoRecovered { after });
             }
-            tracing::debug!(method_name, video_data_len);
+        }
+    }
+
+    // Asks for a keyframe for a new consumer, the video controller thread
+    // keeps requesting it (with backoff) until it arrives.
+    pub(crate) fn want_keyframe(&self) {
+        self.video.lock().unwrap().want_keyframe();
+        self.request_keyframe();
+    }
+
+    // Asks for the SPS/PPS and a keyframe when the video stream needs them.
+    pub(crate) fn request_keyframe(&self) {
+        let due = self.video.lock().unwrap().keyframe_request(Instant::now());
+        if due {
+            self.query_video_sps_pps();
         }
     }
 
@@ -564,48 +1457,56 @@ impl Tello {
 
     pub(crate) fn send_update_sticks(&self) {
         let method_name = "update_sticks";
+        let clock = StickClock::new();
+        let mut scheduler = DeadlineScheduler::new(self.stick_rate_hz.load(Ordering::Relaxed));
         loop {
-            let start = Instant::now();
-            let st = self.stick.read().unwrap();
+            self.commands.expire();
+            self.run_failsafe();
+            self.run_autopilot();
+            let (st, kind) = self.arbiter.resolve();
+            let st = if kind == SourceKind::Manual {
+                let yaw = self.telemetry.read().unwrap().yaw();
+                self.frame.lock().unwrap().apply(&st, yaw)
+            } else {
+                st
+            };
+            let flying = self.flying.load(Ordering::Relaxed);
+            let send = flying || self.sticks_on_ground.load(Ordering::Relaxed);
+            // recorded before the fence and envelope limits, the open-loop replay
+            // goes through them again
+            if send {
+                self.record_sticks(&st);
+            }
+            let (pos, height, heading) = self.pose();
+            let st = self.apply_geofence(&st, pos, height, heading);
+            let st = self.apply_envelope(&st, pos, height, heading);
             let rx = Self::joy(st.rx, RC_VAL_MIN, RC_VAL_MAX, true);
             let ry = Self::joy(st.ry, RC_VAL_MIN, RC_VAL_MAX, true);
             let lx = Self::joy(st.lx, RC_VAL_MIN, RC_VAL_MAX, true);
             let ly = Self::joy(st.ly, RC_VAL_MIN, RC_VAL_MAX, true);
-            drop(st);
-
-            let now = chrono::Local::now();
-            let ms = now.timestamp_subsec_micros() & 0xffff;
-            let g = self.flying.read().unwrap();
-            let flying = *g;
-            drop(g);
-            if flying {
+
+            if send {
+                let now = clock.now();
+                let ms = now.timestamp_subsec_micros() & 0xffff;
                 tracing::debug!(method_name, rx, ry, lx, ly, "update drone movement");
                 let msg = messages::send_stick_update(
                     rx,
                     ry,
                     lx,
                     ly,
-                    false,
+                    self.envelope.lock().unwrap().sports_mode(),
                     now.hour() as u8,
                     now.minute() as u8,
                     now.second() as u8,
                     ms as u16,
                 );
                 let r = self.ctrl_conn.send_to(&msg, &self.remote_addr);
-                if r.is_err() {
-                    tracing::warn!(method_name, "unable to ack log header: {}", r.unwrap_err());
+                if let Err(e) = r {
+                    tracing::warn!(method_name, "unable to send sticks: {}", e);
                 }
             }
-            let now = Instant::now();
-            let dur = now - start;
-            let dur_ms = dur.as_millis();
-            if dur_ms < 50 {
-                let sleep_duration = 50 - dur_ms;
-                // tracing::debug!(method_name, "update sticks duration={:?}", dur);
-                thread::sleep(Duration::from_millis(
-                    sleep_duration.try_into().expect("too big value to fit"),
-                ));
-            }
+            scheduler.set_rate(self.stick_rate_hz.load(Ordering::Relaxed));
+            scheduler.wait(&self.stick_metrics);
         }
     }
 }
@@ -617,6 +1518,7 @@ impl UpdateData {
             wifi: None,
             light: None,
             log: None,
+            video: None,
         }
     }
     pub(crate) fn from_flight_data(flight: FlightData) -> Self {
@@ -625,15 +1527,17 @@ impl UpdateData {
             wifi: None,
             light: None,
             log: None,
+            video: None,
         }
     }
 
-    pub(crate) fn from_wifi_data(wifi: WifiData) -> Self {
+    pub(crate) fn from_wifi_data(wifi: WifiData, video: VideoStats) -> Self {
         Self {
             flight: None,
             wifi: Some(wifi),
             light: None,
             log: None,
+            video: Some(video),
         }
     }
 
@@ -643,6 +1547,7 @@ impl UpdateData {
             wifi: None,
             light: Some(light),
             log: None,
+            video: None,
         }
     }
 
@@ -652,6 +1557,7 @@ impl UpdateData {
             wifi: None,
             light: None,
             log: Some(log),
+            video: None,
         }
     }
 }
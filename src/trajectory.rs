@@ -0,0 +1,285 @@
+use std::{fmt, time::Duration};
+
+use crate::utils::Vec3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    // stops at every waypoint, each segment has the minimum jerk profile
+    MinJerk,
+    // passes through the waypoints without stopping, starts and ends at rest
+    CatmullRom,
+}
+
+// Position in meters (x forward, y right, z up) and yaw in degrees (clockwise),
+// relative to the drone position and heading when the trajectory starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Waypoint {
+    pub time: Duration,
+    pub position: Vec3<f32>,
+    pub yaw: Option<f64>,
+}
+
+impl Waypoint {
+    pub fn new(time: Duration, position: Vec3<f32>, yaw: Option<f64>) -> Self {
+        Self {
+            time,
+            position,
+            yaw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrajectoryError {
+    TooFewWaypoints,
+    // the waypoint time is not after the time of the previous waypoint
+    NotIncreasing { index: usize },
+}
+
+impl fmt::Display for TrajectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrajectoryError::TooFewWaypoints => write!(f, "trajectory needs at least 2 waypoints"),
+            TrajectoryError::NotIncreasing { index } => {
+                write!(f, "waypoint {} is not after the previous one", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TrajectoryError {}
+
+// Desired state at a point of time, velocity in m/s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrajectorySample {
+    pub position: Vec3<f32>,
+    pub velocity: Vec3<f32>,
+    pub yaw: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    waypoints: Vec<Waypoint>,
+    interpolation: Interpolation,
+}
+
+impl Trajectory {
+    pub fn new(
+        waypoints: Vec<Waypoint>,
+        interpolation: Interpolation,
+    ) -> Result<Self, TrajectoryError> {
+        if waypoints.len() < 2 {
+            return Err(TrajectoryError::TooFewWaypoints);
+        }
+        for i in 1..waypoints.len() {
+            if waypoints[i].time <= waypoints[i - 1].time {
+                return Err(TrajectoryError::NotIncreasing { index: i });
+            }
+        }
+        Ok(Self {
+            waypoints,
+            interpolation,
+        })
+    }
+
+    pub fn waypoints(&self) -> &[Waypoint] {
+        &self.waypoints
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    // Time of the last waypoint.
+    pub fn duration(&self) -> Duration {
+        self.waypoints[self.waypoints.len() - 1].time
+    }
+
+    // Samples the trajectory at `t` from the start, clamped to the first and last waypoint.
+    pub fn sample(&self, t: Duration) -> TrajectorySample {
+        let wps = &self.waypoints;
+        let last = wps.len() - 1;
+        if t <= wps[0].time {
+            return self.at_rest(0);
+        }
+        if t >= wps[last].time {
+            return self.at_rest(last);
+        }
+        let i = wps.iter().rposition(|wp| wp.time <= t).unwrap_or(0);
+        let (a, b) = (&wps[i], &wps[i + 1]);
+        let span = (b.time - a.time).as_secs_f32();
+        let tau = (t - a.time).as_secs_f32() / span;
+        let (position, velocity) = match self.interpolation {
+            Interpolation::MinJerk => {
+                let (s, ds) = min_jerk(tau);
+                let d = sub(&b.position, &a.position);
+                (add(&a.position, &scale(&d, s)), scale(&d, ds / span))
+            }
+            Interpolation::CatmullRom => {
+                let m1 = self.tangent(i);
+                let m2 = self.tangent(i + 1);
+                hermite(&a.position, &m1, &b.position, &m2, tau, span)
+            }
+        };
+        TrajectorySample {
+            position,
+            velocity,
+            yaw: self.yaw(i, tau),
+        }
+    }
+
+    fn at_rest(&self, i: usize) -> TrajectorySample {
+        TrajectorySample {
+            position: self.waypoints[i].position,
+            velocity: Vec3::new(0.0, 0.0, 0.0),
+            yaw: self.yaw_before(i),
+        }
+    }
+
+    // Catmull-Rom tangent (m/s) for non uniform waypoint times, zero at the ends.
+    fn tangent(&self, i: usize) -> Vec3<f32> {
+        let wps = &self.waypoints;
+        if i == 0 || i == wps.len() - 1 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let dt = (wps[i + 1].time - wps[i - 1].time).as_secs_f32();
+        scale(&sub(&wps[i + 1].position, &wps[i - 1].position), 1.0 / dt)
+    }
+
+    // The last yaw set at or before waypoint `i`.
+    fn yaw_before(&self, i: usize) -> Option<f64> {
+        self.waypoints[..=i].iter().rev().find_map(|wp| wp.yaw)
+    }
+
+    // Turns from the last known yaw to the yaw of the next waypoint (if set) with a min-jerk profile.
+    fn yaw(&self, i: usize, tau: f32) -> Option<f64> {
+        let from = self.yaw_before(i);
+        match (from, self.waypoints[i + 1].yaw) {
+            (Some(from), Some(to)) => {
+                let (s, _) = min_jerk(tau);
+                let diff = (to - from + 180.0).rem_euclid(360.0) - 180.0;
+                Some(from + diff * s as f64)
+            }
+            (from, to) => from.or(to),
+        }
+    }
+}
+
+// Position (0..1) and its derivative of the min-jerk profile.
+fn min_jerk(tau: f32) -> (f32, f32) {
+    let (t2, t3) = (tau * tau, tau * tau * tau);
+    (
+        10.0 * t3 - 15.0 * t3 * tau + 6.0 * t3 * t2,
+        30.0 * t2 - 60.0 * t3 + 30.0 * t2 * t2,
+    )
+}
+
+fn hermite(
+    p1: &Vec3<f32>,
+    m1: &Vec3<f32>,
+    p2: &Vec3<f32>,
+    m2: &Vec3<f32>,
+    tau: f32,
+    span: f32,
+) -> (Vec3<f32>, Vec3<f32>) {
+    let (t2, t3) = (tau * tau, tau * tau * tau);
+    let (h00, h10, h01, h11) = (
+        2.0 * t3 - 3.0 * t2 + 1.0,
+        t3 - 2.0 * t2 + tau,
+        -2.0 * t3 + 3.0 * t2,
+        t3 - t2,
+    );
+    let (d00, d10, d01, d11) = (
+        6.0 * t2 - 6.0 * tau,
+        3.0 * t2 - 4.0 * tau + 1.0,
+        -6.0 * t2 + 6.0 * tau,
+        3.0 * t2 - 2.0 * tau,
+    );
+    let combine = |a: f32, b: f32, c: f32, d: f32| {
+        add(
+            &add(&scale(p1, a), &scale(m1, b * span)),
+            &add(&scale(p2, c), &scale(m2, d * span)),
+        )
+    };
+    (
+        combine(h00, h10, h01, h11),
+        scale(&combine(d00, d10, d01, d11), 1.0 / span),
+    )
+}
+
+fn add(a: &Vec3<f32>, b: &Vec3<f32>) -> Vec3<f32> {
+    Vec3::new(a.x + b.x, a.y + b.y, a.z + b.z)
+}
+
+fn sub(a: &Vec3<f32>, b: &Vec3<f32>) -> Vec3<f32> {
+    Vec3::new(a.x - b.x, a.y - b.y, a.z - b.z)
+}
+
+fn scale(a: &Vec3<f32>, k: f32) -> Vec3<f32> {
+    Vec3::new(a.x * k, a.y * k, a.z * k)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wp(secs: u64, x: f32, yaw: Option<f64>) -> Waypoint {
+        Waypoint::new(Duration::from_secs(secs), Vec3::new(x, 0.0, 0.0), yaw)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(
+            Err(TrajectoryError::TooFewWaypoints),
+            Trajectory::new(vec![wp(0, 0.0, None)], Interpolation::MinJerk)
+        );
+        assert_eq!(
+            Err(TrajectoryError::NotIncreasing { index: 2 }),
+            Trajectory::new(
+                vec![wp(0, 0.0, None), wp(2, 1.0, None), wp(2, 2.0, None)],
+                Interpolation::MinJerk
+            )
+        );
+    }
+
+    #[test]
+    fn test_min_jerk_rest_to_rest() {
+        let tr = Trajectory::new(
+            vec![wp(0, 0.0, Some(0.0)), wp(2, 1.0, Some(-90.0))],
+            Interpolation::MinJerk,
+        )
+        .unwrap();
+        let start = tr.sample(Duration::ZERO);
+        assert_eq!(0.0, start.velocity.x);
+        let mid = tr.sample(Duration::from_secs(1));
+        assert!(close(0.5, mid.position.x));
+        // peak velocity of min-jerk is 1.875 * average
+        assert!(close(0.9375, mid.velocity.x), "{:?}", mid);
+        assert!((mid.yaw.unwrap() + 45.0).abs() < 1e-6);
+        let end = tr.sample(Duration::from_secs(5));
+        assert_eq!(1.0, end.position.x);
+        assert_eq!(0.0, end.velocity.x);
+        assert_eq!(Some(-90.0), end.yaw);
+    }
+
+    #[test]
+    fn test_catmull_rom_passes_waypoints_without_stopping() {
+        let tr = Trajectory::new(
+            vec![wp(0, 0.0, None), wp(1, 1.0, None), wp(2, 2.0, None)],
+            Interpolation::CatmullRom,
+        )
+        .unwrap();
+        let at = tr.sample(Duration::from_secs(1));
+        assert!(close(1.0, at.position.x));
+        assert!(close(1.0, at.velocity.x), "{:?}", at);
+        assert!(close(0.0, tr.sample(Duration::ZERO).velocity.x));
+        assert_eq!(None, at.yaw);
+        // continuous velocity around the waypoint
+        let before = tr.sample(Duration::from_millis(990)).velocity.x;
+        assert!((before - 1.0).abs() < 0.05, "{}", before);
+    }
+}
@@ -1,8 +1,6 @@
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::OpenOptions,
-    io::Write,
     net::UdpSocket,
-    process::{ChildStdin, Command, Stdio},
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -63,54 +61,15 @@ pub fn udp_sock_clone(s: &UdpSocket) -> UdpSocket {
     r.unwrap()
 }
 
-pub fn append_to_file(path: &str, buffer: Vec<u8>) {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(path)
-        .unwrap();
-
-    let r = file.write_all(&buffer);
-    if r.is_err() {
-        tracing::error!("error writing video to file: {}", r.unwrap_err());
-    }
-}
-
-pub fn start_mplayer_with_stdin(use_x11: bool) -> Option<ChildStdin> {
-    let method_name = "start_mplayer";
-    let args = if use_x11 {
-        vec!["-nosound", "-vo", "x11", "-fps", "60", "-"]
-    } else {
-        vec!["-nosound", "-fps", "60", "-"]
-    };
-    let mplayer = Command::new("mplayer")
-        .args(args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .spawn();
-    if mplayer.is_err() {
-        let e = mplayer.err().unwrap();
-        tracing::warn!(method_name, "can't execute mplayer: {}", e);
-        return None;
-    }
-
-    let stdin = mplayer.unwrap().stdin.take();
-    if stdin.is_none() {
-        tracing::warn!(method_name, "can't open mplayer stdin");
-    }
-    stdin
-}
-
-#[derive(Debug, PartialEq)]
-pub(crate) struct Vec3<T> {
-    x: T,
-    y: T,
-    z: T,
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Vec3<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
 }
 
 impl<T> Vec3<T> {
-    pub(crate) fn new(x: T, y: T, z: T) -> Self {
+    pub fn new(x: T, y: T, z: T) -> Self {
         Self { x, y, z }
     }
 }
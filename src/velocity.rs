@@ -0,0 +1,276 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    frame::rotate_xy,
+    messages::MVOData,
+    pid::{Pid, PidGains},
+    tello::Stick,
+};
+
+// MVO velocities are reported in cm/s
+pub const MVO_VELOCITY_SCALE: f32 = 0.01;
+pub const DEFAULT_MVO_TIMEOUT: Duration = Duration::from_millis(500);
+pub const DEFAULT_MAX_STICK: f32 = 0.6;
+
+// longer gaps between two control steps (e.g. a stalled stick loop) don't feed the integral
+const MAX_STEP: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VelocityFrame {
+    // forward/right/up of the drone
+    Body,
+    // forward/right/up of the drone at takeoff
+    World,
+}
+
+// Target velocity in m/s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityTarget {
+    pub forward: f32,
+    pub right: f32,
+    pub up: f32,
+    pub frame: VelocityFrame,
+}
+
+impl VelocityTarget {
+    pub fn body(forward: f32, right: f32, up: f32) -> Self {
+        Self {
+            forward,
+            right,
+            up,
+            frame: VelocityFrame::Body,
+        }
+    }
+
+    pub fn world(forward: f32, right: f32, up: f32) -> Self {
+        Self {
+            forward,
+            right,
+            up,
+            frame: VelocityFrame::World,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityConfig {
+    pub horizontal: PidGains,
+    pub vertical: PidGains,
+    // hover when no MVO velocity was received for this long
+    pub stale_timeout: Duration,
+    // stick deflection limit of the controller output
+    pub max_stick: f32,
+    // converts the MVO velocities to m/s
+    pub mvo_scale: f32,
+}
+
+impl Default for VelocityConfig {
+    fn default() -> Self {
+        Self {
+            horizontal: PidGains::new(0.8, 0.3, 0.05),
+            vertical: PidGains::new(1.0, 0.3, 0.0),
+            stale_timeout: DEFAULT_MVO_TIMEOUT,
+            max_stick: DEFAULT_MAX_STICK,
+            mvo_scale: MVO_VELOCITY_SCALE,
+        }
+    }
+}
+
+// Holds a target velocity with PID loops on the MVO (vision positioning) velocities.
+// MVO velocities are relative to the takeoff heading, so the controller hovers
+// when either the MVO data or the drone heading are unknown.
+#[derive(Debug)]
+pub struct VelocityController {
+    config: VelocityConfig,
+    target: Option<VelocityTarget>,
+    forward: Pid,
+    right: Pid,
+    up: Pid,
+    last_step: Option<Instant>,
+    stale: bool,
+}
+
+impl VelocityController {
+    pub fn new(config: VelocityConfig) -> Self {
+        Self {
+            config,
+            target: None,
+            forward: Pid::new(config.horizontal, config.max_stick),
+            right: Pid::new(config.horizontal, config.max_stick),
+            up: Pid::new(config.vertical, config.max_stick),
+            last_step: None,
+            stale: false,
+        }
+    }
+
+    pub fn config(&self) -> VelocityConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: VelocityConfig) {
+        self.config = config;
+        self.forward.set_gains(config.horizontal, config.max_stick);
+        self.right.set_gains(config.horizontal, config.max_stick);
+        self.up.set_gains(config.vertical, config.max_stick);
+    }
+
+    pub fn target(&self) -> Option<VelocityTarget> {
+        self.target
+    }
+
+    pub fn set_target(&mut self, target: VelocityTarget) {
+        tracing::info!(?target, "velocity target");
+        if self.target.is_none() {
+            self.reset();
+        }
+        self.target = Some(target);
+    }
+
+    pub fn clear(&mut self) {
+        self.target = None;
+        self.reset();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.target.is_some()
+    }
+
+    fn reset(&mut self) {
+        self.forward.reset();
+        self.right.reset();
+        self.up.reset();
+        self.last_step = None;
+    }
+
+    fn set_stale(&mut self, stale: bool) {
+        let method_name = "velocity_controller";
+        if stale && !self.stale {
+            tracing::warn!(method_name, "MVO velocity stale, hovering");
+        } else if !stale && self.stale {
+            tracing::info!(method_name, "MVO velocity back");
+        }
+        self.stale = stale;
+    }
+
+    // One control step, `heading` is the drone heading relative to the takeoff heading.
+    // Returns None while there is no target.
+    pub(crate) fn update(
+        &mut self,
+        mvo: Option<(&MVOData, Instant)>,
+        heading: Option<f64>,
+        now: Instant,
+    ) -> Option<Stick> {
+        let target = self.target?;
+        let fresh =
+            mvo.filter(|(_, at)| now.saturating_duration_since(*at) <= self.config.stale_timeout);
+        let (mvo, heading) = match (fresh, heading) {
+            (Some((mvo, _)), Some(heading)) => (mvo, heading),
+            _ => {
+                self.set_stale(true);
+                self.reset();
+                return Some(Stick::default());
+            }
+        };
+        self.set_stale(false);
+        let dt = match self.last_step {
+            Some(last) => now.saturating_duration_since(last).min(MAX_STEP),
+            None => Duration::ZERO,
+        }
+        .as_secs_f32();
+        self.last_step = Some(now);
+
+        let (target_forward, target_right) = match target.frame {
+            VelocityFrame::Body => (target.forward, target.right),
+            VelocityFrame::World => rotate_xy(target.forward, target.right, heading),
+        };
+        let scale = self.config.mvo_scale;
+        // an axis without a valid velocity is left alone
+        let (ry, rx) = match (mvo.vx, mvo.vy) {
+            (Some(vx), Some(vy)) => {
+                let (forward, right) = rotate_xy(vx as f32 * scale, vy as f32 * scale, heading);
+                (
+                    self.forward.update(target_forward - forward, dt),
+                    self.right.update(target_right - right, dt),
+                )
+            }
+            _ => {
+                self.forward.reset();
+                self.right.reset();
+                (0.0, 0.0)
+            }
+        };
+        let ly = match mvo.vz {
+            Some(vz) => self.up.update(target.up - vz as f32 * scale, dt),
+            None => {
+                self.up.reset();
+                0.0
+            }
+        };
+        Some(Stick::new((rx, ry), (0.0, ly)))
+    }
+}
+
+impl Default for VelocityController {
+    fn default() -> Self {
+        Self::new(VelocityConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mvo(vx: i16, vy: i16, vz: i16) -> MVOData {
+        MVOData {
+            position: None,
+            vx: Some(vx),
+            vy: Some(vy),
+            vz: Some(vz),
+        }
+    }
+
+    #[test]
+    fn test_inactive_without_target() {
+        let mut ctrl = VelocityController::default();
+        let now = Instant::now();
+        assert!(ctrl
+            .update(Some((&mvo(0, 0, 0), now)), Some(0.0), now)
+            .is_none());
+    }
+
+    #[test]
+    fn test_hover_on_stale_mvo() {
+        let mut ctrl = VelocityController::default();
+        ctrl.set_target(VelocityTarget::body(1.0, 0.0, 0.0));
+        let at = Instant::now();
+        let data = mvo(0, 0, 0);
+        let st = ctrl.update(Some((&data, at)), Some(0.0), at).unwrap();
+        assert!(st.ry > 0.0);
+        let later = at + DEFAULT_MVO_TIMEOUT * 2;
+        let st = ctrl.update(Some((&data, at)), Some(0.0), later).unwrap();
+        assert_eq!(0.0, st.max_deflection());
+        // unknown heading
+        let st = ctrl.update(Some((&data, later)), None, later).unwrap();
+        assert_eq!(0.0, st.max_deflection());
+    }
+
+    #[test]
+    fn test_world_frame_target() {
+        let mut ctrl = VelocityController::default();
+        // fly "north" while the drone faces east: that's left for the drone
+        ctrl.set_target(VelocityTarget::world(0.5, 0.0, 0.0));
+        let now = Instant::now();
+        let st = ctrl
+            .update(Some((&mvo(0, 0, 0), now)), Some(90.0), now)
+            .unwrap();
+        assert!(st.rx < 0.0, "rx={}", st.rx);
+        assert!(st.ry.abs() < 1e-5, "ry={}", st.ry);
+        // already at the target speed (50 cm/s north), nothing to correct
+        ctrl.clear();
+        ctrl.set_target(VelocityTarget::world(0.5, 0.0, 0.2));
+        let st = ctrl
+            .update(Some((&mvo(50, 0, 20), now)), Some(90.0), now)
+            .unwrap();
+        assert!(st.max_deflection() < 1e-5, "{:?}", st);
+    }
+}
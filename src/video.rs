@@ -0,0 +1,942 @@
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "snapshot")]
+use std::sync::mpsc;
+
+use crate::video_queue::BufferPool;
+
+// H.264 NAL unit types
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+// set in the second header byte on the last fragment of a frame
+const LAST_FRAGMENT: u8 = 0x80;
+
+// One H.264 access unit (Annex B, with start codes). Keyframes always carry
+// the SPS and PPS, `pts` is the receive time since the start of the stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoFrame {
+    pub data: Vec<u8>,
+    pub is_keyframe: bool,
+    pub seq: u64,
+    pub pts: Duration,
+}
+
+impl VideoFrame {
+    pub fn nal_units(&self) -> Vec<&[u8]> {
+        nal_units(&self.data)
+    }
+}
+
+// Parsed from the SPS, see parse_sps().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpsInfo {
+    pub profile: u8,
+    pub level: u8,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Exp-Golomb reader over a NAL unit payload with the emulation prevention bytes removed.
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    fn new(nal: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(nal.len());
+        for &b in nal {
+            let n = data.len();
+            if b == 3 && n >= 2 && data[n - 1] == 0 && data[n - 2] == 0 {
+                continue;
+            }
+            data.push(b);
+        }
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = *self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        (0..n).try_fold(0, |v, _| Some(v << 1 | self.bit()?))
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+        Some(if v % 2 == 1 {
+            v.div_ceil(2) as i32
+        } else {
+            -((v / 2) as i32)
+        })
+    }
+}
+
+// Profile, level and the cropped picture size from an SPS NAL unit (with the NAL header).
+pub fn parse_sps(sps: &[u8]) -> Option<SpsInfo> {
+    if nal_type(sps) != NAL_SPS {
+        return None;
+    }
+    let mut r = BitReader::new(&sps[1..]);
+    let profile = r.bits(8)? as u8;
+    r.bits(8)?; // constraint flags
+    let level = r.bits(8)? as u8;
+    r.ue()?; // sps id
+    let mut chroma_format = 1;
+    if matches!(
+        profile,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format = r.ue()?;
+        if chroma_format == 3 {
+            r.bit()?; // separate colour planes
+        }
+        r.ue()?; // luma bit depth
+        r.ue()?; // chroma bit depth
+        r.bit()?; // qpprime y zero transform bypass
+        if r.bit()? == 1 {
+            let lists = if chroma_format == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? == 1 {
+                    // scaling list, only skipped
+                    let size = if i < 6 { 16 } else { 64 };
+                    let (mut last, mut next) = (8, 8);
+                    for _ in 0..size {
+                        if next != 0 {
+                            next = (last + r.se()? + 256) % 256;
+                        }
+                        if next != 0 {
+                            last = next;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    r.ue()?; // log2 max frame num
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2 max pic order count lsb
+        }
+        1 => {
+            r.bit()?;
+            r.se()?;
+            r.se()?;
+            for _ in 0..r.ue()? {
+                r.se()?;
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max ref frames
+    r.bit()?; // gaps in frame num allowed
+    let width_mbs = r.ue()? + 1;
+    let height_map_units = r.ue()? + 1;
+    let frame_mbs_only = r.bit()?;
+    if frame_mbs_only == 0 {
+        r.bit()?; // mb adaptive frame field
+    }
+    r.bit()?; // direct 8x8 inference
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if r.bit()? == 1 {
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        let (unit_x, unit_y) = match chroma_format {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        crop_x = unit_x * (left + right);
+        crop_y = unit_y * (2 - frame_mbs_only) * (top + bottom);
+    }
+    let width = (width_mbs * 16).checked_sub(crop_x)?;
+    let height = ((2 - frame_mbs_only) * height_map_units * 16).checked_sub(crop_y)?;
+    Some(SpsInfo {
+        profile,
+        level,
+        width,
+        height,
+    })
+}
+
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map_or(0, |b| b & 0x1f)
+}
+
+// Splits an Annex B byte stream into NAL units (without the start codes).
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = vec![];
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let mut nals = vec![];
+    for (n, &start) in starts.iter().enumerate() {
+        let mut end = match starts.get(n + 1) {
+            Some(&next) => next - 3,
+            None => data.len(),
+        };
+        // 4 byte start code, or trailing zeros
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            nals.push(&data[start..end]);
+        }
+    }
+    nals
+}
+
+// first keyframe request backoff, doubled up to KEYFRAME_REQUEST_MAX_BACKOFF
+pub const KEYFRAME_REQUEST_BACKOFF: Duration = Duration::from_millis(100);
+pub const KEYFRAME_REQUEST_MAX_BACKOFF: Duration = Duration::from_secs(2);
+// no datagram for this long while the video is on
+pub const VIDEO_STALL_TIMEOUT: Duration = Duration::from_secs(1);
+// consecutive datagrams behind the last frame number taken as a counter reset
+const RESYNC_LATE_DATAGRAMS: u32 = 4;
+// fps and bitrate are measured over this window
+pub const VIDEO_RATE_WINDOW: Duration = Duration::from_secs(1);
+// gain of the smoothed inter-arrival time and jitter, as in RTP (RFC 3550)
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+// Loss, recovery and quality counters of the video stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VideoStats {
+    // frames sent on the video channel
+    pub frames: u64,
+    pub keyframes: u64,
+    // frame numbers never seen, or seen with missing fragments
+    pub lost_frames: u64,
+    // complete frames dropped while waiting for a keyframe
+    pub dropped_frames: u64,
+    // the stream broke / was decodable again
+    pub losses: u64,
+    pub recoveries: u64,
+    pub keyframe_requests: u64,
+    // from the first lost frame to the next keyframe, the last time
+    pub last_recovery: Option<Duration>,
+    // datagrams received, and the ones missing from the frames we saw parts of
+    // (at least one per frame never seen)
+    pub fragments: u64,
+    pub lost_fragments: u64,
+    // payload bytes received
+    pub bytes: u64,
+    // frames sent and bits received per second, over the last VIDEO_RATE_WINDOW
+    pub fps: f32,
+    pub bitrate: u32,
+    // between the last two keyframes sent
+    pub keyframe_interval: Option<Duration>,
+    // smoothed deviation of the frame inter-arrival time
+    pub jitter: Duration,
+    // bytes per frame sent
+    pub avg_frame_size: u32,
+    // width x height from the SPS
+    pub resolution: Option<(u32, u32)>,
+}
+
+impl VideoStats {
+    // Share of the fragments that never arrived, 0.0 ..= 1.0.
+    pub fn fragment_loss_rate(&self) -> f32 {
+        let total = self.fragments + self.lost_fragments;
+        if total == 0 {
+            return 0.0;
+        }
+        self.lost_fragments as f32 / total as f32
+    }
+}
+
+// Counts for the fps / bitrate window and the inter-arrival smoothing.
+#[derive(Debug, Default)]
+struct RateMeter {
+    window_start: Option<Instant>,
+    frames: u64,
+    bytes: u64,
+    frame_bytes: u64,
+    last_frame: Option<Instant>,
+    last_keyframe: Option<Instant>,
+    // seconds
+    interval: f64,
+    jitter: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StreamChange {
+    Lost { frames: u64 },
+    Recovered { after: Duration },
+}
+
+// Fragments of the frame being received.
+#[derive(Debug)]
+struct PartialFrame {
+    number: u8,
+    seq: u64,
+    next_fragment: u8,
+    data: Vec<u8>,
+    received: Instant,
+    // a fragment is missing or out of order
+    broken: bool,
+}
+
+// Reassembles the video datagrams into access units. Each datagram starts with
+// the frame number and the fragment index (bit 7 marks the last fragment).
+// After a loss the frames are dropped until the next keyframe, the decoder
+// can't use them anyway.
+#[derive(Debug)]
+pub struct VideoAssembler {
+    partial: Option<PartialFrame>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    started: Option<Instant>,
+    last_number: Option<u8>,
+    seq: u64,
+    // the stream starts in the middle of a GOP
+    needs_keyframe: bool,
+    // a consumer (e.g. a new RTSP viewer) wants one, the stream is fine
+    keyframe_wanted: bool,
+    lost_at: Option<Instant>,
+    last_packet: Option<Instant>,
+    // consecutive datagrams behind last_number
+    late: u32,
+    next_request: Option<Instant>,
+    backoff: Duration,
+    stats: VideoStats,
+    rates: RateMeter,
+    changes: Vec<StreamChange>,
+    pool: BufferPool,
+}
+
+impl Default for VideoAssembler {
+    fn default() -> Self {
+        Self {
+            partial: None,
+            sps: None,
+            pps: None,
+            started: None,
+            last_number: None,
+            seq: 0,
+            needs_keyframe: true,
+            keyframe_wanted: false,
+            lost_at: None,
+            last_packet: None,
+            late: 0,
+            next_request: None,
+            backoff: KEYFRAME_REQUEST_BACKOFF,
+            stats: VideoStats::default(),
+            rates: RateMeter::default(),
+            changes: vec![],
+            pool: BufferPool::default(),
+        }
+    }
+}
+
+impl VideoAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sps(&self) -> Option<&[u8]> {
+        self.sps.as_deref()
+    }
+
+    pub fn pps(&self) -> Option<&[u8]> {
+        self.pps.as_deref()
+    }
+
+    // Frame buffers come from (and the dropped ones go back to) the pool.
+    pub fn set_pool(&mut self, pool: BufferPool) {
+        self.pool = pool;
+    }
+
+    pub fn stats(&self) -> VideoStats {
+        self.stats
+    }
+
+    // Closes the fps / bitrate window when it's over, called on every datagram.
+    // Call it before reading stats() so a stalled stream reads 0 fps.
+    pub fn update_rates(&mut self, now: Instant) {
+        let start = *self.rates.window_start.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed < VIDEO_RATE_WINDOW {
+            return;
+        }
+        let secs = elapsed.as_secs_f64();
+        self.stats.fps = (self.rates.frames as f64 / secs) as f32;
+        self.stats.bitrate = (self.rates.bytes as f64 * 8.0 / secs) as u32;
+        self.rates.window_start = Some(now);
+        self.rates.frames = 0;
+        self.rates.bytes = 0;
+    }
+
+    // True until a keyframe arrives after the start or a loss.
+    pub fn needs_keyframe(&self) -> bool {
+        self.needs_keyframe
+    }
+
+    // Adds one datagram, returns the frame it completed.
+    pub fn push(&mut self, packet: &[u8], now: Instant) -> Option<VideoFrame> {
+        let method_name = "video_assembler";
+        if packet.len() < 2 {
+            return None;
+        }
+        let (number, fragment) = (packet[0], packet[1] & !LAST_FRAGMENT);
+        let last = packet[1] & LAST_FRAGMENT != 0;
+        self.started.get_or_insert(now);
+        let stalled = self
+            .last_packet
+            .is_some_and(|at| now.saturating_duration_since(at) > VIDEO_STALL_TIMEOUT);
+        self.last_packet = Some(now);
+        self.update_rates(now);
+        self.stats.fragments += 1;
+        self.stats.bytes += packet.len() as u64 - 2;
+        self.rates.bytes += packet.len() as u64 - 2;
+
+        if self.partial.as_ref().is_none_or(|p| p.number != number) {
+            let step = self.last_number.map_or(1, |last| number.wrapping_sub(last));
+            let behind = step == 0 || step > 128;
+            if behind && !stalled && self.late + 1 < RESYNC_LATE_DATAGRAMS {
+                // late datagram of a frame we're done with, the frame being
+                // assembled is left alone
+                self.late += 1;
+                return None;
+            }
+            if let Some(p) = self.partial.take() {
+                tracing::debug!(method_name, p.number, "incomplete frame dropped");
+                self.pool.put(p.data);
+                // its last fragment at least
+                self.stats.lost_fragments += 1;
+                self.lost(1, now);
+            }
+            // after a stall or a counter reset (video off/on, reconnect) the
+            // number of frames in between is unknown
+            let missing = if behind {
+                tracing::info!(method_name, number, "video frame counter reset, resyncing");
+                1
+            } else {
+                step as u64 - 1
+            };
+            if self.last_number.is_some() {
+                self.seq += missing + 1;
+            }
+            if missing > 0 {
+                self.stats.lost_fragments += missing;
+                self.lost(missing, now);
+            }
+            self.last_number = Some(number);
+            self.partial = Some(PartialFrame {
+                number,
+                seq: self.seq,
+                next_fragment: 0,
+                data: self.pool.get(),
+                received: now,
+                broken: false,
+            });
+        }
+        self.late = 0;
+        let partial = self.partial.as_mut().unwrap();
+        if fragment != partial.next_fragment {
+            partial.broken = true;
+            let missing = fragment.wrapping_sub(partial.next_fragment) & !LAST_FRAGMENT;
+            self.stats.lost_fragments += missing as u64;
+        }
+        partial.next_fragment = fragment.wrapping_add(1);
+        partial.data.extend_from_slice(&packet[2..]);
+        if !last {
+            return None;
+        }
+        let p = self.partial.take().unwrap();
+        if p.broken {
+            tracing::debug!(
+                method_name,
+                p.number,
+                "frame with missing fragments dropped"
+            );
+            self.pool.put(p.data);
+            self.lost(1, now);
+            return None;
+        }
+        self.access_unit(p, now)
+    }
+
+    // Asks for a keyframe without dropping frames, with the keyframe_request() backoff.
+    pub fn want_keyframe(&mut self) {
+        self.keyframe_wanted = true;
+    }
+
+    // Keyframe requests wanted by the stream state, see TelloController::start_video_contoller().
+    // Returns true when a request is due, repeated requests back off.
+    pub fn keyframe_request(&mut self, now: Instant) -> bool {
+        let stalled = self
+            .last_packet
+            .is_none_or(|at| now.saturating_duration_since(at) > VIDEO_STALL_TIMEOUT);
+        let wanted = self.needs_keyframe
+            || self.keyframe_wanted
+            || self.sps.is_none()
+            || self.pps.is_none()
+            || stalled;
+        if !wanted {
+            self.next_request = None;
+            self.backoff = KEYFRAME_REQUEST_BACKOFF;
+            return false;
+        }
+        if self.next_request.is_some_and(|at| now < at) {
+            return false;
+        }
+        self.next_request = Some(now + self.backoff);
+        self.backoff = (self.backoff * 2).min(KEYFRAME_REQUEST_MAX_BACKOFF);
+        self.stats.keyframe_requests += 1;
+        true
+    }
+
+    // Loss and recovery changes since the last call.
+    pub(crate) fn changes(&mut self) -> Vec<StreamChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    fn frame_sent(&mut self, len: usize, is_keyframe: bool, at: Instant) {
+        self.stats.frames += 1;
+        self.rates.frames += 1;
+        self.rates.frame_bytes += len as u64;
+        self.stats.avg_frame_size = (self.rates.frame_bytes / self.stats.frames) as u32;
+        if is_keyframe {
+            self.stats.keyframes += 1;
+            if let Some(last) = self.rates.last_keyframe {
+                self.stats.keyframe_interval = Some(at.saturating_duration_since(last));
+            }
+            self.rates.last_keyframe = Some(at);
+        }
+        if let Some(last) = self.rates.last_frame {
+            let interval = at.saturating_duration_since(last).as_secs_f64();
+            let rates = &mut self.rates;
+            if rates.interval == 0.0 {
+                rates.interval = interval;
+            }
+            rates.jitter += JITTER_GAIN * ((interval - rates.interval).abs() - rates.jitter);
+            rates.interval += JITTER_GAIN * (interval - rates.interval);
+            self.stats.jitter = Duration::from_secs_f64(rates.jitter);
+        }
+        self.rates.last_frame = Some(at);
+    }
+
+    fn lost(&mut self, frames: u64, now: Instant) {
+        self.stats.lost_frames += frames;
+        if self.lost_at.is_none() {
+            self.lost_at = Some(now);
+            self.stats.losses += 1;
+            self.changes.push(StreamChange::Lost { frames });
+        }
+        self.needs_keyframe = true;
+    }
+
+    fn access_unit(&mut self, p: PartialFrame, now: Instant) -> Option<VideoFrame> {
+        let mut is_keyframe = false;
+        let mut has_parameters = false;
+        let mut has_picture = false;
+        for nal in nal_units(&p.data) {
+            match nal_type(nal) {
+                NAL_SPS => {
+                    if self.sps.as_deref() != Some(nal) {
+                        self.stats.resolution = parse_sps(nal).map(|sps| (sps.width, sps.height));
+                        self.sps = Some(nal.to_vec());
+                    }
+                    has_parameters = true;
+                }
+                NAL_PPS => {
+                    self.pps = Some(nal.to_vec());
+                    has_parameters = true;
+                }
+                NAL_IDR => {
+                    is_keyframe = true;
+                    has_picture = true;
+                }
+                _ => has_picture = true,
+            }
+        }
+        if !has_picture {
+            // parameter sets only, they go with the next keyframe
+            self.pool.put(p.data);
+            return None;
+        }
+        let decodable = is_keyframe && self.sps.is_some() && self.pps.is_some();
+        if self.needs_keyframe && !decodable {
+            self.stats.dropped_frames += 1;
+            self.pool.put(p.data);
+            return None;
+        }
+        if is_keyframe {
+            self.keyframe_wanted = false;
+        }
+        if is_keyframe && self.needs_keyframe {
+            self.needs_keyframe = false;
+            if let Some(at) = self.lost_at.take() {
+                let after = now.saturating_duration_since(at);
+                self.stats.recoveries += 1;
+                self.stats.last_recovery = Some(after);
+                self.changes.push(StreamChange::Recovered { after });
+            }
+        }
+        let mut data = p.data;
+        if is_keyframe && !has_parameters {
+            if let (Some(sps), Some(pps)) = (&self.sps, &self.pps) {
+                let mut with_parameters = self.pool.get();
+                for nal in [sps, pps] {
+                    with_parameters.extend_from_slice(&START_CODE);
+                    with_parameters.extend_from_slice(nal);
+                }
+                with_parameters.extend_from_slice(&data);
+                self.pool.put(std::mem::replace(&mut data, with_parameters));
+            }
+        }
+        self.frame_sent(data.len(), is_keyframe, p.received);
+        let started = self.started.unwrap_or(p.received);
+        Some(VideoFrame {
+            data,
+            is_keyframe,
+            seq: p.seq,
+            pts: p.received.saturating_duration_since(started),
+        })
+    }
+}
+
+// Callers waiting for the next keyframe (TelloController::snapshot()), the last
+// keyframe is kept for a caller that gives up waiting.
+#[cfg(feature = "snapshot")]
+#[derive(Debug, Default)]
+pub(crate) struct KeyframeWaiters {
+    next_id: u64,
+    waiters: Vec<(u64, mpsc::SyncSender<VideoFrame>)>,
+    last: Option<VideoFrame>,
+}
+
+#[cfg(feature = "snapshot")]
+impl KeyframeWaiters {
+    pub(crate) fn wait(&mut self) -> (u64, mpsc::Receiver<VideoFrame>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiters.push((id, tx));
+        (id, rx)
+    }
+
+    // Stops waiting, returns the last keyframe received.
+    pub(crate) fn cancel(&mut self, id: u64) -> Option<VideoFrame> {
+        self.waiters.retain(|(waiter, _)| *waiter != id);
+        self.last.clone()
+    }
+
+    pub(crate) fn push(&mut self, frame: &VideoFrame) {
+        if !frame.is_keyframe {
+            return;
+        }
+        for (_, tx) in self.waiters.drain(..) {
+            let _ = tx.try_send(frame.clone());
+        }
+        self.last = Some(frame.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nal(kind: u8, len: usize) -> Vec<u8> {
+        let mut v = START_CODE.to_vec();
+        v.push(0x60 | kind);
+        v.extend(std::iter::repeat(0xab).take(len));
+        v
+    }
+
+    fn packet(number: u8, fragment: u8, last: bool, payload: &[u8]) -> Vec<u8> {
+        let mut v = vec![number, fragment | if last { LAST_FRAGMENT } else { 0 }];
+        v.extend_from_slice(payload);
+        v
+    }
+
+    #[test]
+    fn test_nal_units() {
+        let mut data = nal(NAL_SPS, 3);
+        data.extend(&[0, 0, 1, 0x65, 1, 2]);
+        let nals = nal_units(&data);
+        assert_eq!(2, nals.len());
+        assert_eq!(NAL_SPS, nal_type(nals[0]));
+        assert_eq!(&[0x65, 1, 2], nals[1]);
+    }
+
+    fn put(bits: &mut Vec<u32>, v: u32, n: u32) {
+        for i in (0..n).rev() {
+            bits.push((v >> i) & 1);
+        }
+    }
+
+    fn ue(bits: &mut Vec<u32>, v: u32) {
+        let n = 32 - (v + 1).leading_zeros();
+        put(bits, 0, n - 1);
+        put(bits, v + 1, n);
+    }
+
+    // Encodes a main profile SPS, `crop_bottom` in chroma units.
+    fn sps(width_mbs: u32, height_mbs: u32, crop_bottom: u32) -> Vec<u8> {
+        let b = &mut vec![];
+        // sps id, log2 max frame num, poc type, ref frames
+        for v in [0, 0, 2, 1] {
+            ue(b, v);
+        }
+        put(b, 0, 1);
+        ue(b, width_mbs - 1);
+        ue(b, height_mbs - 1);
+        // frame mbs only, direct 8x8
+        put(b, 0b11, 2);
+        put(b, (crop_bottom > 0) as u32, 1);
+        if crop_bottom > 0 {
+            for v in [0, 0, 0, crop_bottom] {
+                ue(b, v);
+            }
+        }
+        // no vui, stop bit
+        put(b, 0b01, 2);
+        let mut out = vec![0x67, 77, 0x40, 40];
+        for chunk in b.chunks(8) {
+            let byte = chunk.iter().fold(0u8, |v, &bit| v << 1 | bit as u8);
+            out.push(byte << (8 - chunk.len()));
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_sps() {
+        let info = parse_sps(&sps(60, 45, 0)).unwrap();
+        assert_eq!(
+            (77, 40, 960, 720),
+            (info.profile, info.level, info.width, info.height)
+        );
+        let info = parse_sps(&sps(120, 68, 4)).unwrap();
+        assert_eq!((1920, 1080), (info.width, info.height));
+        assert_eq!(None, parse_sps(&[0x68, 1, 2]));
+    }
+
+    #[test]
+    fn test_reassembly_and_parameter_sets() {
+        let mut asm = VideoAssembler::new();
+        let at = Instant::now();
+        let mut params = nal(NAL_SPS, 4);
+        params.extend(nal(NAL_PPS, 2));
+        assert_eq!(None, asm.push(&packet(1, 0, true, &params), at));
+        assert!(asm.sps().is_some() && asm.pps().is_some());
+
+        // keyframe in two fragments gets the parameter sets
+        let idr = nal(NAL_IDR, 1500);
+        assert_eq!(None, asm.push(&packet(2, 0, false, &idr[..1000]), at));
+        let later = at + Duration::from_millis(40);
+        let frame = asm.push(&packet(2, 1, true, &idr[1000..]), later).unwrap();
+        assert!(frame.is_keyframe);
+        let types: Vec<u8> = frame.nal_units().iter().map(|n| nal_type(n)).collect();
+        assert_eq!(vec![NAL_SPS, NAL_PPS, NAL_IDR], types);
+        assert_eq!(Duration::ZERO, frame.pts);
+
+        let p = nal(NAL_SLICE, 1200);
+        let frame = asm.push(&packet(3, 0, true, &p), later).unwrap();
+        assert!(!frame.is_keyframe);
+        assert_eq!(p, frame.data);
+        assert_eq!(2, frame.seq);
+        assert_eq!(Duration::from_millis(40), frame.pts);
+    }
+
+    #[test]
+    fn test_loss_drops_until_keyframe() {
+        let mut asm = VideoAssembler::new();
+        let at = Instant::now();
+        let mut idr = nal(NAL_SPS, 4);
+        idr.extend(nal(NAL_PPS, 2));
+        idr.extend(nal(NAL_IDR, 100));
+        let p = nal(NAL_SLICE, 100);
+        // joined in the middle of a GOP
+        assert_eq!(None, asm.push(&packet(9, 0, true, &p), at));
+        assert!(asm.push(&packet(10, 0, true, &idr), at).is_some());
+        assert!(asm.changes().is_empty());
+
+        // fragment 1 of frame 11 and all of frame 12 lost
+        assert_eq!(None, asm.push(&packet(11, 0, false, &p[..50]), at));
+        assert_eq!(None, asm.push(&packet(11, 2, true, &p[50..]), at));
+        let later = at + Duration::from_millis(300);
+        assert_eq!(None, asm.push(&packet(13, 0, true, &p), later));
+        assert!(asm.needs_keyframe());
+        let frame = asm.push(&packet(14, 0, true, &idr), later).unwrap();
+        assert_eq!(5, frame.seq);
+        let stats = asm.stats();
+        assert_eq!(
+            (2, 2, 1, 1),
+            (
+                stats.lost_frames,
+                stats.dropped_frames,
+                stats.losses,
+                stats.recoveries
+            )
+        );
+        assert_eq!(
+            vec![
+                StreamChange::Lost { frames: 1 },
+                StreamChange::Recovered {
+                    after: Duration::from_millis(300)
+                }
+            ],
+            asm.changes()
+        );
+    }
+
+    #[test]
+    fn test_keyframe_request_backoff() {
+        let mut asm = VideoAssembler::new();
+        let at = Instant::now();
+        let due =
+            |asm: &mut VideoAssembler, ms| asm.keyframe_request(at + Duration::from_millis(ms));
+        assert!(due(&mut asm, 0));
+        assert!(!due(&mut asm, 50));
+        assert!(due(&mut asm, 100));
+        assert!(!due(&mut asm, 250));
+        assert!(due(&mut asm, 300));
+        let mut idr = nal(NAL_SPS, 4);
+        idr.extend(nal(NAL_PPS, 2));
+        idr.extend(nal(NAL_IDR, 100));
+        asm.push(&packet(0, 0, true, &idr), at + Duration::from_millis(310));
+        assert!(!due(&mut asm, 320));
+        assert_eq!(3, asm.stats().keyframe_requests);
+        // a new viewer wants one, the frames keep flowing
+        asm.want_keyframe();
+        assert!(due(&mut asm, 330));
+        assert!(!due(&mut asm, 340));
+        assert!(asm
+            .push(
+                &packet(1, 0, true, &nal(NAL_SLICE, 10)),
+                at + Duration::from_millis(345)
+            )
+            .is_some());
+        asm.push(&packet(2, 0, true, &idr), at + Duration::from_millis(350));
+        assert!(!due(&mut asm, 460));
+        assert_eq!(4, asm.stats().keyframe_requests);
+        // stalled stream, the backoff starts over
+        assert!(due(&mut asm, 1500));
+        assert!(!due(&mut asm, 1550));
+    }
+
+    #[test]
+    fn test_quality_stats() {
+        let mut asm = VideoAssembler::new();
+        let at = Instant::now();
+        let mut params = START_CODE.to_vec();
+        params.extend(sps(60, 45, 0));
+        params.extend(nal(NAL_PPS, 2));
+        let (idr, p) = (nal(NAL_IDR, 500), nal(NAL_SLICE, 100));
+        asm.push(&packet(1, 0, true, &params), at);
+        let mut window_bytes = params.len() as u64;
+        let mut frame_bytes = 0;
+        // 25 fps with a keyframe every second
+        for n in 2..=31u8 {
+            let now = at + Duration::from_millis(40) * (n as u32 - 1);
+            let data = if n % 25 == 2 { &idr } else { &p };
+            let frame = asm.push(&packet(n, 0, true, data), now).unwrap();
+            frame_bytes += frame.data.len();
+            if n < 26 {
+                window_bytes += data.len() as u64;
+            }
+        }
+        let stats = asm.stats();
+        assert_eq!(Some((960, 720)), stats.resolution);
+        assert_eq!(24.0, stats.fps);
+        assert_eq!(window_bytes * 8, stats.bitrate as u64);
+        assert_eq!(Some(Duration::from_secs(1)), stats.keyframe_interval);
+        assert_eq!(Duration::ZERO, stats.jitter);
+        assert_eq!(frame_bytes / 30, stats.avg_frame_size as usize);
+
+        // fragment 1 of frame 32 and all of frame 33 lost
+        let later = at + Duration::from_millis(1300);
+        asm.push(&packet(32, 0, false, &p[..50]), later);
+        asm.push(&packet(32, 2, true, &p[50..]), later);
+        asm.push(&packet(34, 0, true, &p), later);
+        let stats = asm.stats();
+        assert_eq!((34, 2), (stats.fragments, stats.lost_fragments));
+        assert_eq!(2.0 / 36.0, stats.fragment_loss_rate());
+    }
+
+    #[test]
+    fn test_frame_counter_reset() {
+        let mut asm = VideoAssembler::new();
+        let at = Instant::now();
+        let mut idr = nal(NAL_SPS, 4);
+        idr.extend(nal(NAL_PPS, 2));
+        idr.extend(nal(NAL_IDR, 100));
+        let p = nal(NAL_SLICE, 100);
+        assert!(asm.push(&packet(100, 0, true, &idr), at).is_some());
+        assert!(asm.push(&packet(101, 0, true, &p), at).is_some());
+        // a late datagram is dropped without a loss
+        assert_eq!(None, asm.push(&packet(100, 0, true, &p), at));
+        assert!(asm.push(&packet(102, 0, true, &p), at).is_some());
+        // also in the middle of a frame, which is still completed
+        assert_eq!(None, asm.push(&packet(103, 0, false, &p[..50]), at));
+        assert_eq!(None, asm.push(&packet(101, 0, true, &p), at));
+        let frame = asm.push(&packet(103, 1, true, &p[50..]), at).unwrap();
+        assert_eq!(p, frame.data);
+        assert_eq!((0, 0), (asm.stats().losses, asm.stats().lost_fragments));
+
+        // the drone restarted its counter
+        for n in 0..3 {
+            assert_eq!(None, asm.push(&packet(n, 0, true, &p), at));
+        }
+        assert!(!asm.needs_keyframe());
+        assert_eq!(None, asm.push(&packet(3, 0, true, &p), at));
+        assert!(asm.needs_keyframe());
+        assert_eq!(1, asm.stats().losses);
+        let frame = asm.push(&packet(4, 0, true, &idr), at).unwrap();
+        assert!(frame.is_keyframe);
+
+        // behind again after a stall, taken at once
+        let later = at + VIDEO_STALL_TIMEOUT * 2;
+        assert_eq!(None, asm.push(&packet(1, 0, true, &p), later));
+        assert_eq!(2, asm.stats().losses);
+        assert!(asm.push(&packet(2, 0, true, &idr), later).is_some());
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_keyframe_waiters() {
+        let frame = |seq, is_keyframe| VideoFrame {
+            data: vec![],
+            is_keyframe,
+            seq,
+            pts: Duration::ZERO,
+        };
+        let mut waiters = KeyframeWaiters::default();
+        let (_, rx) = waiters.wait();
+        waiters.push(&frame(1, false));
+        assert!(rx.try_recv().is_err());
+        waiters.push(&frame(2, true));
+        assert_eq!(2, rx.try_recv().unwrap().seq);
+        // a caller giving up gets the last keyframe and is not sent the next one
+        let (id, rx) = waiters.wait();
+        assert_eq!(Some(2), waiters.cancel(id).map(|f| f.seq));
+        waiters.push(&frame(3, true));
+        assert!(rx.try_recv().is_err());
+        assert!(waiters.waiters.is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::video::VideoFrame;
+
+// one second of video
+pub const DEFAULT_VIDEO_QUEUE_CAPACITY: usize = 30;
+// buffers kept for reuse, the rest is freed
+pub const DEFAULT_POOL_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    // a full queue drops its oldest frame
+    DropOldest,
+    // a full queue is flushed and the frames are dropped until the next
+    // keyframe, so the consumer never sees a broken GOP
+    #[default]
+    DropUntilKeyframe,
+    // the video receiver waits for the consumer, the UDP socket drops datagrams instead
+    Block,
+}
+
+// Reusable frame buffers, shared by the assembler and the frame queue.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    max: usize,
+}
+
+impl BufferPool {
+    pub fn new(max: usize) -> Self {
+        Self {
+            free: Arc::new(Mutex::new(vec![])),
+            max,
+        }
+    }
+
+    // An empty buffer, reused when there is one.
+    pub fn get(&self) -> Vec<u8> {
+        self.free.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max && buffer.capacity() > 0 {
+            free.push(buffer);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_SIZE)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub queued: u64,
+    pub dropped: u64,
+    // frames waiting for the consumer, and the most there ever were
+    pub len: usize,
+    pub high_water: usize,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    frames: VecDeque<VideoFrame>,
+    capacity: usize,
+    policy: QueuePolicy,
+    skipping: bool,
+    senders: usize,
+    receiver: bool,
+    stats: QueueStats,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    pool: BufferPool,
+}
+
+// The sending half of the video channel, see crate::video_channel().
+#[derive(Debug)]
+pub struct FrameSender {
+    shared: Arc<Shared>,
+}
+
+// The receiving half, pass the consumed frames to recycle() to reuse their buffers.
+#[derive(Debug)]
+pub struct FrameReceiver {
+    shared: Arc<Shared>,
+}
+
+pub fn frame_queue(capacity: usize, policy: QueuePolicy) -> (FrameSender, FrameReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState {
+            frames: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            policy,
+            skipping: false,
+            senders: 1,
+            receiver: true,
+            stats: QueueStats::default(),
+        }),
+        changed: Condvar::new(),
+        pool: BufferPool::default(),
+    });
+    (
+        FrameSender {
+            shared: shared.clone(),
+        },
+        FrameReceiver { shared },
+    )
+}
+
+impl FrameSender {
+    pub fn pool(&self) -> BufferPool {
+        self.shared.pool.clone()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.state.lock().unwrap().stats
+    }
+
+    // Fails only when the receiver went away, a frame dropped by the policy is not an error.
+    pub fn send(&self, frame: VideoFrame) -> Result<(), SendError<VideoFrame>> {
+        let shared = &self.shared;
+        let mut g = shared.state.lock().unwrap();
+        if !g.receiver {
+            return Err(SendError(frame));
+        }
+        if g.skipping && !frame.is_keyframe {
+            g.stats.dropped += 1;
+            drop(g);
+            shared.pool.put(frame.data);
+            return Ok(());
+        }
+        g.skipping = false;
+        while g.frames.len() >= g.capacity {
+            match g.policy {
+                QueuePolicy::DropOldest => {
+                    let old = g.frames.pop_front().unwrap();
+                    g.stats.dropped += 1;
+                    shared.pool.put(old.data);
+                }
+                QueuePolicy::DropUntilKeyframe => {
+                    g.stats.dropped += g.frames.len() as u64;
+                    for old in g.frames.drain(..) {
+                        shared.pool.put(old.data);
+                    }
+                    if !frame.is_keyframe {
+                        g.skipping = true;
+                        g.stats.dropped += 1;
+                        drop(g);
+                        shared.pool.put(frame.data);
+                        return Ok(());
+                    }
+                }
+                QueuePolicy::Block => {
+                    g = shared.changed.wait(g).unwrap();
+                    if !g.receiver {
+                        return Err(SendError(frame));
+                    }
+                }
+            }
+        }
+        g.frames.push_back(frame);
+        g.stats.queued += 1;
+        g.stats.len = g.frames.len();
+        g.stats.high_water = g.stats.high_water.max(g.stats.len);
+        shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl Clone for FrameSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for FrameSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl FrameReceiver {
+    pub fn pool(&self) -> BufferPool {
+        self.shared.pool.clone()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.state.lock().unwrap().stats
+    }
+
+    // Waits for a frame, fails once all the senders are gone and the queue is empty.
+    pub fn recv(&self) -> Result<VideoFrame, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<VideoFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn try_recv(&self) -> Result<VideoFrame, TryRecvError> {
+        match self.recv_until(Some(Instant::now())) {
+            Ok(frame) => Ok(frame),
+            Err(RecvTimeoutError::Timeout) => Err(TryRecvError::Empty),
+            Err(RecvTimeoutError::Disconnected) => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    // Gives the frame buffer back to the pool.
+    pub fn recycle(&self, frame: VideoFrame) {
+        self.shared.pool.put(frame.data);
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<VideoFrame, RecvTimeoutError> {
+        let shared = &self.shared;
+        let mut g = shared.state.lock().unwrap();
+        loop {
+            if let Some(frame) = g.frames.pop_front() {
+                g.stats.len = g.frames.len();
+                shared.changed.notify_all();
+                return Ok(frame);
+            }
+            if g.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            g = match deadline {
+                None => shared.changed.wait(g).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    shared.changed.wait_timeout(g, left).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+impl Drop for FrameReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver = false;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(seq: u64, is_keyframe: bool) -> VideoFrame {
+        VideoFrame {
+            data: vec![0; 16],
+            is_keyframe,
+            seq,
+            pts: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_drop_policies() {
+        let (tx, rx) = frame_queue(2, QueuePolicy::DropOldest);
+        for seq in 0..4 {
+            tx.send(frame(seq, false)).unwrap();
+        }
+        assert_eq!(2, rx.recv().unwrap().seq);
+        assert_eq!(2, tx.stats().dropped);
+        assert_eq!(2, tx.pool().available());
+
+        let (tx, rx) = frame_queue(2, QueuePolicy::DropUntilKeyframe);
+        tx.send(frame(0, true)).unwrap();
+        tx.send(frame(1, false)).unwrap();
+        // full, flushed
+        tx.send(frame(2, false)).unwrap();
+        tx.send(frame(3, false)).unwrap();
+        assert_eq!(Err(TryRecvError::Empty), rx.try_recv());
+        tx.send(frame(4, true)).unwrap();
+        tx.send(frame(5, false)).unwrap();
+        assert_eq!(4, rx.recv().unwrap().seq);
+        assert_eq!(5, rx.recv().unwrap().seq);
+        let stats = rx.stats();
+        assert_eq!((4, 4, 2), (stats.queued, stats.dropped, stats.high_water));
+        drop(tx);
+        assert_eq!(Err(RecvError), rx.recv());
+    }
+
+    #[test]
+    fn test_block_waits_for_consumer() {
+        let (tx, rx) = frame_queue(1, QueuePolicy::Block);
+        let sender = std::thread::spawn(move || {
+            for seq in 0..3 {
+                tx.send(frame(seq, false)).unwrap();
+            }
+            tx.stats().dropped
+        });
+        for seq in 0..3 {
+            let f = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(seq, f.seq);
+            rx.recycle(f);
+        }
+        assert_eq!(0, sender.join().unwrap());
+        assert_eq!(
+            Err(RecvTimeoutError::Disconnected),
+            rx.recv_timeout(Duration::from_millis(10))
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::video::VideoFrame;
+
+// how long the player gets to exit once its stdin is closed, it's killed afterwards
+pub const PLAYER_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+const PLAYER_EXIT_POLL: Duration = Duration::from_millis(50);
+
+// Consumes the assembled video frames, see TelloController::start_video_sink().
+pub trait VideoSink: Send {
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()>;
+
+    // Called once when the video channel closes or a write fails.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Player {
+    Ffplay,
+    Mpv,
+    Mplayer,
+}
+
+impl Player {
+    pub fn program(&self) -> &'static str {
+        match self {
+            Player::Ffplay => "ffplay",
+            Player::Mpv => "mpv",
+            Player::Mplayer => "mplayer",
+        }
+    }
+
+    // Low latency playback of a raw H.264 stream from stdin.
+    pub fn default_args(&self) -> Vec<String> {
+        let args: &[&str] = match self {
+            Player::Ffplay => &[
+                "-loglevel",
+                "error",
+                "-fflags",
+                "nobuffer",
+                "-flags",
+                "low_delay",
+                "-f",
+                "h264",
+                "-i",
+                "-",
+            ],
+            Player::Mpv => &["--no-cache", "--untimed", "--demuxer-lavf-format=h264", "-"],
+            Player::Mplayer => &["-nosound", "-demuxer", "h264es", "-fps", "30", "-"],
+        };
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    // The first player found in PATH.
+    pub fn detect() -> Option<Player> {
+        let path = env::var_os("PATH")?;
+        [Player::Ffplay, Player::Mpv, Player::Mplayer]
+            .into_iter()
+            .find(|p| env::split_paths(&path).any(|dir| dir.join(p.program()).is_file()))
+    }
+}
+
+// Pipes the stream to an external player.
+#[derive(Debug)]
+pub struct PlayerSink {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl PlayerSink {
+    pub fn new(player: Player, args: &[String]) -> io::Result<Self> {
+        let method_name = "player_sink";
+        let mut command = Command::new(player.program());
+        command.args(args);
+        let sink = Self::spawn(&mut command)?;
+        tracing::info!(method_name, player = player.program(), ?args, "started");
+        Ok(sink)
+    }
+
+    fn spawn(command: &mut Command) -> io::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        Ok(Self { child, stdin })
+    }
+
+    // Starts the first player found, with its default arguments.
+    pub fn detect() -> io::Result<Self> {
+        let player = Player::detect().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no ffplay, mpv or mplayer in PATH")
+        })?;
+        Self::new(player, &player.default_args())
+    }
+
+    // Closes the player stdin and waits up to `timeout` for it to exit, kills it afterwards.
+    fn reap(&mut self, timeout: Duration) -> io::Result<()> {
+        let method_name = "player_sink";
+        self.stdin.take();
+        let deadline = Instant::now() + timeout;
+        while self.child.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                tracing::warn!(
+                    method_name,
+                    pid = self.child.id(),
+                    "player still running, killing it"
+                );
+                self.child.kill()?;
+                return self.child.wait().map(|_| ());
+            }
+            thread::sleep(PLAYER_EXIT_POLL);
+        }
+        Ok(())
+    }
+}
+
+impl VideoSink for PlayerSink {
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        match self.stdin.as_mut() {
+            Some(stdin) => stdin.write_all(&frame.data),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.reap(PLAYER_EXIT_TIMEOUT)
+    }
+}
+
+// A sink dropped without finish() doesn't leave the player running or a zombie.
+impl Drop for PlayerSink {
+    fn drop(&mut self) {
+        let method_name = "player_sink";
+        if let Err(e) = self.reap(PLAYER_EXIT_TIMEOUT) {
+            tracing::warn!(method_name, "unable to stop the player: {}", e);
+        }
+    }
+}
+
+// Writes the raw Annex B stream, playable with `ffplay -f h264`.
+#[derive(Debug)]
+pub struct FileSink {
+    out: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl VideoSink for FileSink {
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        self.out.write_all(&frame.data)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub struct CallbackSink<F> {
+    callback: F,
+}
+
+impl<F: FnMut(&VideoFrame) + Send> CallbackSink<F> {
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F: FnMut(&VideoFrame) + Send> VideoSink for CallbackSink<F> {
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        (self.callback)(frame);
+        Ok(())
+    }
+}
+
+// Sends every frame to several sinks, a failing sink is finished and removed.
+#[derive(Default)]
+pub struct FanOut {
+    sinks: Vec<Box<dyn VideoSink>>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, sink: impl VideoSink + 'static) -> Self {
+        self.add(sink);
+        self
+    }
+
+    pub fn add(&mut self, sink: impl VideoSink + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl VideoSink for FanOut {
+    // Fails once no sink is left.
+    fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        let method_name = "fan_out";
+        self.sinks.retain_mut(|sink| match sink.write_frame(frame) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!(method_name, "video sink removed: {}", e);
+                let _ = sink.finish();
+                false
+            }
+        });
+        if self.sinks.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "no video sink left",
+            ));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut r = Ok(());
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.finish() {
+                r = Err(e);
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    struct Broken;
+
+    impl VideoSink for Broken {
+        fn write_frame(&mut self, _frame: &VideoFrame) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn test_fan_out_drops_failing_sinks() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_local = seen.clone();
+        let path = env::temp_dir().join(format!("tello-sink-{}.h264", std::process::id()));
+        let mut sink = FanOut::new()
+            .with(CallbackSink::new(move |f: &VideoFrame| {
+                seen_local.lock().unwrap().push(f.seq)
+            }))
+            .with(Broken)
+            .with(FileSink::create(&path).unwrap());
+        for seq in 0..2 {
+            let frame = VideoFrame {
+                data: vec![0, 0, 0, 1, 0x65, seq as u8],
+                is_keyframe: true,
+                seq,
+                pts: Duration::ZERO,
+            };
+            sink.write_frame(&frame).unwrap();
+        }
+        assert_eq!(2, sink.len());
+        sink.finish().unwrap();
+        assert_eq!(vec![0, 1], *seen.lock().unwrap());
+        assert_eq!(12, std::fs::read(&path).unwrap().len());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_player_killed_after_timeout() {
+        // ignores the closed stdin
+        let mut sink = PlayerSink::spawn(Command::new("sh").args(["-c", "exec sleep 30"])).unwrap();
+        let started = Instant::now();
+        sink.reap(Duration::from_millis(100)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(sink.child.try_wait().unwrap().is_some());
+    }
+}